@@ -1,10 +1,10 @@
-use std::{sync::mpsc, thread};
+use std::thread;
 
 use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
 use kvs::{
     rwlock,
     thread_pool::{SharedQueueThreadPool, ThreadPool},
-    KvStore, KvsEngine, SledKvsEngine,
+    KvStore, KvsEngine, MemKvsEngine, SledKvsEngine,
 };
 use tempfile::TempDir;
 
@@ -22,23 +22,17 @@ fn concurrent_set(c: &mut Criterion) {
                     (db, temp_dir)
                 },
                 |(db, _temp_dir)| {
-                    let (tx, rx) = mpsc::sync_channel(tot - 1);
-                    for key_i in 1..tot {
-                        let db = db.clone();
-                        let tx = tx.clone();
-                        pool.spawn(move || {
-                            db.set(format!("key{}", key_i), "value".to_string())
-                                .unwrap();
-                            tx.send(()).unwrap();
+                    let handles = (1..tot)
+                        .map(|key_i| {
+                            let db = db.clone();
+                            pool.spawn_with_handle(move || {
+                                db.set(format!("key{}", key_i), "value".to_string())
+                                    .unwrap();
+                            })
                         })
-                    }
-                    drop(tx);
-                    let mut count = 0;
-                    for _ in rx {
-                        count += 1;
-                        if count >= tot - 1 {
-                            break;
-                        }
+                        .collect::<Vec<_>>();
+                    for handle in handles {
+                        handle.join().unwrap();
                     }
                 },
                 BatchSize::SmallInput,
@@ -52,23 +46,17 @@ fn concurrent_set(c: &mut Criterion) {
                     (db, temp_dir)
                 },
                 |(db, _temp_dir)| {
-                    let (tx, rx) = mpsc::sync_channel(tot - 1);
-                    for key_i in 1..tot {
-                        let db = db.clone();
-                        let tx = tx.clone();
-                        pool.spawn(move || {
-                            db.set(format!("key{}", key_i), "value".to_string())
-                                .unwrap();
-                            tx.send(()).unwrap();
+                    let handles = (1..tot)
+                        .map(|key_i| {
+                            let db = db.clone();
+                            pool.spawn_with_handle(move || {
+                                db.set(format!("key{}", key_i), "value".to_string())
+                                    .unwrap();
+                            })
                         })
-                    }
-                    drop(tx);
-                    let mut count = 0;
-                    for _ in rx {
-                        count += 1;
-                        if count >= tot - 1 {
-                            break;
-                        }
+                        .collect::<Vec<_>>();
+                    for handle in handles {
+                        handle.join().unwrap();
                     }
                 },
                 BatchSize::SmallInput,
@@ -82,23 +70,37 @@ fn concurrent_set(c: &mut Criterion) {
                     (db, temp_dir)
                 },
                 |(db, _temp_dir)| {
-                    let (tx, rx) = mpsc::sync_channel(tot - 1);
-                    for key_i in 1..tot {
-                        let db = db.clone();
-                        let tx = tx.clone();
-                        pool.spawn(move || {
-                            db.set(format!("key{}", key_i), "value".to_string())
-                                .unwrap();
-                            tx.send(()).unwrap();
+                    let handles = (1..tot)
+                        .map(|key_i| {
+                            let db = db.clone();
+                            pool.spawn_with_handle(move || {
+                                db.set(format!("key{}", key_i), "value".to_string())
+                                    .unwrap();
+                            })
                         })
+                        .collect::<Vec<_>>();
+                    for handle in handles {
+                        handle.join().unwrap();
                     }
-                    drop(tx);
-                    let mut count = 0;
-                    for _ in rx {
-                        count += 1;
-                        if count >= tot - 1 {
-                            break;
-                        }
+                },
+                BatchSize::SmallInput,
+            )
+        });
+        group.bench_with_input(BenchmarkId::new("memory", tot), &tot, |b, &tot| {
+            b.iter_batched_ref(
+                MemKvsEngine::new,
+                |db| {
+                    let handles = (1..tot)
+                        .map(|key_i| {
+                            let db = db.clone();
+                            pool.spawn_with_handle(move || {
+                                db.set(format!("key{}", key_i), "value".to_string())
+                                    .unwrap();
+                            })
+                        })
+                        .collect::<Vec<_>>();
+                    for handle in handles {
+                        handle.join().unwrap();
                     }
                 },
                 BatchSize::SmallInput,
@@ -128,25 +130,19 @@ fn concurrent_get(c: &mut Criterion) {
             store.flush().unwrap();
             fastrand::seed(19260817);
             b.iter(|| {
-                let (tx, rx) = mpsc::sync_channel(tot - 1);
-                for _ in 0..tot {
-                    let store = store.clone();
-                    let tx = tx.clone();
-                    pool.spawn(move || {
-                        for _ in 0..GET_TOT / tot {
-                            let key_i = fastrand::usize(0..KEY_TOT);
-                            store.get(&format!("key{}", key_i)).unwrap();
-                        }
-                        tx.send(()).unwrap();
+                let handles = (0..tot)
+                    .map(|_| {
+                        let store = store.clone();
+                        pool.spawn_with_handle(move || {
+                            for _ in 0..GET_TOT / tot {
+                                let key_i = fastrand::usize(0..KEY_TOT);
+                                store.get(&format!("key{}", key_i)).unwrap();
+                            }
+                        })
                     })
-                }
-                drop(tx);
-                let mut count = 0;
-                for _ in rx {
-                    count += 1;
-                    if count >= tot {
-                        break;
-                    }
+                    .collect::<Vec<_>>();
+                for handle in handles {
+                    handle.join().unwrap();
                 }
             })
         });
@@ -161,25 +157,19 @@ fn concurrent_get(c: &mut Criterion) {
             store.flush().unwrap();
             fastrand::seed(19260817);
             b.iter(|| {
-                let (tx, rx) = mpsc::sync_channel(tot - 1);
-                for _ in 0..tot {
-                    let store = store.clone();
-                    let tx = tx.clone();
-                    pool.spawn(move || {
-                        for _ in 0..GET_TOT / tot {
-                            let key_i = fastrand::usize(0..KEY_TOT);
-                            store.get(&format!("key{}", key_i)).unwrap();
-                        }
-                        tx.send(()).unwrap();
+                let handles = (0..tot)
+                    .map(|_| {
+                        let store = store.clone();
+                        pool.spawn_with_handle(move || {
+                            for _ in 0..GET_TOT / tot {
+                                let key_i = fastrand::usize(0..KEY_TOT);
+                                store.get(&format!("key{}", key_i)).unwrap();
+                            }
+                        })
                     })
-                }
-                drop(tx);
-                let mut count = 0;
-                for _ in rx {
-                    count += 1;
-                    if count >= tot {
-                        break;
-                    }
+                    .collect::<Vec<_>>();
+                for handle in handles {
+                    handle.join().unwrap();
                 }
             })
         });
@@ -193,25 +183,43 @@ fn concurrent_get(c: &mut Criterion) {
             db.flush().unwrap();
             fastrand::seed(19260817);
             b.iter(|| {
-                let (tx, rx) = mpsc::sync_channel(tot - 1);
-                for _ in 0..tot {
-                    let db = db.clone();
-                    let tx = tx.clone();
-                    pool.spawn(move || {
-                        for _ in 0..GET_TOT / tot {
-                            let key_i = fastrand::usize(0..KEY_TOT);
-                            db.get(&format!("key{}", key_i)).unwrap();
-                        }
-                        tx.send(()).unwrap();
+                let handles = (0..tot)
+                    .map(|_| {
+                        let db = db.clone();
+                        pool.spawn_with_handle(move || {
+                            for _ in 0..GET_TOT / tot {
+                                let key_i = fastrand::usize(0..KEY_TOT);
+                                db.get(&format!("key{}", key_i)).unwrap();
+                            }
+                        })
                     })
+                    .collect::<Vec<_>>();
+                for handle in handles {
+                    handle.join().unwrap();
                 }
-                drop(tx);
-                let mut count = 0;
-                for _ in rx {
-                    count += 1;
-                    if count >= tot {
-                        break;
-                    }
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("memory", tot), &tot, |b, &tot| {
+            let db = MemKvsEngine::new();
+            for key_i in 0..KEY_TOT {
+                db.set(format!("key{}", key_i), "value".to_string())
+                    .unwrap();
+            }
+            fastrand::seed(19260817);
+            b.iter(|| {
+                let handles = (0..tot)
+                    .map(|_| {
+                        let db = db.clone();
+                        pool.spawn_with_handle(move || {
+                            for _ in 0..GET_TOT / tot {
+                                let key_i = fastrand::usize(0..KEY_TOT);
+                                db.get(&format!("key{}", key_i)).unwrap();
+                            }
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                for handle in handles {
+                    handle.join().unwrap();
                 }
             })
         });
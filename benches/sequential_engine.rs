@@ -1,5 +1,5 @@
 use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
-use kvs::{KvStore, KvsEngine, SledKvsEngine};
+use kvs::{KvStore, KvStoreOptions, KvsEngine, MemKvsEngine, SledKvsEngine};
 use tempfile::TempDir;
 
 fn sequential_set(c: &mut Criterion) {
@@ -38,6 +38,18 @@ fn sequential_set(c: &mut Criterion) {
                 BatchSize::SmallInput,
             )
         });
+        group.bench_with_input(BenchmarkId::new("memory", tot), &tot, |b, &tot| {
+            b.iter_batched_ref(
+                MemKvsEngine::new,
+                |db| {
+                    for key_i in 1..tot {
+                        db.set(format!("key{}", key_i), "value".to_string())
+                            .unwrap();
+                    }
+                },
+                BatchSize::SmallInput,
+            )
+        });
     }
     group.finish();
 }
@@ -60,6 +72,24 @@ fn sequential_get(c: &mut Criterion) {
                     .unwrap();
             })
         });
+        group.bench_with_input(BenchmarkId::new("kvs_cached", tot), &tot, |b, &tot| {
+            let temp_dir = TempDir::new().unwrap();
+            let store = KvStoreOptions::new()
+                .value_cache(tot)
+                .open(temp_dir.path())
+                .unwrap();
+            for key_i in 1..tot {
+                store
+                    .set(format!("key{}", key_i), "value".to_string())
+                    .unwrap();
+            }
+            fastrand::seed(19260817);
+            b.iter(|| {
+                store
+                    .get(&format!("key{}", fastrand::usize(1..tot)))
+                    .unwrap();
+            })
+        });
         group.bench_with_input(BenchmarkId::new("sled", tot), &tot, |b, &tot| {
             let temp_dir = TempDir::new().unwrap();
             let db = SledKvsEngine::open(&temp_dir).unwrap();
@@ -72,6 +102,17 @@ fn sequential_get(c: &mut Criterion) {
                 db.get(&format!("key{}", fastrand::usize(1..tot))).unwrap();
             })
         });
+        group.bench_with_input(BenchmarkId::new("memory", tot), &tot, |b, &tot| {
+            let db = MemKvsEngine::new();
+            for key_i in 1..tot {
+                db.set(format!("key{}", key_i), "value".to_string())
+                    .unwrap();
+            }
+            fastrand::seed(19260817);
+            b.iter(|| {
+                db.get(&format!("key{}", fastrand::usize(1..tot))).unwrap();
+            })
+        });
     }
     group.finish();
 }
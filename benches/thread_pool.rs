@@ -1,5 +1,7 @@
 use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
-use kvs::thread_pool::{RayonThreadPool, SharedQueueThreadPool, ThreadPool};
+use kvs::thread_pool::{
+    NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool, ThreadPool, WorkStealingThreadPool,
+};
 
 // TODO: The compare here doesn't make sense now,
 // because the pool just spawn the job and won't wait the job to be done
@@ -24,6 +26,23 @@ fn lightweight_job(c: &mut Criterion) {
                 }
             });
         });
+        group.bench_with_input(BenchmarkId::new("work_stealing", i), &i, |b, i| {
+            let work_stealing_pool = WorkStealingThreadPool::new(*i).unwrap();
+            b.iter(|| {
+                for _ in 0..100 {
+                    work_stealing_pool.spawn(dummy)
+                }
+            });
+        });
+        // Threads are joined when the pool drops, so it can't outlive an iteration
+        group.bench_with_input(BenchmarkId::new("naive", i), &i, |b, i| {
+            b.iter(|| {
+                let naive_pool = NaiveThreadPool::new(*i).unwrap();
+                for _ in 0..100 {
+                    naive_pool.spawn(dummy)
+                }
+            });
+        });
     }
     group.finish();
 }
@@ -65,6 +84,22 @@ fn compute_intensive(c: &mut Criterion) {
                 }
             });
         });
+        group.bench_with_input(BenchmarkId::new("work_stealing", i), &i, |b, i| {
+            let work_stealing_pool = WorkStealingThreadPool::new(*i).unwrap();
+            b.iter(|| {
+                for &num in &params {
+                    work_stealing_pool.spawn(move || decompose(num))
+                }
+            });
+        });
+        group.bench_with_input(BenchmarkId::new("naive", i), &i, |b, i| {
+            b.iter(|| {
+                let naive_pool = NaiveThreadPool::new(*i).unwrap();
+                for &num in &params {
+                    naive_pool.spawn(move || decompose(num))
+                }
+            });
+        });
     }
     group.finish();
 }
@@ -1,11 +1,21 @@
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+};
 
 use anyhow::{anyhow, Result};
-use clap::{Parser, Subcommand};
-use kvs::{KvsClient, Request, Response};
+use clap::{Parser, Subcommand, ValueEnum};
+use kvs::{Event, KvsClient, Request, Response, ShardedKvsClient};
+use serde::{Deserialize, Serialize};
 
 const DEFAULT_SOCKET_ADDR: SocketAddr =
     SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 4000);
+/// Report progress of `load` and `dump` every this many records
+const PROGRESS_INTERVAL: usize = 1000;
+/// Pairs fetched in one request by `dump`
+const DUMP_PAGE_SIZE: usize = 1000;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -21,31 +31,188 @@ enum Commands {
     Set {
         key: String,
         value: String,
+        /// Seconds after which the key expires, if the server is a cache
+        #[arg(long, conflicts_with = "cluster")]
+        ttl: Option<u64>,
         #[arg(long, default_value_t = DEFAULT_SOCKET_ADDR)]
         addr: SocketAddr,
+        /// Servers the keys are sharded across, instead of `--addr`
+        #[arg(long, value_delimiter = ',', conflicts_with = "addr")]
+        cluster: Vec<SocketAddr>,
     },
     Get {
         key: String,
         #[arg(long, default_value_t = DEFAULT_SOCKET_ADDR)]
         addr: SocketAddr,
+        /// Servers the keys are sharded across, instead of `--addr`
+        #[arg(long, value_delimiter = ',', conflicts_with = "addr")]
+        cluster: Vec<SocketAddr>,
     },
     Rm {
         key: String,
         #[arg(long, default_value_t = DEFAULT_SOCKET_ADDR)]
         addr: SocketAddr,
+        /// Servers the keys are sharded across, instead of `--addr`
+        #[arg(long, value_delimiter = ',', conflicts_with = "addr")]
+        cluster: Vec<SocketAddr>,
     },
+    /// Get the value as of a sequence number
+    GetAt {
+        key: String,
+        seq: u64,
+        #[arg(long, default_value_t = DEFAULT_SOCKET_ADDR)]
+        addr: SocketAddr,
+    },
+    /// Print the latest writes of the key with their sequence numbers, newest first
+    History {
+        key: String,
+        #[arg(long, default_value_t = 10)]
+        limit: u32,
+        #[arg(long, default_value_t = DEFAULT_SOCKET_ADDR)]
+        addr: SocketAddr,
+    },
+    /// Print writes to keys with the prefix as they happen
+    Watch {
+        #[arg(long, default_value_t = String::new())]
+        prefix: String,
+        #[arg(long, default_value_t = DEFAULT_SOCKET_ADDR)]
+        addr: SocketAddr,
+    },
+    /// Checkpoint the store into a directory under the backup directory of the server
+    Backup {
+        dir: String,
+        #[arg(long, default_value_t = DEFAULT_SOCKET_ADDR)]
+        addr: SocketAddr,
+    },
+    /// Set every pair in the file, `-` for stdin
+    Load {
+        file: PathBuf,
+        /// Guessed from the file extension if not given
+        #[arg(long, value_enum)]
+        format: Option<Format>,
+        /// Pairs sent in one request, 1 to send plain `set` requests
+        #[arg(long, default_value_t = 100)]
+        batch_size: usize,
+        #[arg(long, default_value_t = DEFAULT_SOCKET_ADDR)]
+        addr: SocketAddr,
+    },
+    /// Print every pair whose key starts with the prefix
+    Dump {
+        #[arg(long, default_value_t = String::new())]
+        prefix: String,
+        #[arg(long, value_enum, default_value_t = Format::Jsonl)]
+        format: Format,
+        #[arg(long, default_value_t = DEFAULT_SOCKET_ADDR)]
+        addr: SocketAddr,
+        /// Servers the keys are sharded across, instead of `--addr`
+        #[arg(long, value_delimiter = ',', conflicts_with = "addr")]
+        cluster: Vec<SocketAddr>,
+    },
+    /// Print the memory use and eviction counters of a cache server
+    Stats {
+        #[arg(long, default_value_t = DEFAULT_SOCKET_ADDR)]
+        addr: SocketAddr,
+    },
+    /// Move every key to the server it belongs to, after adding servers to the cluster.
+    ///
+    /// Stop writing to the cluster while it runs.
+    Rebalance {
+        #[arg(long, value_delimiter = ',', required = true)]
+        cluster: Vec<SocketAddr>,
+    },
+}
+
+/// Format of the `load` and `dump` files
+#[derive(Clone, Copy, ValueEnum)]
+enum Format {
+    /// One `{"key": .., "value": ..}` object per line
+    Jsonl,
+    /// One `key,value` row per line, without header
+    Csv,
+}
+
+impl Format {
+    fn guess(path: &Path) -> Self {
+        match path.extension() {
+            Some(ext) if ext.eq_ignore_ascii_case("csv") => Format::Csv,
+            _ => Format::Jsonl,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+struct Record {
+    key: String,
+    value: String,
 }
 
 fn main() -> Result<()> {
     let cli = Cli::parse();
     let mut is_remove = false;
     let (addr, request) = match cli.commands {
-        Commands::Set { key, value, addr } => (addr, Request::Set(key, value)),
-        Commands::Get { key, addr } => (addr, Request::Get(key)),
-        Commands::Rm { key, addr } => {
+        Commands::Set {
+            key,
+            value,
+            cluster,
+            ..
+        } if !cluster.is_empty() => return sharded(&cluster, Request::Set(key, value)),
+        Commands::Get { key, cluster, .. } if !cluster.is_empty() => {
+            return sharded(&cluster, Request::Get(key))
+        }
+        Commands::Rm { key, cluster, .. } if !cluster.is_empty() => {
+            return sharded(&cluster, Request::Rm(key))
+        }
+        Commands::Set {
+            key,
+            value,
+            ttl: Some(ttl),
+            addr,
+            ..
+        } => (addr, Request::SetTtl(key, value, ttl.saturating_mul(1000))),
+        Commands::Set {
+            key, value, addr, ..
+        } => (addr, Request::Set(key, value)),
+        Commands::Get { key, addr, .. } => (addr, Request::Get(key)),
+        Commands::Rm { key, addr, .. } => {
             is_remove = true;
             (addr, Request::Rm(key))
         }
+        Commands::GetAt { key, seq, addr } => (addr, Request::GetAt(key, seq)),
+        Commands::History { key, limit, addr } => (addr, Request::History(key, limit)),
+        Commands::Backup { dir, addr } => (addr, Request::Backup(dir)),
+        Commands::Stats { addr } => (addr, Request::CacheStats),
+        Commands::Load {
+            file,
+            format,
+            batch_size,
+            addr,
+        } => {
+            let format = format.unwrap_or_else(|| Format::guess(&file));
+            return load(&file, format, batch_size.max(1), addr);
+        }
+        Commands::Dump {
+            prefix,
+            format,
+            addr,
+            cluster,
+        } => {
+            if cluster.is_empty() {
+                let mut client = KvsClient::new(addr);
+                return dump(&prefix, format, |start| {
+                    scan_page(&mut client, &prefix, start)
+                });
+            }
+            let mut client = ShardedKvsClient::connect(&cluster)?;
+            return dump(&prefix, format, |start| {
+                Ok(client.scan_page(&prefix, start, DUMP_PAGE_SIZE)?)
+            });
+        }
+        Commands::Rebalance { cluster } => {
+            let moved = ShardedKvsClient::connect(&cluster)?.rebalance()?;
+            eprintln!("Moved {moved} keys");
+            return Ok(());
+        }
+        Commands::Watch { prefix, addr } => return watch(prefix, addr),
     };
     let mut client = KvsClient::new(addr);
     let response = client.request(request)?;
@@ -60,9 +227,220 @@ fn main() -> Result<()> {
             };
         }
         Response::Ok => {}
+        Response::History(writes) => {
+            for (seq, value) in writes {
+                match value {
+                    Some(value) => println!("{seq} {value}"),
+                    None => println!("{seq} (removed)"),
+                }
+            }
+        }
+        Response::CacheStats(stats) => {
+            println!("used_memory {}", stats.used_memory);
+            println!("max_memory {}", stats.max_memory);
+            println!("keys {}", stats.keys);
+            println!("hits {}", stats.hits);
+            println!("misses {}", stats.misses);
+            println!("evictions {}", stats.evictions);
+            println!("expirations {}", stats.expirations);
+        }
         Response::Err => {
             return Err(anyhow!("Server internal error"));
         }
+        Response::Busy => {
+            return Err(anyhow!("Server busy, try again later"));
+        }
+        response => {
+            return Err(anyhow!("Unexpected response: {response:?}"));
+        }
+    }
+    Ok(())
+}
+
+/// Read records of the file and send them over one connection.
+///
+/// Bad records and failed sets are reported and skipped,
+/// an error is returned at the end if there is any of them.
+fn load(file: &Path, format: Format, batch_size: usize, addr: SocketAddr) -> Result<()> {
+    let input: Box<dyn Read> = if file == Path::new("-") {
+        Box::new(io::stdin())
+    } else {
+        Box::new(File::open(file)?)
+    };
+    let records: Box<dyn Iterator<Item = Result<Record>>> = match format {
+        Format::Jsonl => Box::new(
+            BufReader::new(input)
+                .lines()
+                .filter(|line| !matches!(line, Ok(line) if line.trim().is_empty()))
+                .map(|line| Ok(serde_json::from_str(&line?)?)),
+        ),
+        Format::Csv => Box::new(
+            csv::ReaderBuilder::new()
+                .has_headers(false)
+                .from_reader(input)
+                .into_deserialize()
+                .map(|record| Ok(record?)),
+        ),
+    };
+
+    let mut client = KvsClient::new(addr);
+    let mut loaded = 0;
+    let mut failed = 0;
+    let mut batch = Vec::with_capacity(batch_size);
+    let mut flush_batch = |batch: &mut Vec<Request>, loaded: &mut usize, failed: &mut usize| {
+        let total = batch.len();
+        let request = if total == 1 {
+            batch.pop().unwrap()
+        } else {
+            Request::Batch(std::mem::take(batch))
+        };
+        let responses = match client.request(request) {
+            Ok(Response::Batch(responses)) => responses,
+            Ok(response) => vec![response],
+            Err(e) => {
+                eprintln!("Request failed: {e}");
+                vec![Response::Err; total]
+            }
+        };
+        let succeeded = responses
+            .iter()
+            .filter(|response| matches!(response, Response::Ok))
+            .count();
+        *loaded += succeeded;
+        *failed += total - succeeded;
+    };
+    for (i, record) in records.enumerate() {
+        match record {
+            Ok(Record { key, value }) => batch.push(Request::Set(key, value)),
+            Err(e) => {
+                eprintln!("Bad record {}: {e}", i + 1);
+                failed += 1;
+            }
+        }
+        if batch.len() >= batch_size {
+            let before = loaded + failed;
+            flush_batch(&mut batch, &mut loaded, &mut failed);
+            if (loaded + failed) / PROGRESS_INTERVAL > before / PROGRESS_INTERVAL {
+                eprintln!("Loaded {loaded} records, {failed} failed");
+            }
+        }
+    }
+    if !batch.is_empty() {
+        flush_batch(&mut batch, &mut loaded, &mut failed);
+    }
+    eprintln!("Loaded {loaded} records, {failed} failed");
+    if failed > 0 {
+        return Err(anyhow!("{failed} records failed to load"));
+    }
+    Ok(())
+}
+
+/// Send a plain request to the server the key belongs to
+fn sharded(cluster: &[SocketAddr], request: Request) -> Result<()> {
+    let mut client = ShardedKvsClient::connect(cluster)?;
+    match request {
+        Request::Set(key, value) => client.set(key, value)?,
+        Request::Get(key) => match client.get(key)? {
+            Some(value) => println!("{value}"),
+            None => println!("Key not found"),
+        },
+        Request::Rm(key) => match client.remove(key) {
+            Err(kvs::Error::RemoveNonexistKey) => {
+                println!("Key not found");
+                return Err(anyhow!("Key not found"));
+            }
+            result => result?,
+        },
+        request => unreachable!("{request:?} is not sharded"),
+    }
+    Ok(())
+}
+
+fn scan_page(client: &mut KvsClient, prefix: &str, start: &str) -> Result<Vec<(String, String)>> {
+    let request = Request::ScanPage(prefix.to_owned(), start.to_owned(), DUMP_PAGE_SIZE as u32);
+    match client.request(request)? {
+        Response::Pairs(pairs) => Ok(pairs),
+        Response::Err => Err(anyhow!("Server internal error")),
+        Response::Busy => Err(anyhow!("Server busy, try again later")),
+        response => Err(anyhow!("Unexpected response: {response:?}")),
+    }
+}
+
+/// Where `dump` writes the records
+enum Output<W: Write> {
+    Jsonl(io::BufWriter<W>),
+    Csv(Box<csv::Writer<W>>),
+}
+
+impl<W: Write> Output<W> {
+    fn new(writer: W, format: Format) -> Self {
+        match format {
+            Format::Jsonl => Output::Jsonl(io::BufWriter::new(writer)),
+            Format::Csv => Output::Csv(Box::new(
+                csv::WriterBuilder::new()
+                    .has_headers(false)
+                    .from_writer(writer),
+            )),
+        }
+    }
+    fn write(&mut self, record: Record) -> Result<()> {
+        match self {
+            Output::Jsonl(writer) => {
+                serde_json::to_writer(&mut *writer, &record)?;
+                writeln!(writer)?;
+            }
+            Output::Csv(writer) => writer.serialize(record)?,
+        }
+        Ok(())
+    }
+    fn flush(&mut self) -> Result<()> {
+        match self {
+            Output::Jsonl(writer) => writer.flush()?,
+            Output::Csv(writer) => writer.flush()?,
+        }
+        Ok(())
+    }
+}
+
+/// Write the pairs to stdout a page at a time, `next_page` gets the page from a key
+fn dump<F>(prefix: &str, format: Format, mut next_page: F) -> Result<()>
+where
+    F: FnMut(&str) -> Result<Vec<(String, String)>>,
+{
+    let mut output = Output::new(io::stdout().lock(), format);
+    let mut start = prefix.to_owned();
+    let mut total = 0;
+    loop {
+        let page = next_page(&start)?;
+        let last_page = page.len() < DUMP_PAGE_SIZE;
+        if let Some((key, _)) = page.last() {
+            // The smallest key after the last one
+            start = format!("{key}\0");
+        }
+        for (key, value) in page {
+            output.write(Record { key, value })?;
+            total += 1;
+            if total % PROGRESS_INTERVAL == 0 {
+                eprintln!("Dumped {total} records");
+            }
+        }
+        output.flush()?;
+        if last_page {
+            break;
+        }
+    }
+    eprintln!("Dumped {total} records");
+    Ok(())
+}
+
+/// Print events until the server closes the connection
+fn watch(prefix: String, addr: SocketAddr) -> Result<()> {
+    let mut client = KvsClient::new(addr);
+    for event in client.watch(prefix)? {
+        match event? {
+            Event::Set(key, value) => println!("set {key} {value}"),
+            Event::Rm(key) => println!("rm {key}"),
+        }
     }
     Ok(())
 }
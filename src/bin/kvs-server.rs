@@ -8,15 +8,28 @@ use std::{
     path::{Path, PathBuf},
     sync::{atomic::AtomicBool, Arc},
     thread,
+    time::Duration,
 };
 
 use anyhow::{anyhow, Result};
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 use env_logger::Target;
-use kvs::{thread_pool::SharedQueueThreadPool, KvStore, KvsEngine, KvsServer, SledKvsEngine};
+use kvs::{
+    raft::{ClusterConfig, RaftEngine, RaftNode, TcpTransport},
+    thread_pool::{panic_message, FullQueuePolicy, PoolConfig, Scheduler, SharedQueueThreadPool},
+    CacheConfig, CacheEngine, EvictionPolicy, KvStoreOptions, KvsEngine, KvsServer, MemKvsEngine,
+    Replica, SledKvsEngine,
+};
 
 const DEFAULT_SOCKET_ADDR: SocketAddr =
     SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 4000);
+const MIN_WORKERS: usize = 4;
+/// Busy pools grow up to this many times the workers kept when idle
+const MAX_WORKERS_PER_MIN: u32 = 4;
+const WORKER_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+/// Connections waiting for a worker, more are refused as busy
+const MAX_QUEUED_CONNECTIONS: usize = 1024;
+const POOL_STATS_INTERVAL: Duration = Duration::from_secs(60);
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -28,12 +41,49 @@ pub struct Cli {
     addr: SocketAddr,
     #[arg(long)]
     engine: Option<String>,
+    /// How many latest sequence numbers the kvs engine keeps history for
+    #[arg(long, default_value_t = 0)]
+    history_retention: u64,
+    /// How many decoded records the kvs engine keeps in memory for hot keys, 0 to disable
+    #[arg(long, default_value_t = 0)]
+    value_cache: usize,
+    /// Follow the primary kvs-server at the address, serving reads only
+    #[arg(long)]
+    replica_of: Option<SocketAddr>,
+    /// JSON file listing the nodes of a Raft cluster, which replicates the writes
+    #[arg(long, requires = "node_id")]
+    cluster: Option<PathBuf>,
+    /// Id of this node in the cluster, it listens on the address given there
+    #[arg(long, requires = "cluster")]
+    node_id: Option<u64>,
+    /// Evict keys to keep the pairs within the size, like `512K`, `64M` or `1G`
+    #[arg(long, value_parser = parse_size)]
+    max_memory: Option<u64>,
+    /// Which keys to evict first, `lru` if not given
+    #[arg(long, value_enum, requires = "max_memory")]
+    eviction_policy: Option<Policy>,
+    /// Only evict keys set with a TTL, writes fail if nothing else can make room
+    #[arg(long, requires = "max_memory")]
+    evict_ttl_only: bool,
+    /// Directory backups requested by clients are written under, backups are refused if not given
+    #[arg(long)]
+    backup_dir: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Policy {
+    /// Least recently used
+    Lru,
+    /// Least frequently used
+    Lfu,
 }
 
 #[derive(Debug)]
 enum Engine {
     Kvs,
     Sled,
+    /// Keeps nothing on disk
+    Memory,
 }
 
 impl Display for Engine {
@@ -41,6 +91,7 @@ impl Display for Engine {
         match self {
             Engine::Kvs => write!(f, "kvs"),
             Engine::Sled => write!(f, "sled"),
+            Engine::Memory => write!(f, "memory"),
         }
     }
 }
@@ -60,16 +111,128 @@ fn main() -> Result<()> {
     log::info!("engine name: {real_engine}",);
     log::info!("listen on https://{}", cli.addr);
 
-    fn run_engine(engine: impl KvsEngine, addr: SocketAddr) -> Result<()> {
+    fn run_engine(
+        engine: impl KvsEngine,
+        addr: SocketAddr,
+        read_only: bool,
+        backup_dir: Option<PathBuf>,
+    ) -> Result<()> {
         let shutdown = Arc::new(AtomicBool::new(false));
-        let n_workers = thread::available_parallelism().unwrap().get();
-        let server = KvsServer::<_, SharedQueueThreadPool>::new(engine, shutdown, n_workers);
+        // A connection holds its worker until closed, such as one running a transaction,
+        // so keep some workers even on a small machine, and start more for bursts of clients
+        let n_workers = thread::available_parallelism()
+            .unwrap()
+            .get()
+            .max(MIN_WORKERS) as u32;
+        let pool = SharedQueueThreadPool::with_config(
+            PoolConfig::new(n_workers)
+                .max_threads(n_workers * MAX_WORKERS_PER_MIN)
+                .idle_timeout(WORKER_IDLE_TIMEOUT)
+                .queue_capacity(MAX_QUEUED_CONNECTIONS, FullQueuePolicy::Reject)
+                .on_panic(|payload| {
+                    log::error!("connection handler panicked: {}", panic_message(payload))
+                }),
+        )?;
+        let monitor = pool.monitor();
+        pool.schedule_every(POOL_STATS_INTERVAL, move || {
+            log::info!("thread pool: {:?}", monitor.stats())
+        });
+        let mut server = KvsServer::with_pool(engine, shutdown, pool);
+        if read_only {
+            server = server.read_only();
+        }
+        if let Some(backup_dir) = backup_dir {
+            server = server.backup_dir(backup_dir);
+        }
         Ok(server.listen_on(addr)?)
     }
 
-    match real_engine {
-        Engine::Kvs => run_engine(KvStore::open(path)?, cli.addr)?,
-        Engine::Sled => run_engine(SledKvsEngine::open(path)?, cli.addr)?,
+    /// Serve the engine, as a cache if it's configured
+    fn serve(
+        engine: impl KvsEngine,
+        addr: SocketAddr,
+        cache: Option<CacheConfig>,
+        backup_dir: Option<PathBuf>,
+    ) -> Result<()> {
+        match cache {
+            Some(config) => run_engine(CacheEngine::new(engine, config)?, addr, false, backup_dir),
+            None => run_engine(engine, addr, false, backup_dir),
+        }
+    }
+
+    /// Replicate the engine with the other nodes of the cluster
+    fn run_cluster(
+        engine: impl KvsEngine,
+        path: &Path,
+        cluster: &Path,
+        id: u64,
+        backup_dir: Option<PathBuf>,
+    ) -> Result<()> {
+        let config = ClusterConfig::load(cluster)?;
+        let addr = config
+            .node(id)
+            .ok_or_else(|| anyhow!("Node {id} is not in the cluster"))?
+            .addr;
+        log::info!("node {id} of cluster, listen on https://{addr}");
+        let (transport, inbox) = TcpTransport::start(id, &config)?;
+        let node = RaftNode::start(id, config.peers(id), path, engine, transport, inbox)?;
+        let addrs = config
+            .nodes
+            .iter()
+            .map(|node| (node.id, node.addr))
+            .collect();
+        run_engine(RaftEngine::new(node, addrs), addr, false, backup_dir)
+    }
+
+    let kvs_options = KvStoreOptions::new()
+        .retention(cli.history_retention)
+        .value_cache(cli.value_cache);
+    let cache = cli.max_memory.map(|max_memory| {
+        let policy = match cli.eviction_policy.unwrap_or(Policy::Lru) {
+            Policy::Lru => EvictionPolicy::Lru,
+            Policy::Lfu => EvictionPolicy::Lfu,
+        };
+        let config = CacheConfig::new(max_memory).policy(policy);
+        if cli.evict_ttl_only {
+            config.ttl_only()
+        } else {
+            config
+        }
+    });
+    if cache.is_some() && (cli.cluster.is_some() || cli.replica_of.is_some()) {
+        return Err(anyhow!("Only a standalone server can be a cache"));
+    }
+
+    let backup_dir = cli.backup_dir;
+    if let (Some(cluster), Some(id)) = (&cli.cluster, cli.node_id) {
+        if cli.replica_of.is_some() {
+            return Err(anyhow!("A cluster node can't be a replica"));
+        }
+        match real_engine {
+            Engine::Kvs => run_cluster(kvs_options.open(&path)?, &path, cluster, id, backup_dir)?,
+            Engine::Sled => {
+                run_cluster(SledKvsEngine::open(&path)?, &path, cluster, id, backup_dir)?
+            }
+            // Raft compacts its log into the engine, which must survive restarts
+            Engine::Memory => return Err(anyhow!("The memory engine can't be a cluster node")),
+        }
+        return Ok(());
+    }
+
+    match (real_engine, cli.replica_of) {
+        (Engine::Kvs, Some(primary)) => {
+            log::info!("replica of {primary}");
+            let mut replica = Replica::open(path, primary)?;
+            let store = replica.store();
+            thread::spawn(move || replica.run());
+            run_engine(store, cli.addr, true, backup_dir)?
+        }
+        (Engine::Sled | Engine::Memory, Some(_)) => {
+            return Err(anyhow!("Only the kvs engine can be a replica"))
+        }
+        (Engine::Kvs, None) => serve(kvs_options.open(path)?, cli.addr, cache, backup_dir)?,
+        (Engine::Sled, None) => serve(SledKvsEngine::open(path)?, cli.addr, cache, backup_dir)?,
+        (Engine::Memory, None) => serve(MemKvsEngine::new(), cli.addr, cache, backup_dir)?,
     }
 
     Ok(())
@@ -80,12 +243,18 @@ fn get_real_engine(cli_engine: Option<String>, path: &Path) -> Result<Engine> {
         match engine.as_str() {
             "kvs" => Some(Engine::Kvs),
             "sled" => Some(Engine::Sled),
+            "memory" => Some(Engine::Memory),
             _ => return Err(anyhow!("Not a valid engine: {}", engine)),
         }
     } else {
         None
     };
 
+    // The memory engine ignores what's in the directory
+    if let Some(Engine::Memory) = sepcified_engine {
+        return Ok(Engine::Memory);
+    }
+
     // empty
     let real_engine = if path.exists() {
         // sled
@@ -112,3 +281,16 @@ fn get_real_engine(cli_engine: Option<String>, path: &Path) -> Result<Engine> {
     };
     Ok(real_engine)
 }
+
+/// Parse a size in bytes, with an optional `K`, `M` or `G` suffix of binary units
+fn parse_size(size: &str) -> Result<u64> {
+    let (digits, unit) = match size.char_indices().last() {
+        Some((i, 'K' | 'k')) => (&size[..i], 1 << 10),
+        Some((i, 'M' | 'm')) => (&size[..i], 1 << 20),
+        Some((i, 'G' | 'g')) => (&size[..i], 1 << 30),
+        _ => (size, 1),
+    };
+    let n: u64 = digits.parse()?;
+    n.checked_mul(unit)
+        .ok_or_else(|| anyhow!("Size {size} is too large"))
+}
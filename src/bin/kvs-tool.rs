@@ -0,0 +1,427 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Display,
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::{anyhow, Result};
+use clap::{Parser, Subcommand};
+use kvs::{log_file_ids, Command, DataFileReader, KvStore, KvsEngine, LogRecord, SledKvsEngine};
+
+/// Pairs read in one `scan_page` while migrating
+const PAGE_SIZE: usize = 1000;
+
+#[derive(Parser)]
+#[command(author, version, about, long_about = None)]
+#[command(arg_required_else_help(true))]
+#[command(disable_help_subcommand(true))]
+pub struct Cli {
+    #[command(subcommand)]
+    commands: Commands,
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    /// Replay all data files and report bad records
+    Verify { dir: PathBuf },
+    /// Report key count, live and dead bytes per file and the largest keys
+    Stats {
+        dir: PathBuf,
+        /// How many of the largest keys to report
+        #[arg(long, default_value_t = 10)]
+        top: usize,
+    },
+    /// Print all records in log order
+    Dump { dir: PathBuf },
+    /// Copy every live key into an empty directory of another engine
+    Migrate {
+        /// `kvs:<dir>` or `sled:<dir>`
+        #[arg(long)]
+        from: EngineDir,
+        /// `kvs:<dir>` or `sled:<dir>`, must not contain data
+        #[arg(long)]
+        to: EngineDir,
+    },
+}
+
+#[derive(Clone)]
+enum EngineDir {
+    Kvs(PathBuf),
+    Sled(PathBuf),
+}
+
+impl EngineDir {
+    fn path(&self) -> &Path {
+        match self {
+            EngineDir::Kvs(path) | EngineDir::Sled(path) => path,
+        }
+    }
+}
+
+impl FromStr for EngineDir {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("kvs", dir)) => Ok(EngineDir::Kvs(dir.into())),
+            Some(("sled", dir)) => Ok(EngineDir::Sled(dir.into())),
+            _ => Err(format!("'{s}' is not in form of kvs:<dir> or sled:<dir>")),
+        }
+    }
+}
+
+impl Display for EngineDir {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EngineDir::Kvs(path) => write!(f, "kvs:{}", path.display()),
+            EngineDir::Sled(path) => write!(f, "sled:{}", path.display()),
+        }
+    }
+}
+
+fn main() -> Result<()> {
+    let cli = Cli::parse();
+    match cli.commands {
+        Commands::Verify { dir } => verify(&dir),
+        Commands::Stats { dir, top } => stats(&dir, top),
+        Commands::Dump { dir } => dump(&dir),
+        Commands::Migrate { from, to } => migrate(&from, &to),
+    }
+}
+
+/// Data file ids of a kvs data directory.
+///
+/// The kvs and rwlock engines share the same layout, sled is refused.
+fn kvs_data_files(dir: &Path) -> Result<Vec<u32>> {
+    if dir.join("conf").exists() {
+        return Err(anyhow!("{} is a sled directory", dir.display()));
+    }
+    if !dir.join("kvs").exists() {
+        return Err(anyhow!("{} is not a kvs directory", dir.display()));
+    }
+    Ok(log_file_ids(dir)?)
+}
+
+fn verify(dir: &Path) -> Result<()> {
+    let file_ids = kvs_data_files(dir)?;
+    let mut n_records = 0;
+    let mut n_bad_files = 0;
+    for file_id in file_ids {
+        let file_len = fs::metadata(dir.join(format!("{file_id}.dat")))?.len();
+        let mut reader = DataFileReader::open(dir, file_id)?;
+        while let Some(record) = reader.next() {
+            match record {
+                Ok(_) => n_records += 1,
+                Err(e) => {
+                    let file_offset = reader.file_offset();
+                    println!(
+                        "{file_id}.dat: bad record at offset {file_offset}, \
+                         {} bytes unreadable: {e}",
+                        file_len - file_offset as u64
+                    );
+                    n_bad_files += 1;
+                }
+            }
+        }
+    }
+    println!("{n_records} records ok, {n_bad_files} files with bad records");
+    if n_bad_files > 0 {
+        return Err(anyhow!("Data directory is corrupted"));
+    }
+    Ok(())
+}
+
+/// Where the live record of a key is
+#[derive(Clone, Copy)]
+struct LiveRecord {
+    file_id: u32,
+    file_offset: u32,
+    len: u32,
+}
+
+/// Replay the data files to find the live record of each key, as `KvStore::open` does.
+///
+/// Total bytes of each file are returned as well.
+fn replay(dir: &Path) -> Result<(HashMap<String, LiveRecord>, BTreeMap<u32, u64>)> {
+    let file_ids = kvs_data_files(dir)?;
+    let mut key_dir = HashMap::new();
+    // key -> sequence number of the latest write, including removal
+    let mut latest_seqs = HashMap::new();
+    let mut last_seq = 0;
+    // file id -> total bytes
+    let mut file_bytes = BTreeMap::new();
+    for file_id in file_ids {
+        let total = file_bytes.entry(file_id).or_insert(0u64);
+        for record in DataFileReader::open(dir, file_id)? {
+            let LogRecord {
+                file_id,
+                file_offset,
+                len,
+                command,
+            } = record?;
+            *total += len as u64;
+            let seq = match command.seq() {
+                0 => last_seq + 1,
+                seq => seq,
+            };
+            last_seq = last_seq.max(seq);
+            let live = LiveRecord {
+                file_id,
+                file_offset,
+                len,
+            };
+            apply_command(&mut key_dir, &mut latest_seqs, command, live, seq);
+        }
+    }
+    Ok((key_dir, file_bytes))
+}
+
+/// Replay the command as `KvStore::open` does.
+///
+/// A command not newer than the latest write of the key is left by an interrupted compaction
+/// and ignored.
+fn apply_command(
+    key_dir: &mut HashMap<String, LiveRecord>,
+    latest_seqs: &mut HashMap<String, u64>,
+    command: Command,
+    live: LiveRecord,
+    seq: u64,
+) {
+    match command {
+        Command::Set { key, .. } | Command::Rm { key, .. }
+            if latest_seqs.get(&key).is_some_and(|&latest| latest >= seq) => {}
+        Command::Set { key, .. } => {
+            latest_seqs.insert(key.clone(), seq);
+            key_dir.insert(key, live);
+        }
+        Command::Rm { key, .. } => {
+            latest_seqs.insert(key.clone(), seq);
+            key_dir.remove(&key);
+        }
+        // Count the whole record for each key of the transaction
+        Command::Txn(commands) => {
+            for command in commands {
+                apply_command(key_dir, latest_seqs, command, live, seq);
+            }
+        }
+    }
+}
+
+fn stats(dir: &Path, top: usize) -> Result<()> {
+    let (key_dir, file_bytes) = replay(dir)?;
+    let mut live_bytes = BTreeMap::new();
+    for live in key_dir.values() {
+        *live_bytes.entry(live.file_id).or_insert(0u64) += live.len as u64;
+    }
+
+    println!("keys: {}", key_dir.len());
+    println!("{:>8} {:>12} {:>12}", "file", "live bytes", "dead bytes");
+    for (file_id, total) in file_bytes {
+        let live = live_bytes.get(&file_id).copied().unwrap_or(0);
+        println!(
+            "{:>8} {live:>12} {:>12}",
+            format!("{file_id}.dat"),
+            total - live
+        );
+    }
+
+    let mut largest = key_dir.into_iter().collect::<Vec<_>>();
+    largest.sort_unstable_by(|(a_key, a), (b_key, b)| {
+        b.len.cmp(&a.len).then_with(|| a_key.cmp(b_key))
+    });
+    println!("largest keys:");
+    for (key, live) in largest.into_iter().take(top) {
+        println!("{:>8} {}.dat {key}", live.len, live.file_id);
+    }
+    Ok(())
+}
+
+fn dump(dir: &Path) -> Result<()> {
+    for file_id in kvs_data_files(dir)? {
+        for record in DataFileReader::open(dir, file_id)? {
+            let LogRecord {
+                file_id,
+                file_offset,
+                len,
+                command,
+            } = record?;
+            println!(
+                "{file_id} {file_offset} {len} {}",
+                serde_json::to_string(&command)?
+            );
+        }
+    }
+    Ok(())
+}
+
+fn migrate(from: &EngineDir, to: &EngineDir) -> Result<()> {
+    // Check the source really holds data of the engine, so a typo won't migrate nothing
+    match from {
+        EngineDir::Kvs(path) => _ = kvs_data_files(path)?,
+        EngineDir::Sled(path) if !path.join("conf").exists() => {
+            return Err(anyhow!("{} is not a sled directory", path.display()));
+        }
+        EngineDir::Sled(_) => {}
+    }
+    let to_path = to.path();
+    if to_path.exists() && to_path.read_dir()?.next().is_some() {
+        return Err(anyhow!("{} is not empty", to_path.display()));
+    }
+    if from.path() == to_path {
+        return Err(anyhow!("Can't migrate into the source directory"));
+    }
+
+    let n_keys = match to {
+        EngineDir::Kvs(path) => {
+            let dest = KvStore::open(path)?;
+            let n_keys = copy_from(from, &dest)?;
+            dest.flush()?;
+            n_keys
+        }
+        EngineDir::Sled(path) => {
+            let dest = SledKvsEngine::open(path)?;
+            let n_keys = copy_from(from, &dest)?;
+            dest.flush()?;
+            n_keys
+        }
+    };
+    println!("Migrated {n_keys} keys from {from} to {to}");
+    Ok(())
+}
+
+/// Copy every live key of the source, then check the destination holds the same count.
+///
+/// Opening a store writes to its directory, so the source is never opened:
+/// kvs data files are read directly, and sled is opened on a copy.
+fn copy_from(from: &EngineDir, dest: &impl KvsEngine) -> Result<usize> {
+    let n_keys = match from {
+        EngineDir::Kvs(path) => copy_data_files(path, dest)?,
+        EngineDir::Sled(path) => {
+            let copy = TempDir::copy_of(path)?;
+            let source = SledKvsEngine::open(&copy.0)?;
+            let mut n_keys = 0;
+            for_each_page(&source, |page| {
+                for (key, value) in page {
+                    dest.set(key, value)?;
+                    n_keys += 1;
+                    report_progress(n_keys);
+                }
+                Ok(())
+            })?;
+            n_keys
+        }
+    };
+    let mut n_copied = 0;
+    for_each_page(dest, |page| {
+        n_copied += page.len();
+        Ok(())
+    })?;
+    if n_copied != n_keys {
+        return Err(anyhow!(
+            "Destination holds {n_copied} keys, but {n_keys} keys are in the source"
+        ));
+    }
+    Ok(n_keys)
+}
+
+/// Copy the live records in log order, only the positions of the keys are kept in memory
+fn copy_data_files(dir: &Path, dest: &impl KvsEngine) -> Result<usize> {
+    let (mut key_dir, _) = replay(dir)?;
+    let mut n_keys = 0;
+    for file_id in kvs_data_files(dir)? {
+        for record in DataFileReader::open(dir, file_id)? {
+            let LogRecord {
+                file_id,
+                file_offset,
+                command,
+                ..
+            } = record?;
+            let commands = match command {
+                Command::Txn(commands) => commands,
+                command => vec![command],
+            };
+            // The last write of a key in the transaction is the one kept
+            for command in commands.into_iter().rev() {
+                let Command::Set { key, value, .. } = command else {
+                    continue;
+                };
+                let is_live = key_dir
+                    .get(&key)
+                    .is_some_and(|live| live.file_id == file_id && live.file_offset == file_offset);
+                if is_live {
+                    key_dir.remove(&key);
+                    dest.set(key, value)?;
+                    n_keys += 1;
+                    report_progress(n_keys);
+                }
+            }
+        }
+    }
+    Ok(n_keys)
+}
+
+fn report_progress(n_keys: usize) {
+    if n_keys % 10000 == 0 {
+        eprintln!("Copied {n_keys} keys");
+    }
+}
+
+/// Call `f` on each page of all pairs, so they are never all in memory
+fn for_each_page<F>(engine: &impl KvsEngine, mut f: F) -> Result<()>
+where
+    F: FnMut(Vec<(String, String)>) -> Result<()>,
+{
+    let mut start = String::new();
+    loop {
+        let page = engine.scan_page("", &start, PAGE_SIZE)?;
+        let last_page = page.len() < PAGE_SIZE;
+        if let Some((key, _)) = page.last() {
+            // The smallest key after the last one
+            start = format!("{key}\0");
+        }
+        f(page)?;
+        if last_page {
+            return Ok(());
+        }
+    }
+}
+
+/// A directory removed when dropped
+struct TempDir(PathBuf);
+
+impl TempDir {
+    /// Copy the directory recursively into a new temporary directory
+    fn copy_of(dir: &Path) -> Result<Self> {
+        let temp_dir = TempDir(std::env::temp_dir().join(format!(
+            "kvs-tool-{}-{}",
+            std::process::id(),
+            dir.file_name().unwrap_or_default().to_string_lossy()
+        )));
+        copy_dir(dir, &temp_dir.0)?;
+        Ok(temp_dir)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_dir_all(&self.0) {
+            eprintln!("Failed to remove {}: {e}", self.0.display());
+        }
+    }
+}
+
+fn copy_dir(from: &Path, to: &Path) -> Result<()> {
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let to = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &to)?;
+        } else {
+            fs::copy(entry.path(), to)?;
+        }
+    }
+    Ok(())
+}
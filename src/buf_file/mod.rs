@@ -1,5 +1,3 @@
-mod buf_reader;
 mod buf_writer;
 
-pub use buf_reader::BufReader;
 pub use buf_writer::BufWriter;
@@ -0,0 +1,470 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    path::Path,
+    sync::{mpsc::Receiver, Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    Error, Event, KvsEngine, KvsSnapshot, LogPosition, LogTail, ReadSet, Result, WriteSet,
+};
+
+/// Memory a pair is assumed to take besides its key and value
+const ENTRY_OVERHEAD: u64 = 64;
+
+/// Which keys `CacheEngine` evicts first
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    /// The least recently used
+    #[default]
+    Lru,
+    /// The least frequently used, the least recently used among equals
+    Lfu,
+}
+
+/// Settings of a `CacheEngine`
+#[derive(Clone, Copy, Debug)]
+pub struct CacheConfig {
+    max_memory: u64,
+    policy: EvictionPolicy,
+    ttl_only: bool,
+}
+
+impl CacheConfig {
+    /// Keep the pairs within `max_memory` bytes, evicting the least recently used
+    pub fn new(max_memory: u64) -> Self {
+        Self {
+            max_memory,
+            policy: EvictionPolicy::default(),
+            ttl_only: false,
+        }
+    }
+    /// Evict by the policy
+    pub fn policy(mut self, policy: EvictionPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+    /// Only evict keys set with a TTL, writes fail with `Error::CacheFull` if they can't make room
+    pub fn ttl_only(mut self) -> Self {
+        self.ttl_only = true;
+        self
+    }
+}
+
+/// Memory use and counters of a `CacheEngine`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Estimated bytes taken by the pairs
+    pub used_memory: u64,
+    /// The budget of `used_memory`
+    pub max_memory: u64,
+    /// Number of pairs
+    pub keys: u64,
+    /// Reads finding the key
+    pub hits: u64,
+    /// Reads not finding the key
+    pub misses: u64,
+    /// Keys removed to make room for writes
+    pub evictions: u64,
+    /// Keys removed because their TTL passed
+    pub expirations: u64,
+}
+
+/// Wraps an engine to keep its pairs within a memory budget, evicting keys by a policy.
+///
+/// Keys may be set with a TTL, after which they're gone. The TTLs are only kept in memory,
+/// so they're lost on restart.
+#[derive(Clone)]
+pub struct CacheEngine<E> {
+    engine: E,
+    /// Held by writes, evictions and expirations,
+    /// so the engine and the state see them in the same order
+    writer: Arc<Mutex<()>>,
+    /// Only held for bookkeeping, never while the engine is used
+    state: Arc<Mutex<CacheState>>,
+}
+
+/// A snapshot of a `CacheEngine`, its keys are gone once their TTLs pass
+pub struct CacheSnapshot<S> {
+    snapshot: S,
+    /// Keys with a TTL when the snapshot was taken, by when they expire
+    deadlines: HashMap<String, Instant>,
+}
+
+impl<S> CacheSnapshot<S> {
+    fn is_expired(&self, key: &str, now: Instant) -> bool {
+        self.deadlines
+            .get(key)
+            .is_some_and(|deadline| *deadline <= now)
+    }
+}
+
+impl<S: KvsSnapshot> KvsSnapshot for CacheSnapshot<S> {
+    fn get(&self, key: &str) -> Result<Option<String>> {
+        if self.is_expired(key, Instant::now()) {
+            return Ok(None);
+        }
+        self.snapshot.get(key)
+    }
+    fn scan(&self, prefix: &str) -> Result<Vec<(String, String)>> {
+        let now = Instant::now();
+        let mut pairs = self.snapshot.scan(prefix)?;
+        pairs.retain(|(key, _)| !self.is_expired(key, now));
+        Ok(pairs)
+    }
+}
+
+struct CacheState {
+    config: CacheConfig,
+    entries: HashMap<String, CacheEntry>,
+    /// Keys which may be evicted, the first goes first
+    candidates: BTreeSet<(Rank, String)>,
+    /// Keys with a TTL, by when they expire
+    deadlines: BTreeSet<(Instant, String)>,
+    /// Bumped on each access, to order the keys by recency
+    clock: u64,
+    stats: CacheStats,
+}
+
+struct CacheEntry {
+    size: u64,
+    last_used: u64,
+    uses: u64,
+    expires_at: Option<Instant>,
+}
+
+/// Position of a key among the candidates, smaller is evicted first
+type Rank = (u64, u64);
+
+impl CacheState {
+    fn rank(&self, entry: &CacheEntry) -> Rank {
+        match self.config.policy {
+            EvictionPolicy::Lru => (entry.last_used, 0),
+            EvictionPolicy::Lfu => (entry.uses, entry.last_used),
+        }
+    }
+    fn is_candidate(&self, entry: &CacheEntry) -> bool {
+        !self.config.ttl_only || entry.expires_at.is_some()
+    }
+    fn is_expired(&self, key: &str, now: Instant) -> bool {
+        matches!(
+            self.entries.get(key),
+            Some(CacheEntry { expires_at: Some(deadline), .. }) if *deadline <= now
+        )
+    }
+    /// Keys whose TTL has passed
+    fn expired(&self, now: Instant) -> Vec<String> {
+        self.deadlines
+            .iter()
+            .take_while(|(deadline, _)| *deadline <= now)
+            .map(|(_, key)| key.clone())
+            .collect()
+    }
+    /// Keys to evict for the writes to fit, they are given with their sizes, 0 for removal.
+    ///
+    /// The written keys themselves are never evicted, nor the keys read by the transaction,
+    /// whose commit would fail validation.
+    fn victims(&self, writes: &[(&str, u64)], reads: &ReadSet) -> Result<Vec<String>> {
+        let mut needed = self.stats.used_memory;
+        for &(key, size) in writes {
+            needed += size;
+            needed -= self.entries.get(key).map_or(0, |entry| entry.size);
+        }
+        let mut candidates = self.candidates.iter().map(|(_, key)| key).filter(|key| {
+            writes.iter().all(|(written, _)| written != key) && !reads.contains_key(*key)
+        });
+        let mut victims = Vec::new();
+        while needed > self.config.max_memory {
+            let victim = candidates.next().ok_or(Error::CacheFull)?;
+            needed -= self.entries[victim].size;
+            victims.push(victim.clone());
+        }
+        Ok(victims)
+    }
+    /// Stop tracking the key, returning its entry
+    fn forget(&mut self, key: &str) -> Option<CacheEntry> {
+        let entry = self.entries.remove(key)?;
+        self.candidates.remove(&(self.rank(&entry), key.to_owned()));
+        if let Some(deadline) = entry.expires_at {
+            self.deadlines.remove(&(deadline, key.to_owned()));
+        }
+        self.stats.used_memory -= entry.size;
+        self.stats.keys -= 1;
+        Some(entry)
+    }
+    /// Track a written key, as used once more
+    fn track(&mut self, key: String, size: u64, expires_at: Option<Instant>) {
+        let uses = self.forget(&key).map_or(0, |entry| entry.uses);
+        self.clock += 1;
+        let entry = CacheEntry {
+            size,
+            last_used: self.clock,
+            uses: uses + 1,
+            expires_at,
+        };
+        if self.is_candidate(&entry) {
+            self.candidates.insert((self.rank(&entry), key.clone()));
+        }
+        if let Some(deadline) = expires_at {
+            self.deadlines.insert((deadline, key.clone()));
+        }
+        self.stats.used_memory += size;
+        self.stats.keys += 1;
+        self.entries.insert(key, entry);
+    }
+    /// Mark the key as used by a read
+    fn touch(&mut self, key: &str) {
+        let Some(mut entry) = self.entries.remove(key) else {
+            return;
+        };
+        let is_candidate = self.is_candidate(&entry);
+        if is_candidate {
+            self.candidates.remove(&(self.rank(&entry), key.to_owned()));
+        }
+        self.clock += 1;
+        entry.last_used = self.clock;
+        entry.uses += 1;
+        if is_candidate {
+            self.candidates.insert((self.rank(&entry), key.to_owned()));
+        }
+        self.entries.insert(key.to_owned(), entry);
+    }
+}
+
+fn pair_size(key: &str, value: &str) -> u64 {
+    (key.len() + value.len()) as u64 + ENTRY_OVERHEAD
+}
+
+impl<E: KvsEngine> CacheEngine<E> {
+    /// Wrap the engine, evicting its pairs right away if they exceed the budget
+    pub fn new(engine: E, config: CacheConfig) -> Result<Self> {
+        let mut state = CacheState {
+            config,
+            entries: HashMap::new(),
+            candidates: BTreeSet::new(),
+            deadlines: BTreeSet::new(),
+            clock: 0,
+            stats: CacheStats {
+                max_memory: config.max_memory,
+                ..CacheStats::default()
+            },
+        };
+        for (key, value) in engine.scan("")? {
+            let size = pair_size(&key, &value);
+            state.track(key, size, None);
+        }
+        let cache = Self {
+            engine,
+            writer: Arc::default(),
+            state: Arc::new(Mutex::new(state)),
+        };
+        let victims = cache.lock().victims(&[], &ReadSet::new());
+        match victims {
+            Ok(victims) => cache.evict(&victims)?,
+            // Whatever doesn't fit with `ttl_only` stays, there's nothing to evict
+            Err(Error::CacheFull) => {}
+            Err(e) => return Err(e),
+        }
+        Ok(cache)
+    }
+    /// Set the key, which expires after `ttl` if it's given
+    fn write(&self, key: String, value: String, ttl: Option<Duration>) -> Result<()> {
+        let _writer = self.write_lock();
+        self.remove_expired()?;
+        let size = pair_size(&key, &value);
+        let victims = self.lock().victims(&[(&key, size)], &ReadSet::new())?;
+        self.engine.set(key.clone(), value)?;
+        self.evict(&victims)?;
+        self.lock()
+            .track(key, size, ttl.map(|ttl| Instant::now() + ttl));
+        Ok(())
+    }
+    fn lock(&self) -> MutexGuard<'_, CacheState> {
+        self.state.lock().unwrap()
+    }
+    fn write_lock(&self) -> MutexGuard<'_, ()> {
+        self.writer.lock().unwrap()
+    }
+    /// Remove the keys whose TTL has passed, the writer lock must be held
+    fn remove_expired(&self) -> Result<()> {
+        let expired = self.lock().expired(Instant::now());
+        for key in expired {
+            self.drop_key(&key)?;
+            self.lock().stats.expirations += 1;
+        }
+        Ok(())
+    }
+    /// Remove the key if its TTL has passed, returning whether it did.
+    ///
+    /// The writer lock must be held.
+    fn remove_if_expired(&self, key: &str) -> Result<bool> {
+        if !self.lock().is_expired(key, Instant::now()) {
+            return Ok(false);
+        }
+        self.drop_key(key)?;
+        self.lock().stats.expirations += 1;
+        Ok(true)
+    }
+    /// Remove the key if its TTL has passed, returning whether it did, for reads.
+    ///
+    /// The writer lock is only taken if the key has expired.
+    fn expire_key(&self, key: &str) -> Result<bool> {
+        if !self.lock().is_expired(key, Instant::now()) {
+            return Ok(false);
+        }
+        let _writer = self.write_lock();
+        self.remove_if_expired(key)
+    }
+    /// Remove the keys whose TTL has passed before a read of many keys
+    fn expire_keys(&self) -> Result<()> {
+        let _writer = self.write_lock();
+        self.remove_expired()
+    }
+    /// Evict the keys picked by `CacheState::victims`, the writer lock must be held
+    fn evict(&self, victims: &[String]) -> Result<()> {
+        for victim in victims {
+            self.drop_key(victim)?;
+            self.lock().stats.evictions += 1;
+        }
+        Ok(())
+    }
+    /// Remove the key from the engine and stop tracking it
+    fn drop_key(&self, key: &str) -> Result<()> {
+        match self.engine.remove(key.to_owned()) {
+            Ok(()) | Err(Error::RemoveNonexistKey) => {}
+            Err(e) => return Err(e),
+        }
+        self.lock().forget(key);
+        Ok(())
+    }
+}
+
+impl<E: KvsEngine> KvsEngine for CacheEngine<E> {
+    type Snapshot = CacheSnapshot<E::Snapshot>;
+
+    /// Set the key without TTL, dropping the one it had
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.write(key, value, None)
+    }
+    fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.write(key, value, Some(ttl))
+    }
+    fn get(&self, key: &str) -> Result<Option<String>> {
+        if self.expire_key(key)? {
+            self.lock().stats.misses += 1;
+            return Ok(None);
+        }
+        let value = self.engine.get(key)?;
+        let mut state = self.lock();
+        if value.is_some() {
+            state.stats.hits += 1;
+            state.touch(key);
+        } else {
+            state.stats.misses += 1;
+        }
+        Ok(value)
+    }
+    fn remove(&self, key: String) -> Result<()> {
+        let _writer = self.write_lock();
+        if self.remove_if_expired(&key)? {
+            return Err(Error::RemoveNonexistKey);
+        }
+        self.engine.remove(key.clone())?;
+        self.lock().forget(&key);
+        Ok(())
+    }
+    fn scan(&self, prefix: &str) -> Result<Vec<(String, String)>> {
+        self.expire_keys()?;
+        self.engine.scan(prefix)
+    }
+    fn scan_page(&self, prefix: &str, start: &str, limit: usize) -> Result<Vec<(String, String)>> {
+        self.expire_keys()?;
+        self.engine.scan_page(prefix, start, limit)
+    }
+    fn checkpoint(&self, dest_dir: &Path) -> Result<()> {
+        self.expire_keys()?;
+        self.engine.checkpoint(dest_dir)
+    }
+    /// Keys of the snapshot are gone once their TTLs pass, as in the cache
+    fn snapshot(&self) -> Result<Self::Snapshot> {
+        // No write may come between the engine's snapshot and the deadlines
+        let _writer = self.write_lock();
+        self.remove_expired()?;
+        let snapshot = self.engine.snapshot()?;
+        let deadlines = self
+            .lock()
+            .deadlines
+            .iter()
+            .map(|(deadline, key)| (key.clone(), *deadline))
+            .collect();
+        Ok(CacheSnapshot {
+            snapshot,
+            deadlines,
+        })
+    }
+    /// Evictions and expirations are seen as removals
+    fn watch(&self, prefix: &str) -> Result<Receiver<Event>> {
+        self.engine.watch(prefix)
+    }
+    fn get_versioned(&self, key: &str) -> Result<Option<(String, u64)>> {
+        if self.expire_key(key)? {
+            return Ok(None);
+        }
+        let versioned = self.engine.get_versioned(key)?;
+        if versioned.is_some() {
+            self.lock().touch(key);
+        }
+        Ok(versioned)
+    }
+    /// Make sure the writes fit before committing, and evict only once committed.
+    ///
+    /// Written keys lose their TTL.
+    fn commit(&self, reads: &ReadSet, writes: WriteSet) -> Result<bool> {
+        let _writer = self.write_lock();
+        self.remove_expired()?;
+        let tracked = writes
+            .iter()
+            .map(|(key, value)| {
+                (
+                    key.clone(),
+                    value.as_ref().map(|value| pair_size(key, value)),
+                )
+            })
+            .collect::<Vec<_>>();
+        let sizes = tracked
+            .iter()
+            .map(|(key, size)| (key.as_str(), size.unwrap_or(0)))
+            .collect::<Vec<_>>();
+        let victims = self.lock().victims(&sizes, reads)?;
+        if !self.engine.commit(reads, writes)? {
+            return Ok(false);
+        }
+        self.evict(&victims)?;
+        let mut state = self.lock();
+        for (key, size) in tracked {
+            match size {
+                Some(size) => state.track(key, size, None),
+                None => {
+                    state.forget(&key);
+                }
+            }
+        }
+        Ok(true)
+    }
+    /// An expired key is removed first, so it's gone as of later writes
+    fn get_at(&self, key: &str, seq: u64) -> Result<Option<String>> {
+        self.expire_key(key)?;
+        self.engine.get_at(key, seq)
+    }
+    /// An expired key is removed first, its history ends with the removal
+    fn history(&self, key: &str, limit: usize) -> Result<Vec<(u64, Option<String>)>> {
+        self.expire_key(key)?;
+        self.engine.history(key, limit)
+    }
+    fn tail_log(&self, from: LogPosition) -> Result<LogTail> {
+        self.engine.tail_log(from)
+    }
+    fn cache_stats(&self) -> Result<CacheStats> {
+        Ok(self.lock().stats)
+    }
+}
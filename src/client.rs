@@ -2,7 +2,7 @@ use std::io::Write;
 use std::net::{SocketAddr, TcpStream};
 use std::time::Duration;
 
-use crate::{Decoder, Encoder, Request, Response};
+use crate::{Decoder, Encoder, Error, Event, Request, Response};
 
 use crate::Result;
 
@@ -12,13 +12,22 @@ pub struct KvsClient {
     encoder: Encoder,
 }
 
+/// Events streamed to a client after `KvsClient::watch`
+pub struct EventStream<'a> {
+    decoder: Decoder<'a>,
+}
+
 impl KvsClient {
     ///
     pub fn new(addr: SocketAddr) -> Self {
-        let conn = TcpStream::connect_timeout(&addr, Duration::from_secs(2)).unwrap();
+        Self::connect(addr).unwrap()
+    }
+    /// Like `new`, but return the error if the server can't be reached
+    pub fn connect(addr: SocketAddr) -> Result<Self> {
+        let conn = TcpStream::connect_timeout(&addr, Duration::from_secs(2))?;
         log::debug!("{:?}", conn.local_addr());
         let encoder = Encoder::new();
-        Self { conn, encoder }
+        Ok(Self { conn, encoder })
     }
     /// Timeout 2s
     pub fn request(&mut self, request: Request) -> Result<Response> {
@@ -28,4 +37,33 @@ impl KvsClient {
         let mut decoder = Decoder::new(&mut self.conn);
         decoder.decode_response()
     }
+    /// Watch keys with the prefix, the connection carries only events afterwards
+    pub fn watch(&mut self, prefix: String) -> Result<EventStream<'_>> {
+        let buf = self.encoder.encode_request(Request::Watch(prefix));
+        self.conn.write_all(buf)?;
+
+        // Events may follow in the same read, so the decoder is kept for them
+        let mut decoder = Decoder::new(&mut self.conn);
+        match decoder.decode_response()? {
+            Response::Ok => Ok(EventStream { decoder }),
+            response => Err(Error::DecodeError(format!(
+                "Unexpected response: {response:?}"
+            ))),
+        }
+    }
+}
+
+impl Iterator for EventStream<'_> {
+    type Item = Result<Event>;
+
+    /// Block until the next event
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(match self.decoder.decode_response() {
+            Ok(Response::Event(event)) => Ok(event),
+            Ok(response) => Err(Error::DecodeError(format!(
+                "Unexpected response: {response:?}"
+            ))),
+            Err(e) => Err(e),
+        })
+    }
 }
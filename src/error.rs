@@ -25,9 +25,60 @@ pub enum Error {
     ///
     #[error("Decode error: {0}")]
     DecodeError(String),
+    /// Checkpoint must be made into an empty directory
+    #[error("Checkpoint directory is not empty")]
+    NonEmptyCheckpointDir,
+    /// `get_at` with a sequence number older than the retained history
+    #[error("History before the sequence number is compacted")]
+    HistoryCompacted,
+    /// The engine doesn't keep version history
+    #[error("Version history is not supported by the engine")]
+    HistoryUnsupported,
+    /// The engine has no log to ship to replicas
+    #[error("Replication is not supported by the engine")]
+    ReplicationUnsupported,
+    /// Writes must go to the leader of the Raft cluster, if it's known
+    #[error("Not the leader, the leader is {0:?}")]
+    NotLeader(Option<u64>),
+    /// The Raft log didn't commit the write in time
+    #[error("Proposal timed out, it may still be committed")]
+    ProposalTimeout,
+    /// The node is not in the cluster config
+    #[error("Node {0} is not in the cluster")]
+    UnknownNode(u64),
+    /// The server failed to handle the request
+    #[error("Server internal error")]
+    ServerError,
+    /// The server refused the connection for having too many queued
+    #[error("Server is busy")]
+    ServerBusy,
+    /// A sharded client needs at least one server
+    #[error("No server in the cluster")]
+    EmptyCluster,
+    /// The engine can't expire keys
+    #[error("TTL is not supported by the engine")]
+    TtlUnsupported,
+    /// The engine has no memory budget
+    #[error("Cache stats are not supported by the engine")]
+    CacheUnsupported,
+    /// Nothing can be evicted to make room for the write
+    #[error("Cache is full")]
+    CacheFull,
+    /// Transaction still conflicts after retrying
+    #[error("Transaction conflicts too many times")]
+    TransactionConflict,
     /// Thread pool cannot be zero size
     #[error("Thread pool cannot be zero size")]
     ZeroSizedPool,
+    /// The thread pool has a fixed size
+    #[error("Thread pool cannot be resized")]
+    ResizeUnsupported,
+    /// A job of a thread pool panicked
+    #[error("Job panicked: {0}")]
+    JobPanicked(String),
+    /// A job of a thread pool was dropped without running
+    #[error("Job was cancelled")]
+    JobCancelled,
     /// Error when build rayon thread pool
     #[error("Rayon error: {0}")]
     RayonError(#[from] rayon::ThreadPoolBuildError),
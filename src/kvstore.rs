@@ -1,31 +1,39 @@
 use std::{
-    cell::RefCell,
-    collections::{hash_map::Entry, HashMap},
+    collections::{btree_map, hash_map::Entry, BTreeMap, HashMap, HashSet},
     fs::{self, File},
     io::{self, Read, Seek, Write},
-    ops::{Deref, DerefMut},
+    ops::DerefMut,
+    os::unix::fs::FileExt,
     path::{Path, PathBuf},
     sync::{
-        atomic::{AtomicU32, Ordering},
-        Arc, Mutex,
+        atomic::{AtomicU32, AtomicU64, Ordering},
+        mpsc::Receiver,
+        Arc, Mutex, RwLock,
     },
 };
 
 use arc_swap::ArcSwap;
 use dashmap::DashMap;
-use serde::{Deserialize, Serialize};
-use serde_json::Deserializer;
+use memmap2::Mmap;
+use serde::{de::IgnoredAny, Deserialize, Serialize};
+use serde_json::{de::IoRead, Deserializer, StreamDeserializer};
 
 use crate::{
-    buf_file::{BufReader, BufWriter},
-    Error, KvsEngine, Result, IS_TEST,
+    buf_file::BufWriter,
+    value_cache::{ValueCache, ValueCacheStats},
+    watch::Watchers,
+    Error, Event, KvsEngine, KvsSnapshot, ReadSet, Result, WriteSet, IS_TEST,
 };
 
 const MAX_DATA_FILE_SIZE: u32 = if IS_TEST { 0x1000 } else { 0x1000000 };
 const COMPACT_THRESHOLD: u32 = if IS_TEST { 0x2000 } else { 0x200000 };
+/// Holds the sequence number below which compaction may have dropped history
+const HISTORY_FLOOR_FILE: &str = "HISTORY_FLOOR";
+/// Holds the id of the first data file in the log, older files are retired
+const LOG_START_FILE: &str = "LOG_START";
 /// a k-v database, map key to value
+#[derive(Clone)]
 pub struct KvStore {
-    readers: Readers,
     /// pack together to use one `Arc`
     shared: Arc<SharedState>,
 }
@@ -38,115 +46,664 @@ struct SharedState {
     ///
     /// When writing new log, key dir will be mutated
     key_dir: ArcSwap<DashMap<String, CommandMeta>>,
-    /// Increment after compacting, to notify readers to update files
+    /// Superseded writes of each key in sequence order, replaced with `key_dir` after compacting
+    history: ArcSwap<DashMap<String, Vec<KeyWrite>>>,
+    /// Reads as of an older sequence number may miss writes dropped by compaction
+    history_floor: AtomicU64,
+    /// Increment after compacting, to notify the value cache to drop old records
     global_version: AtomicU32,
+    /// Opened data files, shared by all clones and snapshots
+    data_files: DataFiles,
+    /// Data files being copied by checkpoints or read by log tails, compaction can't delete them
+    pinned_files: Mutex<PinnedFiles>,
+    /// Map from the sequence number of open snapshots to their count,
+    /// compaction keeps the writes they see
+    snapshot_seqs: Mutex<BTreeMap<u64, u32>>,
+    /// Decoded records of hot keys, `None` if disabled
+    value_cache: Option<ValueCache>,
 }
 
-impl Clone for KvStore {
-    fn clone(&self) -> Self {
-        Self {
-            readers: Readers {
-                local_version: AtomicU32::new(self.readers.local_version.load(Ordering::SeqCst)),
-                files: RefCell::new(HashMap::new()),
-            },
-            shared: Arc::clone(&self.shared),
+#[derive(Default)]
+struct PinnedFiles {
+    /// Map from file id to pin count
+    pins: HashMap<u32, u32>,
+    /// File ids of the log tails, which hold that file and all newer ones
+    holds: Vec<u32>,
+    /// Pinned files which compaction wants to delete
+    obsolete: HashSet<u32>,
+}
+
+impl PinnedFiles {
+    fn is_pinned(&self, file_id: u32) -> bool {
+        self.pins.contains_key(&file_id) || self.holds.iter().any(|&from| file_id >= from)
+    }
+    /// Delete the obsolete files which are no longer pinned
+    fn remove_released(&mut self, shared: &SharedState) -> Result<()> {
+        let released = self
+            .obsolete
+            .iter()
+            .copied()
+            .filter(|&file_id| !self.is_pinned(file_id))
+            .collect::<Vec<_>>();
+        for file_id in released {
+            self.obsolete.remove(&file_id);
+            shared.delete_data_file(file_id)?;
+        }
+        Ok(())
+    }
+}
+
+impl SharedState {
+    fn data_file_path(&self, file_id: u32) -> PathBuf {
+        self.curr_dir.join(format!("{file_id}.dat"))
+    }
+    /// Unpin the files, and delete those which compaction has dropped
+    fn unpin_files(&self, file_ids: &[u32]) -> Result<()> {
+        let mut pinned = self.pinned_files.lock().unwrap();
+        for &file_id in file_ids {
+            let Entry::Occupied(mut entry) = pinned.pins.entry(file_id) else {
+                continue;
+            };
+            *entry.get_mut() -= 1;
+            if *entry.get() == 0 {
+                entry.remove();
+            }
+        }
+        pinned.remove_released(self)
+    }
+    /// Move a hold of a log tail to a newer file, releasing the files before it
+    fn move_hold(&self, from: u32, to: u32) -> Result<()> {
+        let mut pinned = self.pinned_files.lock().unwrap();
+        if let Some(hold) = pinned.holds.iter_mut().find(|hold| **hold == from) {
+            *hold = to;
+        }
+        pinned.remove_released(self)
+    }
+    /// Drop a hold of a log tail
+    fn release_hold(&self, from: u32) -> Result<()> {
+        let mut pinned = self.pinned_files.lock().unwrap();
+        if let Some(i) = pinned.holds.iter().position(|&hold| hold == from) {
+            pinned.holds.swap_remove(i);
+        }
+        pinned.remove_released(self)
+    }
+    /// Record the log starts at the file, then remove the older files.
+    ///
+    /// Pinned files may outlive the process, so the record keeps them out of the log
+    /// when the store is opened again.
+    fn retire_files_before(&self, log_start: u32) -> Result<()> {
+        fs::write(
+            self.curr_dir.join(LOG_START_FILE),
+            serde_json::to_vec(&log_start)?,
+        )?;
+        for file_id in data_file_ids(&self.curr_dir)? {
+            if file_id < log_start {
+                self.remove_data_file(file_id)?;
+            }
         }
+        Ok(())
+    }
+    /// Delete the data file, or defer it if the file is pinned
+    fn remove_data_file(&self, file_id: u32) -> Result<()> {
+        let mut pinned = self.pinned_files.lock().unwrap();
+        if pinned.is_pinned(file_id) {
+            pinned.obsolete.insert(file_id);
+        } else {
+            self.delete_data_file(file_id)?;
+        }
+        Ok(())
+    }
+    /// Unmap the data file and delete it, reads holding the map can still finish
+    fn delete_data_file(&self, file_id: u32) -> Result<()> {
+        self.data_files.mapped.write().unwrap().remove(&file_id);
+        fs::remove_file(self.data_file_path(file_id))?;
+        Ok(())
     }
 }
 
+/// Written to the checkpoint directory, listing the copied data files
+#[derive(Deserialize, Serialize)]
+struct Manifest {
+    file_ids: Vec<u32>,
+    /// The empty file new writes go to when the checkpoint is opened
+    active_file_id: u32,
+}
+
 #[derive(Clone, Copy)]
 struct CommandMeta {
     file_id: u32,
     /// For this project, offset can't overflow u32.
     file_offset: u32,
     len: u32,
+    /// Sequence number of the record, also the version of the key
+    seq: u64,
 }
 
-#[derive(Deserialize, Serialize)]
-enum Command {
-    Set { key: String, value: String },
-    Rm { key: String },
+/// A write of a key kept in the history
+#[derive(Clone, Copy)]
+struct KeyWrite {
+    seq: u64,
+    /// `None` for removal
+    meta: Option<CommandMeta>,
+}
+
+impl From<CommandMeta> for KeyWrite {
+    fn from(meta: CommandMeta) -> Self {
+        Self {
+            seq: meta.seq,
+            meta: Some(meta),
+        }
+    }
+}
+
+/// A command in the data file
+#[derive(Debug, Deserialize, Serialize)]
+pub enum Command {
+    /// Set `key` to `value`
+    Set {
+        /// the key
+        key: String,
+        /// the value
+        value: String,
+        /// sequence number, 0 in logs written before it's recorded
+        #[serde(default, skip_serializing_if = "is_unknown_seq")]
+        seq: u64,
+    },
+    /// Remove `key`
+    Rm {
+        /// the key
+        key: String,
+        /// sequence number, 0 in logs written before it's recorded
+        #[serde(default, skip_serializing_if = "is_unknown_seq")]
+        seq: u64,
+    },
+    /// `Set` and `Rm` commands of a committed transaction, applied all or none.
+    ///
+    /// They share the sequence number of the transaction.
+    Txn(Vec<Command>),
+}
+
+impl Command {
+    /// Sequence number of the record
+    pub fn seq(&self) -> u64 {
+        match self {
+            Command::Set { seq, .. } | Command::Rm { seq, .. } => *seq,
+            Command::Txn(commands) => commands.first().map_or(0, Command::seq),
+        }
+    }
+}
+
+fn is_unknown_seq(seq: &u64) -> bool {
+    *seq == 0
+}
+
+/// A command read back from the data file, with its position
+#[derive(Debug)]
+pub struct LogRecord {
+    /// Id of the data file, `N` of `N.dat`
+    pub file_id: u32,
+    /// Offset of the record in the data file
+    pub file_offset: u32,
+    /// Length of the record in bytes
+    pub len: u32,
+    /// The command itself
+    pub command: Command,
+}
+
+/// Ids of all data files in the directory, in log order
+pub fn data_file_ids(dir: impl AsRef<Path>) -> Result<Vec<u32>> {
+    let mut file_ids = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_name = entry.file_name();
+        let file_name = file_name.to_string_lossy();
+        let Some(Ok(file_id)) = file_name.strip_suffix(".dat").map(str::parse) else {
+            continue;
+        };
+        file_ids.push(file_id);
+    }
+    file_ids.sort_unstable();
+    Ok(file_ids)
+}
+
+/// Ids of the data files in the log, in log order.
+///
+/// Files retired by compaction may be left in the directory, they are not in the log.
+pub fn log_file_ids(dir: impl AsRef<Path>) -> Result<Vec<u32>> {
+    let log_start = log_start(dir.as_ref())?;
+    let mut file_ids = data_file_ids(dir)?;
+    file_ids.retain(|&file_id| file_id >= log_start);
+    Ok(file_ids)
+}
+
+fn log_start(dir: &Path) -> Result<u32> {
+    match fs::read(dir.join(LOG_START_FILE)) {
+        Ok(log_start) => Ok(serde_json::from_slice(&log_start)?),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Iterate the records of one data file.
+///
+/// After a bad record is met, the error is returned and the iteration ends.
+/// `file_offset` then tells where the bad record begins.
+pub struct DataFileReader {
+    file_id: u32,
+    file_offset: u32,
+    de: StreamDeserializer<'static, IoRead<io::BufReader<File>>, Command>,
+}
+
+impl DataFileReader {
+    /// Open `N.dat` in the directory
+    pub fn open(dir: impl AsRef<Path>, file_id: u32) -> Result<Self> {
+        let file = File::open(dir.as_ref().join(format!("{file_id}.dat")))?;
+        Ok(Self {
+            file_id,
+            file_offset: 0,
+            de: Deserializer::from_reader(io::BufReader::new(file)).into_iter(),
+        })
+    }
+    /// Offset of the next record
+    pub fn file_offset(&self) -> u32 {
+        self.file_offset
+    }
+}
+
+impl Iterator for DataFileReader {
+    type Item = Result<LogRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let command = match self.de.next()? {
+            Ok(command) => command,
+            Err(e) => return Some(Err(e.into())),
+        };
+        let new_offset = self.de.byte_offset() as u32;
+        let record = LogRecord {
+            file_id: self.file_id,
+            file_offset: self.file_offset,
+            len: new_offset - self.file_offset,
+            command,
+        };
+        self.file_offset = new_offset;
+        Some(Ok(record))
+    }
+}
+
+/// Where a record begins in the data files
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct LogPosition {
+    /// Id of the data file, `N` of `N.dat`
+    pub file_id: u32,
+    /// Offset in the data file
+    pub file_offset: u32,
+}
+
+/// Follow the records appended to the data files, see `KvsEngine::tail_log`.
+///
+/// The file being read and all newer ones are held, so compaction won't delete them
+/// before they are read.
+pub struct LogTail {
+    shared: Arc<SharedState>,
+    /// Position of the next record
+    position: LogPosition,
+    file: File,
+    /// Bytes read from `file` but not returned as a record yet
+    buf: Vec<u8>,
+    resync: bool,
+}
+
+impl LogTail {
+    /// Start at `from` if it's still in the data files, otherwise at the beginning of the log
+    fn new(shared: Arc<SharedState>, from: LogPosition) -> Result<Self> {
+        let (position, mut file) = {
+            // Files are only deleted under this lock, so the listed ones exist until held
+            let mut pinned = shared.pinned_files.lock().unwrap();
+            let file_ids = data_file_ids(&shared.curr_dir)?
+                .into_iter()
+                .filter(|file_id| !pinned.obsolete.contains(file_id))
+                .collect::<Vec<_>>();
+            let mut position = LogPosition::default();
+            let mut file = None;
+            if file_ids.contains(&from.file_id) {
+                let from_file = File::open(shared.data_file_path(from.file_id))?;
+                if from_file.metadata()?.len() >= from.file_offset as u64 {
+                    position = from;
+                    file = Some(from_file);
+                }
+            }
+            let file = match file {
+                Some(file) => file,
+                None => {
+                    // The active file always exists
+                    position.file_id = file_ids[0];
+                    File::open(shared.data_file_path(position.file_id))?
+                }
+            };
+            pinned.holds.push(position.file_id);
+            (position, file)
+        };
+        file.seek(io::SeekFrom::Start(position.file_offset as u64))?;
+        Ok(Self {
+            resync: position != from,
+            shared,
+            position,
+            file,
+            buf: Vec::new(),
+        })
+    }
+    /// Whether the tail starts at the beginning of the log, as the asked position is compacted
+    pub fn is_resync(&self) -> bool {
+        self.resync
+    }
+    /// Sequence number of the last write to the store
+    pub fn last_seq(&self) -> u64 {
+        self.shared.writer.lock().unwrap().last_seq
+    }
+    /// Get the next record with its position and bytes, `None` if there is no new record yet
+    pub fn next_record(&mut self) -> Result<Option<(LogPosition, String)>> {
+        loop {
+            if let Some(record) = self.parse_record()? {
+                return Ok(Some(record));
+            }
+            if self.file.read_to_end(&mut self.buf)? > 0 {
+                continue;
+            }
+            let next_file_id = data_file_ids(&self.shared.curr_dir)?
+                .into_iter()
+                .find(|&file_id| file_id > self.position.file_id);
+            let Some(next_file_id) = next_file_id else {
+                return Ok(None);
+            };
+            // The writer flushes a file before creating the next one,
+            // so reading again gets all the rest
+            if self.file.read_to_end(&mut self.buf)? > 0 {
+                continue;
+            }
+            if !self.buf.iter().all(u8::is_ascii_whitespace) {
+                log::warn!(
+                    "Skip the broken record at {:?}, {} bytes",
+                    self.position,
+                    self.buf.len()
+                );
+            }
+            self.file = File::open(self.shared.data_file_path(next_file_id))?;
+            self.shared.move_hold(self.position.file_id, next_file_id)?;
+            self.position = LogPosition {
+                file_id: next_file_id,
+                file_offset: 0,
+            };
+            self.buf.clear();
+        }
+    }
+    /// Split a complete record off the read bytes
+    fn parse_record(&mut self) -> Result<Option<(LogPosition, String)>> {
+        let mut de = Deserializer::from_slice(&self.buf).into_iter::<IgnoredAny>();
+        match de.next() {
+            Some(Ok(_)) => {}
+            // A record being written
+            Some(Err(e)) if e.is_eof() => return Ok(None),
+            Some(Err(e)) => return Err(e.into()),
+            None => return Ok(None),
+        }
+        let len = de.byte_offset();
+        let record = std::str::from_utf8(&self.buf[..len])?.to_owned();
+        self.buf.drain(..len);
+        let position = self.position;
+        self.position.file_offset += len as u32;
+        Ok(Some((position, record)))
+    }
+}
+
+impl Drop for LogTail {
+    fn drop(&mut self) {
+        if let Err(e) = self.shared.release_hold(self.position.file_id) {
+            log::error!("Failed to delete data files released by log tail: {e}");
+        }
+    }
+}
+
+/// Apply the command read from `meta` to the key dir, return the size it makes useless.
+///
+/// The replaced write is moved into the history before the key dir changes,
+/// so `get_at` never misses it.
+/// A command not newer than the latest write of the key is left by an interrupted compaction
+/// and ignored.
+fn apply_command(
+    key_dir: &DashMap<String, CommandMeta>,
+    history: &DashMap<String, Vec<KeyWrite>>,
+    command: &Command,
+    meta: CommandMeta,
+) -> u32 {
+    match command {
+        Command::Set { key, .. } | Command::Rm { key, .. }
+            if latest_seq(key_dir, history, key) >= Some(meta.seq) =>
+        {
+            0
+        }
+        Command::Set { key, .. } => {
+            let old = key_dir.get(key).map(|old| *old);
+            if let Some(old) = old {
+                history.entry(key.clone()).or_default().push(old.into());
+            }
+            key_dir.insert(key.clone(), meta);
+            old.map_or(0, |old| old.len)
+        }
+        Command::Rm { key, .. } => {
+            let old = key_dir.get(key).map(|old| *old);
+            {
+                let mut writes = history.entry(key.clone()).or_default();
+                writes.extend(old.map(KeyWrite::from));
+                writes.push(KeyWrite {
+                    seq: meta.seq,
+                    meta: None,
+                });
+            }
+            key_dir.remove(key);
+            old.map_or(0, |old| old.len)
+        }
+        Command::Txn(commands) => {
+            // Share the record between the keys set by it
+            let n_sets = commands
+                .iter()
+                .filter(|command| matches!(command, Command::Set { .. }))
+                .count() as u32;
+            let meta = CommandMeta {
+                len: meta.len / n_sets.max(1),
+                ..meta
+            };
+            commands
+                .iter()
+                .map(|command| apply_command(key_dir, history, command, meta))
+                .sum()
+        }
+    }
+}
+
+/// Sequence number of the latest write of the key, including removal
+fn latest_seq(
+    key_dir: &DashMap<String, CommandMeta>,
+    history: &DashMap<String, Vec<KeyWrite>>,
+    key: &str,
+) -> Option<u64> {
+    let live = key_dir.get(key).map(|meta| meta.seq);
+    let old = history
+        .get(key)
+        .and_then(|writes| writes.last().map(|write| write.seq));
+    live.max(old)
+}
+
+/// The write of the key seen as of `seq`, from the live one to older ones in the history
+fn meta_at(
+    key_dir: &DashMap<String, CommandMeta>,
+    history: &DashMap<String, Vec<KeyWrite>>,
+    key: &str,
+    seq: u64,
+) -> Option<CommandMeta> {
+    match key_dir.get(key).map(|meta| *meta) {
+        Some(meta) if meta.seq <= seq => Some(meta),
+        _ => history.get(key).and_then(|writes| {
+            let n_visible = writes.partition_point(|write| write.seq <= seq);
+            n_visible.checked_sub(1).and_then(|last| writes[last].meta)
+        }),
+    }
+}
+
+/// Drop the writes which reads as of `floor` or later, and snapshots at `pinned`, can't see
+fn retain_writes(writes: &mut Vec<KeyWrite>, floor: u64, pinned: &[u64]) {
+    let last_seen = |seq: u64| {
+        writes
+            .partition_point(|write| write.seq <= seq)
+            .checked_sub(1)
+    };
+    let mut keep = vec![false; writes.len()];
+    // A snapshot sees the last write not after its sequence number
+    for &seq in pinned {
+        if let Some(last) = last_seen(seq) {
+            keep[last] = true;
+        }
+    }
+    // The last write not after the floor is seen by reads as of the floor
+    let n_visible = writes.partition_point(|write| write.seq <= floor);
+    keep[n_visible..].fill(true);
+    if let Some(last) = n_visible.checked_sub(1) {
+        // A removal seen at the floor is the same as no write at all, unless older writes are kept
+        keep[last] = writes[last].meta.is_some() || keep[..last].contains(&true);
+    }
+    let mut keep = keep.into_iter();
+    writes.retain(|_| keep.next().unwrap());
+}
+
+/// Options to open a `KvStore` with
+#[derive(Clone, Copy, Debug, Default)]
+pub struct KvStoreOptions {
+    retention: u64,
+    value_cache: usize,
+}
+
+impl KvStoreOptions {
+    /// Keep no history and no cache
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Compaction keeps the history of the latest `retention` sequence numbers
+    pub fn retention(mut self, retention: u64) -> Self {
+        self.retention = retention;
+        self
+    }
+    /// Keep about `capacity` decoded records in memory, so reads of hot keys skip the disk
+    pub fn value_cache(mut self, capacity: usize) -> Self {
+        self.value_cache = capacity;
+        self
+    }
+    /// Open the store at the path
+    pub fn open(self, path: impl AsRef<Path>) -> Result<KvStore> {
+        KvStore::open_with_options(path, self)
+    }
 }
 
 impl KvStore {
     /// open log file and replay it
     pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        Self::open_with_retention(path, 0)
+    }
+
+    /// Open the store, compaction keeps the history of the latest `retention` sequence numbers.
+    ///
+    /// `get_at` can read as of any sequence number in the window.
+    pub fn open_with_retention(path: impl AsRef<Path>, retention: u64) -> Result<Self> {
+        KvStoreOptions::new().retention(retention).open(path)
+    }
+
+    fn open_with_options(path: impl AsRef<Path>, options: KvStoreOptions) -> Result<Self> {
+        let KvStoreOptions {
+            retention,
+            value_cache,
+        } = options;
         let curr_dir = path.as_ref().to_path_buf();
         fs::create_dir_all(&path)?;
         File::create(path.as_ref().join("kvs"))?;
 
-        let dir = fs::read_dir(path)?;
+        // Retired files were only kept for snapshots and log tails, which are gone now
+        let log_start = log_start(&curr_dir)?;
+        let mut file_ids = data_file_ids(&path)?;
+        for &file_id in file_ids.iter().filter(|&&file_id| file_id < log_start) {
+            fs::remove_file(curr_dir.join(format!("{file_id}.dat")))?;
+        }
+        file_ids.retain(|&file_id| file_id >= log_start);
         let mut useless_size = 0;
 
-        // Traverse the directory to read all data files and generate key dir.
+        // Replay all data files in log order to generate key dir.
         let key_dir = DashMap::new();
-        let mut curr_file_id = 0;
-        let mut data_file_cnt = 0;
-        for entry in dir {
-            let entry = entry?;
-            let file_name = entry.file_name();
-            let file_name = file_name.to_string_lossy();
-            let Ok(file_id) = file_name.split('.').next().unwrap().parse() else {
-                continue;
-            };
-            data_file_cnt += 1;
-            curr_file_id = curr_file_id.max(file_id);
-            let mut reader = io::BufReader::new(File::open(entry.path())?);
-            let mut file_content = String::new();
-            reader.read_to_string(&mut file_content).unwrap();
-            reader.seek(io::SeekFrom::Start(0)).unwrap();
-            let mut de = Deserializer::from_reader(reader).into_iter();
-            let mut file_offset = 0;
-            while let Some(command) = de.next() {
-                let command = command?;
-                let new_offset = de.byte_offset() as u32;
-                match command {
-                    Command::Set { key, .. } => {
-                        let meta = CommandMeta {
-                            file_id,
-                            file_offset,
-                            len: new_offset - file_offset,
-                        };
-                        if let Some(CommandMeta { len, .. }) = key_dir.insert(key, meta) {
-                            useless_size += len;
-                        }
-                    }
-                    Command::Rm { key } => {
-                        if let Some((_, CommandMeta { len, .. })) = key_dir.remove(&key) {
-                            useless_size += len;
-                        }
-                    }
+        let history = DashMap::new();
+        let mut last_seq = 0;
+        for &file_id in &file_ids {
+            for record in DataFileReader::open(&path, file_id)? {
+                let LogRecord {
+                    file_id,
+                    file_offset,
+                    len,
+                    command,
+                } = record?;
+                let seq = match command.seq() {
+                    0 => last_seq + 1,
+                    seq => seq,
+                };
+                last_seq = last_seq.max(seq);
+                let meta = CommandMeta {
+                    file_id,
+                    file_offset,
+                    len,
+                    seq,
                 };
-                file_offset = new_offset;
+                useless_size += apply_command(&key_dir, &history, &command, meta);
             }
         }
-
-        let readers = Readers {
-            local_version: AtomicU32::new(0),
-            files: RefCell::new(HashMap::with_capacity(data_file_cnt)),
+        let history_floor = match fs::read(curr_dir.join(HISTORY_FLOOR_FILE)) {
+            Ok(floor) => serde_json::from_slice(&floor)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e.into()),
         };
+        let curr_file_id = file_ids.last().copied().unwrap_or(log_start);
 
         // Get a writer of the newest data file in the end of the file
         let curr_file_path = curr_dir.join(format!("{curr_file_id}.dat"));
-        let mut write_file = BufWriter::create(curr_file_path)?;
+        let mut write_file = BufWriter::create(&curr_file_path)?;
+        let data_files = DataFiles {
+            mapped: RwLock::new(HashMap::with_capacity(file_ids.len())),
+            active: RwLock::new((curr_file_id, File::open(curr_file_path)?)),
+        };
         write_file.seek(io::SeekFrom::End(0))?;
         let writer = Mutex::new(Writer {
             curr_file_id,
             useless_size,
+            last_seq,
+            retention,
             file: write_file,
+            watchers: Watchers::default(),
         });
 
         Ok(Self {
-            readers,
             shared: Arc::new(SharedState {
                 curr_dir,
                 writer,
                 key_dir: ArcSwap::new(Arc::new(key_dir)),
+                history: ArcSwap::new(Arc::new(history)),
+                history_floor: AtomicU64::new(history_floor),
                 global_version: AtomicU32::new(0),
+                data_files,
+                pinned_files: Mutex::new(PinnedFiles::default()),
+                snapshot_seqs: Mutex::new(BTreeMap::new()),
+                value_cache: (value_cache > 0).then(|| ValueCache::new(value_cache)),
             }),
         })
     }
 
+    /// Hit and miss counters of the value cache, `None` if it's disabled
+    pub fn value_cache_stats(&self) -> Option<ValueCacheStats> {
+        self.shared.value_cache.as_ref().map(ValueCache::stats)
+    }
+
     /// try to begin compacting
     ///
     /// 目前采取最朴素的做法，即：
@@ -154,152 +711,638 @@ impl KvStore {
     /// 顺序扫描所有键，找到对应的值，追加到末尾
     fn compact(&self, writer: &mut Writer) -> Result<()> {
         writer.file.flush()?;
-        writer.create_new_data_file(&self.shared.curr_dir)?;
+        writer.create_new_data_file(&self.shared)?;
+        let compacted_file_id = writer.curr_file_id;
 
-        let new_key_dir = (**self.shared.key_dir.load()).clone();
-        for mut kv_pair in new_key_dir.iter_mut() {
-            let value =
-                self.readers
-                    .read_value(kv_pair.file_id, kv_pair.file_offset, &self.shared)?;
-            let command = Command::Set {
-                key: kv_pair.key().to_owned(),
-                value,
+        // Reads as of the floor or later must see the same values after compacting
+        let history_floor = writer
+            .last_seq
+            .saturating_sub(writer.retention)
+            .max(self.shared.history_floor.load(Ordering::SeqCst));
+        // Open snapshots must also see the same values
+        let snapshot_seqs =
+            Vec::from_iter(self.shared.snapshot_seqs.lock().unwrap().keys().copied());
+        let key_dir = self.shared.key_dir.load();
+        let history = self.shared.history.load();
+        let mut retained = Vec::new();
+        for kv_pair in history.iter() {
+            let mut writes = kv_pair.value().clone();
+            writes.extend(key_dir.get(kv_pair.key()).map(|meta| KeyWrite::from(*meta)));
+            retain_writes(&mut writes, history_floor, &snapshot_seqs);
+            retained.extend(
+                writes
+                    .into_iter()
+                    .map(|write| (kv_pair.key().clone(), write)),
+            );
+        }
+        for kv_pair in key_dir.iter() {
+            if !history.contains_key(kv_pair.key()) {
+                retained.push((kv_pair.key().clone(), KeyWrite::from(*kv_pair.value())));
+            }
+        }
+        // Rewrite in sequence order, so replaying rebuilds the same history
+        retained.sort_by_key(|(_, write)| write.seq);
+
+        let new_key_dir = DashMap::new();
+        let new_history = DashMap::new();
+        for (key, write) in retained {
+            let command = match write.meta {
+                Some(meta) => Command::Set {
+                    value: read_value(&key, meta, &self.shared)?,
+                    key,
+                    seq: write.seq,
+                },
+                None => Command::Rm {
+                    key,
+                    seq: write.seq,
+                },
             };
-            let meta = writer.append_log(command, &self.shared.curr_dir)?;
-            *kv_pair.value_mut() = meta;
+            let meta = writer.append_log(&command, &self.shared)?;
+            apply_command(&new_key_dir, &new_history, &command, meta);
         }
+        // Readers may use the new key_dir as soon as it is stored
+        writer.file.flush()?;
+        // Recorded before deleting old files, so reads never see a silently shortened history
+        fs::write(
+            self.shared.curr_dir.join(HISTORY_FLOOR_FILE),
+            serde_json::to_vec(&history_floor)?,
+        )?;
+        self.shared
+            .history_floor
+            .store(history_floor, Ordering::SeqCst);
 
         // It's best to follow this order for consistency
 
-        // First delete old file.
-        for (file_id, _) in self.readers.files.borrow().deref() {
-            fs::remove_file(self.shared.curr_dir.join(format!("{file_id}.dat")))?;
-        }
-        // If the deleted file was accessed before global_version is upgraded, it will return error.
+        // First update the key_dir, new reads go to the compacted files
+        self.shared.history.store(Arc::new(new_history));
+        self.shared.key_dir.store(Arc::new(new_key_dir));
 
-        // Second upgrade the global_version
+        // Second upgrade the global_version, so the value cache drops old records
         self.shared.global_version.fetch_add(1, Ordering::SeqCst);
-        // Seems ok to swap these two steps?
 
-        // Third update the key_dir
-        self.shared.key_dir.store(Arc::new(new_key_dir));
+        // Third delete old files.
+        // Reads of them in progress fail, and are retried by `retry_compacted`.
+        self.shared.retire_files_before(compacted_file_id)?;
 
         writer.useless_size = 0;
 
         Ok(())
     }
 
+    /// Run `read` again if compaction has deleted the data files it was reading.
+    ///
+    /// Compaction replaces the key dir before deleting files,
+    /// so a missing file with the key dir unchanged is a real error.
+    fn retry_compacted<T>(&self, read: impl Fn() -> Result<T>) -> Result<T> {
+        loop {
+            let key_dir = self.shared.key_dir.load_full();
+            match read() {
+                Err(Error::IoError(e))
+                    if e.kind() == io::ErrorKind::NotFound
+                        && !Arc::ptr_eq(&key_dir, &self.shared.key_dir.load()) => {}
+                result => return result,
+            }
+        }
+    }
+
+    /// The value of the key as of `seq`, not below the history floor unless pinned by a snapshot
+    fn value_at(&self, key: &str, seq: u64) -> Result<Option<String>> {
+        self.retry_compacted(|| {
+            let meta = meta_at(
+                &self.shared.key_dir.load(),
+                &self.shared.history.load(),
+                key,
+                seq,
+            );
+            meta.map(|meta| read_value(key, meta, &self.shared))
+                .transpose()
+        })
+    }
+
+    /// All pairs with the prefix as of `seq`, sorted by key
+    fn scan_at(&self, prefix: &str, seq: u64) -> Result<Vec<(String, String)>> {
+        self.retry_compacted(|| {
+            let key_dir = self.shared.key_dir.load();
+            let history = self.shared.history.load();
+            // Keys removed since `seq` are only in the history
+            let mut keys = key_dir
+                .iter()
+                .map(|kv_pair| kv_pair.key().clone())
+                .chain(history.iter().map(|kv_pair| kv_pair.key().clone()))
+                .filter(|key| key.starts_with(prefix))
+                .collect::<Vec<_>>();
+            keys.sort_unstable();
+            keys.dedup();
+            keys.into_iter()
+                .filter_map(|key| {
+                    let meta = meta_at(&key_dir, &history, &key, seq)?;
+                    Some(read_value(&key, meta, &self.shared).map(|value| (key, value)))
+                })
+                .collect()
+        })
+    }
+
     ///
     pub fn flush(&self) -> Result<()> {
         self.shared.writer.lock().unwrap().file.flush()?;
         Ok(())
     }
+
+    /// Sequence number of the last write, the newest one applied for a replica
+    pub fn last_seq(&self) -> u64 {
+        self.shared.writer.lock().unwrap().last_seq
+    }
+
+    /// Apply a record of another store's log, keeping its sequence number.
+    ///
+    /// A record not newer than the key's latest write, such as one rewritten by compaction
+    /// of the other store, is skipped, so applying records again is harmless.
+    pub fn apply_replicated(&self, command: Command) -> Result<()> {
+        let mut writer = self.shared.writer.lock().unwrap();
+        let key_dir = self.shared.key_dir.load();
+        let history = self.shared.history.load();
+        let key = match &command {
+            Command::Set { key, .. } | Command::Rm { key, .. } => Some(key),
+            Command::Txn(commands) => commands.first().map(|command| match command {
+                Command::Set { key, .. } | Command::Rm { key, .. } => key,
+                Command::Txn(_) => unreachable!(),
+            }),
+        };
+        let Some(key) = key else {
+            return Ok(());
+        };
+        // Records written before sequence numbers are recorded get new ones
+        let seq = match command.seq() {
+            0 => writer.next_seq(),
+            seq => seq,
+        };
+        if latest_seq(&key_dir, &history, key) >= Some(seq) {
+            return Ok(());
+        }
+        writer.last_seq = writer.last_seq.max(seq);
+        let meta = CommandMeta {
+            seq,
+            ..writer.append_log(&command, &self.shared)?
+        };
+        if IS_TEST {
+            writer.file.flush()?;
+        }
+        writer.useless_size += apply_command(&key_dir, &history, &command, meta);
+        writer.notify(&command);
+
+        if writer.useless_size > COMPACT_THRESHOLD {
+            self.compact(writer.deref_mut())?;
+        }
+        Ok(())
+    }
+
+    /// Drop all pairs and history, to apply another store's log from the beginning.
+    ///
+    /// Watchers aren't notified of the dropped pairs.
+    pub fn reset(&self) -> Result<()> {
+        let mut writer = self.shared.writer.lock().unwrap();
+        writer.create_new_data_file(&self.shared)?;
+        let active_file_id = writer.curr_file_id;
+
+        match fs::remove_file(self.shared.curr_dir.join(HISTORY_FLOOR_FILE)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+        self.shared.history_floor.store(0, Ordering::SeqCst);
+        self.shared.history.store(Arc::new(DashMap::new()));
+        self.shared.key_dir.store(Arc::new(DashMap::new()));
+        self.shared.global_version.fetch_add(1, Ordering::SeqCst);
+        self.shared.retire_files_before(active_file_id)?;
+
+        writer.useless_size = 0;
+        writer.last_seq = 0;
+        Ok(())
+    }
 }
 
 impl KvsEngine for KvStore {
+    type Snapshot = KvStoreSnapshot;
+
     /// get the value the `key` corresponding to
     fn get(&self, key: &str) -> Result<Option<String>> {
-        let file_id;
-        let file_offset;
-        if let Some(meta) = self.shared.key_dir.load().get(key) {
-            file_id = meta.file_id;
-            file_offset = meta.file_offset;
-        } else {
-            return Ok(None);
-        }
-        self.readers
-            .read_value(file_id, file_offset, &self.shared)
-            .map(Some)
+        self.retry_compacted(|| {
+            let meta;
+            if let Some(found) = self.shared.key_dir.load().get(key) {
+                meta = *found;
+            } else {
+                return Ok(None);
+            }
+            read_value(key, meta, &self.shared).map(Some)
+        })
     }
     /// Set the value corresponding to key to `value`
     fn set(&self, key: String, value: String) -> Result<()> {
         let mut writer = self.shared.writer.lock().unwrap();
-        let command = Command::Set {
-            key: key.clone(),
-            value,
-        };
-        let meta = writer.append_log(command, &self.shared.curr_dir)?;
+        let seq = writer.next_seq();
+        let command = Command::Set { key, value, seq };
+        let meta = writer.append_log(&command, &self.shared)?;
+        // Use this to pass test.
+        // Flush before updating the key dir, readers may read the log at once.
+        if IS_TEST {
+            writer.file.flush()?;
+        }
 
         // NOTE: If we removed this key and insert it again, the remove log should also be useless.
         // We need some kind of mechnism to record the remove, such as another dashmap.
         // For now the useless_size is just estimation.
-        if let Some(CommandMeta { len, .. }) = self.shared.key_dir.load().insert(key, meta) {
-            writer.useless_size += len;
+        writer.useless_size += apply_command(
+            &self.shared.key_dir.load(),
+            &self.shared.history.load(),
+            &command,
+            meta,
+        );
+        writer.notify(&command);
+        if writer.useless_size > COMPACT_THRESHOLD {
+            self.compact(writer.deref_mut())?;
         }
-        // Use this to pass test
+        Ok(())
+    }
 
+    /// Remove the key, write to log
+    fn remove(&self, key: String) -> Result<()> {
+        let mut writer = self.shared.writer.lock().unwrap();
+        let key_dir = self.shared.key_dir.load();
+        if !key_dir.contains_key(&key) {
+            return Err(Error::RemoveNonexistKey);
+        }
+        let seq = writer.next_seq();
+        let command = Command::Rm { key, seq };
+        let meta = writer.append_log(&command, &self.shared)?;
+        // Use this to pass test
         if IS_TEST {
             writer.file.flush()?;
         }
+        writer.useless_size += apply_command(&key_dir, &self.shared.history.load(), &command, meta);
+        writer.notify(&command);
+
         if writer.useless_size > COMPACT_THRESHOLD {
             self.compact(writer.deref_mut())?;
         }
         Ok(())
     }
 
-    /// Remove the key, write to log
-    fn remove(&self, key: String) -> Result<()> {
+    fn scan(&self, prefix: &str) -> Result<Vec<(String, String)>> {
+        self.retry_compacted(|| scan(&self.shared.key_dir.load(), prefix, &self.shared))
+    }
+    /// Only the values of the page are read
+    fn scan_page(&self, prefix: &str, start: &str, limit: usize) -> Result<Vec<(String, String)>> {
+        self.retry_compacted(|| {
+            scan_page(
+                &self.shared.key_dir.load(),
+                prefix,
+                start,
+                limit,
+                &self.shared,
+            )
+        })
+    }
+
+    /// Pin the sequence number of the last write, reads of the snapshot go through the history.
+    ///
+    /// Compaction keeps the writes seen as of the pinned number until the snapshot is dropped.
+    fn snapshot(&self) -> Result<Self::Snapshot> {
         let mut writer = self.shared.writer.lock().unwrap();
-        if let Some((_, CommandMeta { len, .. })) = self.shared.key_dir.load().remove(&key) {
-            writer.useless_size += len;
-        } else {
-            return Err(Error::RemoveNonexistKey);
+        // Readers of the snapshot may read the active file
+        writer.file.flush()?;
+        let seq = writer.last_seq;
+        *self
+            .shared
+            .snapshot_seqs
+            .lock()
+            .unwrap()
+            .entry(seq)
+            .or_insert(0) += 1;
+        drop(writer);
+
+        Ok(KvStoreSnapshot {
+            store: self.clone(),
+            seq,
+        })
+    }
+
+    fn tail_log(&self, from: LogPosition) -> Result<LogTail> {
+        LogTail::new(Arc::clone(&self.shared), from)
+    }
+
+    /// Watchers are notified by writes after they are applied, under the writer lock
+    fn watch(&self, prefix: &str) -> Result<Receiver<Event>> {
+        Ok(self.shared.writer.lock().unwrap().watchers.add(prefix))
+    }
+
+    fn get_versioned(&self, key: &str) -> Result<Option<(String, u64)>> {
+        self.retry_compacted(|| {
+            let meta;
+            if let Some(found) = self.shared.key_dir.load().get(key) {
+                meta = *found;
+            } else {
+                return Ok(None);
+            }
+            let value = read_value(key, meta, &self.shared)?;
+            Ok(Some((value, meta.seq)))
+        })
+    }
+
+    /// Find the last write not after `seq`, from the live one to older ones in the history
+    fn get_at(&self, key: &str, seq: u64) -> Result<Option<String>> {
+        if seq < self.shared.history_floor.load(Ordering::SeqCst) {
+            return Err(Error::HistoryCompacted);
         }
-        let command = Command::Rm { key };
-        writer.append_log(command, &self.shared.curr_dir)?;
+        self.value_at(key, seq)
+    }
 
-        // Use this to pass test
+    fn history(&self, key: &str, limit: usize) -> Result<Vec<(u64, Option<String>)>> {
+        self.retry_compacted(|| {
+            let live = self.shared.key_dir.load().get(key).map(|meta| *meta);
+            let mut writes = self
+                .shared
+                .history
+                .load()
+                .get(key)
+                .map(|writes| writes.clone())
+                .unwrap_or_default();
+            // The live write may have been moved into the history meanwhile
+            writes.extend(live.map(KeyWrite::from));
+            writes.dedup_by_key(|write| write.seq);
+            writes
+                .into_iter()
+                .rev()
+                .take(limit)
+                .map(|write| {
+                    let value = write
+                        .meta
+                        .map(|meta| read_value(key, meta, &self.shared))
+                        .transpose()?;
+                    Ok((write.seq, value))
+                })
+                .collect()
+        })
+    }
+
+    /// Validate the reads and append one `Txn` log under the writer lock
+    fn commit(&self, reads: &ReadSet, mut writes: WriteSet) -> Result<bool> {
+        let mut writer = self.shared.writer.lock().unwrap();
+        let key_dir = self.shared.key_dir.load();
+        for (key, version) in reads {
+            if key_dir.get(key).map(|meta| meta.seq) != *version {
+                return Ok(false);
+            }
+        }
+        writes.retain(|key, value| value.is_some() || key_dir.contains_key(key));
+        if writes.is_empty() {
+            return Ok(true);
+        }
+
+        let seq = writer.next_seq();
+        let commands = writes
+            .into_iter()
+            .map(|(key, value)| match value {
+                Some(value) => Command::Set { key, value, seq },
+                None => Command::Rm { key, seq },
+            })
+            .collect::<Vec<_>>();
+        let command = Command::Txn(commands);
+        let meta = writer.append_log(&command, &self.shared)?;
         if IS_TEST {
             writer.file.flush()?;
         }
+        writer.useless_size += apply_command(&key_dir, &self.shared.history.load(), &command, meta);
+        writer.notify(&command);
+
         if writer.useless_size > COMPACT_THRESHOLD {
             self.compact(writer.deref_mut())?;
         }
-        Ok(())
+        Ok(true)
+    }
+
+    /// Freeze the active data file, then copy all immutable data files.
+    ///
+    /// Writes are only blocked while freezing, the frozen files are pinned
+    /// so compaction won't delete them during copying.
+    fn checkpoint(&self, dest_dir: &Path) -> Result<()> {
+        if dest_dir.exists() && dest_dir.read_dir()?.next().is_some() {
+            return Err(Error::NonEmptyCheckpointDir);
+        }
+        let (file_ids, active_file_id, history_floor) = {
+            let mut writer = self.shared.writer.lock().unwrap();
+            writer.file.flush()?;
+            writer.create_new_data_file(&self.shared)?;
+            let active_file_id = writer.curr_file_id;
+            // Files compaction has dropped only wait for their pins, they must not be copied
+            let mut pinned = self.shared.pinned_files.lock().unwrap();
+            let file_ids = data_file_ids(&self.shared.curr_dir)?
+                .into_iter()
+                .filter(|&file_id| file_id < active_file_id && !pinned.obsolete.contains(&file_id))
+                .collect::<Vec<_>>();
+            for &file_id in &file_ids {
+                *pinned.pins.entry(file_id).or_insert(0) += 1;
+            }
+            drop(pinned);
+            let history_floor = self.shared.history_floor.load(Ordering::SeqCst);
+            (file_ids, active_file_id, history_floor)
+        };
+
+        let copy_result = (|| {
+            fs::create_dir_all(dest_dir)?;
+            for &file_id in &file_ids {
+                let src = self.shared.data_file_path(file_id);
+                let dest = dest_dir.join(format!("{file_id}.dat"));
+                // The frozen files are never written again, it's safe to share them
+                if fs::hard_link(&src, &dest).is_err() {
+                    fs::copy(&src, &dest)?;
+                }
+            }
+            // So the opened checkpoint won't append to a shared file
+            File::create(dest_dir.join(format!("{active_file_id}.dat")))?;
+            let manifest = Manifest {
+                file_ids: file_ids.clone(),
+                active_file_id,
+            };
+            fs::write(dest_dir.join("MANIFEST"), serde_json::to_vec(&manifest)?)?;
+            fs::write(
+                dest_dir.join(HISTORY_FLOOR_FILE),
+                serde_json::to_vec(&history_floor)?,
+            )?;
+            File::create(dest_dir.join("kvs"))?;
+            Ok(())
+        })();
+        self.shared.unpin_files(&file_ids)?;
+        copy_result
+    }
+}
+
+/// A frozen view of `KvStore`, see `KvsEngine::snapshot`
+pub struct KvStoreSnapshot {
+    store: KvStore,
+    /// Writes after it are not seen, those seen are kept until the snapshot is dropped
+    seq: u64,
+}
+
+impl KvsSnapshot for KvStoreSnapshot {
+    fn get(&self, key: &str) -> Result<Option<String>> {
+        self.store.value_at(key, self.seq)
+    }
+    fn scan(&self, prefix: &str) -> Result<Vec<(String, String)>> {
+        self.store.scan_at(prefix, self.seq)
     }
 }
 
-/// When compacting, readers can still read.
+impl Drop for KvStoreSnapshot {
+    fn drop(&mut self) {
+        let mut seqs = self.store.shared.snapshot_seqs.lock().unwrap();
+        if let btree_map::Entry::Occupied(mut entry) = seqs.entry(self.seq) {
+            *entry.get_mut() -= 1;
+            if *entry.get() == 0 {
+                entry.remove();
+            }
+        }
+    }
+}
+
+/// Data files opened for reading.
 ///
-/// And after new compacted data files generated,
-/// readers should update its files.
-struct Readers {
-    /// If `global_version` is greater than reader's `local_version`,
-    /// clear the files to load new files, and update `local_verion`
-    local_version: AtomicU32,
-    files: RefCell<HashMap<u32, BufReader>>,
-}
-
-impl Readers {
-    /// Find value in the disk.
-    fn read_value(&self, file_id: u32, file_offset: u32, shared: &SharedState) -> Result<String> {
-        let mut files = self.files.borrow_mut();
+/// Files other than the active one are never written again, so each is mapped once
+/// and read by slicing. The active file grows, it's read at offsets instead.
+/// Compaction may delete a file while it's read, the reads holding its map still finish.
+struct DataFiles {
+    mapped: RwLock<HashMap<u32, Arc<Mmap>>>,
+    /// The id of the file new writes go to, and the file
+    active: RwLock<(u32, File)>,
+}
+
+impl DataFiles {
+    /// Read the record at the offset.
+    ///
+    /// The `len` of a meta is only its share of a transaction, so the record is parsed
+    /// until it ends instead.
+    fn read(&self, meta: CommandMeta, dir: &Path) -> Result<Command> {
+        let CommandMeta {
+            file_id,
+            file_offset,
+            ..
+        } = meta;
         {
-            let global_version = shared.global_version.load(Ordering::SeqCst);
-            if global_version > self.local_version.load(Ordering::SeqCst) {
-                files.clear();
-                self.local_version.store(global_version, Ordering::SeqCst);
+            let active = self.active.read().unwrap();
+            if active.0 == file_id {
+                let reader = io::BufReader::new(ReadAt {
+                    file: &active.1,
+                    offset: file_offset as u64,
+                });
+                return first_record(Deserializer::from_reader(reader).into_iter());
             }
         }
-        let reader = match files.entry(file_id) {
-            Entry::Vacant(entry) => {
-                let file = File::open(shared.curr_dir.join(format!("{file_id}.dat")))?;
-                let reader = BufReader::new(file);
-                entry.insert(reader)
-            }
-            Entry::Occupied(entry) => entry.into_mut(),
-        };
+        let map = self.map(file_id, dir)?;
+        first_record(Deserializer::from_slice(&map[file_offset as usize..]).into_iter())
+    }
+    fn map(&self, file_id: u32, dir: &Path) -> Result<Arc<Mmap>> {
+        if let Some(map) = self.mapped.read().unwrap().get(&file_id) {
+            return Ok(Arc::clone(map));
+        }
+        let file = File::open(dir.join(format!("{file_id}.dat")))?;
+        // SAFETY: a data file is never modified once it's no longer active
+        let map = Arc::new(unsafe { Mmap::map(&file)? });
+        let mut mapped = self.mapped.write().unwrap();
+        Ok(Arc::clone(mapped.entry(file_id).or_insert(map)))
+    }
+    /// Switch reads of the new active file to offsets, the old one gets mapped
+    fn set_active(&self, file_id: u32, dir: &Path) -> Result<()> {
+        let file = File::open(dir.join(format!("{file_id}.dat")))?;
+        *self.active.write().unwrap() = (file_id, file);
+        Ok(())
+    }
+}
 
-        reader.seek(file_offset as u64)?;
-        let mut de = Deserializer::from_reader(reader).into_iter::<Command>();
+fn first_record<'de, R: serde_json::de::Read<'de>>(
+    mut de: StreamDeserializer<'de, R, Command>,
+) -> Result<Command> {
+    match de.next() {
+        Some(command) => Ok(command?),
+        None => unreachable!(),
+    }
+}
 
-        let command = match de.next() {
-            Some(command) => command?,
-            None => unreachable!(),
-        };
-        match command {
-            Command::Set { value, .. } => Ok(value),
-            Command::Rm { .. } => unreachable!(),
+/// Reads a file from an offset without moving the shared cursor
+struct ReadAt<'a> {
+    file: &'a File,
+    offset: u64,
+}
+
+impl Read for ReadAt<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.file.read_at(buf, self.offset)?;
+        self.offset += n as u64;
+        Ok(n)
+    }
+}
+
+/// Find value of the key in the cache or the disk.
+fn read_value(key: &str, meta: CommandMeta, shared: &SharedState) -> Result<String> {
+    let Some(cache) = &shared.value_cache else {
+        return Ok(value_of(&read_record(meta, shared)?, key).to_owned());
+    };
+    let global_version = shared.global_version.load(Ordering::SeqCst);
+    let record = cache.get_or_read((meta.file_id, meta.file_offset), global_version, || {
+        read_record(meta, shared)
+    })?;
+    Ok(value_of(&record, key).to_owned())
+}
+
+/// Read the record holding the value from the disk.
+fn read_record(meta: CommandMeta, shared: &SharedState) -> Result<Command> {
+    shared.data_files.read(meta, &shared.curr_dir)
+}
+
+/// Find all pairs with the prefix in the key dir, sorted by key.
+fn scan(
+    key_dir: &DashMap<String, CommandMeta>,
+    prefix: &str,
+    shared: &SharedState,
+) -> Result<Vec<(String, String)>> {
+    scan_page(key_dir, prefix, "", usize::MAX, shared)
+}
+
+/// Find at most `limit` pairs with the prefix from the key `start`, sorted by key.
+fn scan_page(
+    key_dir: &DashMap<String, CommandMeta>,
+    prefix: &str,
+    start: &str,
+    limit: usize,
+    shared: &SharedState,
+) -> Result<Vec<(String, String)>> {
+    let mut metas = key_dir
+        .iter()
+        .filter(|kv_pair| kv_pair.key().starts_with(prefix) && kv_pair.key().as_str() >= start)
+        .map(|kv_pair| (kv_pair.key().to_owned(), *kv_pair.value()))
+        .collect::<Vec<_>>();
+    metas.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+    metas.truncate(limit);
+    metas
+        .into_iter()
+        .map(|(key, meta)| {
+            let value = read_value(&key, meta, shared)?;
+            Ok((key, value))
+        })
+        .collect()
+}
+
+/// The value of the key written by the record
+fn value_of<'a>(command: &'a Command, key: &str) -> &'a str {
+    match command {
+        Command::Set { value, .. } => value,
+        Command::Rm { .. } => unreachable!(),
+        // The last write of the key in the transaction must be the `Set`
+        Command::Txn(commands) => {
+            match commands.iter().rev().find_map(|command| match command {
+                Command::Set { key: k, value, .. } if k == key => Some(value),
+                _ => None,
+            }) {
+                Some(value) => value,
+                None => unreachable!(),
+            }
         }
     }
 }
@@ -309,21 +1352,47 @@ struct Writer {
     curr_file_id: u32,
     /// When writing, may be mutated
     useless_size: u32,
+    /// Sequence number of the last written log
+    last_seq: u64,
+    /// How many latest sequence numbers compaction keeps the history for
+    retention: u64,
     file: BufWriter,
+    watchers: Watchers,
 }
 
 impl Writer {
+    /// Sequence number for a new write
+    fn next_seq(&mut self) -> u64 {
+        self.last_seq += 1;
+        self.last_seq
+    }
+
+    /// Tell the watchers about the applied command
+    fn notify(&mut self, command: &Command) {
+        match command {
+            Command::Set { key, value, .. } => self
+                .watchers
+                .notify(key, || Event::Set(key.clone(), value.clone())),
+            Command::Rm { key, .. } => self.watchers.notify(key, || Event::Rm(key.clone())),
+            Command::Txn(commands) => {
+                for command in commands {
+                    self.notify(command);
+                }
+            }
+        }
+    }
+
     /// Append write log in the disk, return the log's meta.
     ///
-    /// If the data file is full, create new one and increment `curr_file_id`
-    fn append_log(&mut self, command: Command, dir: &Path) -> Result<CommandMeta> {
+    /// If the data file is full, create new one and increment `curr_file_id`.
+    /// A log larger than a data file, such as a big transaction, takes a file alone.
+    fn append_log(&mut self, command: &Command, shared: &SharedState) -> Result<CommandMeta> {
         let mut file_offset = self.file.file_offset() as u32;
 
-        let log = serde_json::to_vec(&command)?;
-        assert!(log.len() as u32 <= MAX_DATA_FILE_SIZE);
-        if log.len() as u32 + file_offset > MAX_DATA_FILE_SIZE {
+        let log = serde_json::to_vec(command)?;
+        if file_offset > 0 && log.len() as u32 + file_offset > MAX_DATA_FILE_SIZE {
             self.curr_file_id += 1;
-            self.create_new_data_file(dir)?;
+            self.create_new_data_file(shared)?;
             file_offset = 0;
         }
         self.file.write_all(&log)?;
@@ -331,17 +1400,24 @@ impl Writer {
             file_id: self.curr_file_id,
             file_offset,
             len: log.len() as u32,
+            seq: command.seq(),
         };
         Ok(meta)
     }
 
     /// create or open a data file, return a reader and a writer
-    fn create_new_data_file(&mut self, dir: &Path) -> Result<()> {
+    fn create_new_data_file(&mut self, shared: &SharedState) -> Result<()> {
+        // Log tails take a file as complete once a newer one exists
+        self.file.flush()?;
         self.curr_file_id += 1;
-        let curr_file_path = dir.join(format!("{}.dat", self.curr_file_id));
+        let curr_file_path = shared.data_file_path(self.curr_file_id);
         self.file = BufWriter::create_new(curr_file_path)?;
         self.file.seek(io::SeekFrom::End(0))?;
         self.file.set_file_offset(0);
+        // Before any record of the new file is in the key dir
+        shared
+            .data_files
+            .set_active(self.curr_file_id, &shared.curr_dir)?;
 
         Ok(())
     }
@@ -355,13 +1431,15 @@ pub mod rwlock {
         io::{self, BufReader, BufWriter, Seek, Write},
         ops::DerefMut,
         path::{Path, PathBuf},
-        sync::{Arc, Mutex, RwLock},
+        sync::{mpsc::Receiver, Arc, Mutex, RwLock},
     };
 
     use serde::{Deserialize, Serialize};
     use serde_json::Deserializer;
 
-    use crate::{Error, KvsEngine, Result, IS_TEST};
+    use crate::{
+        watch::Watchers, Error, Event, KvsEngine, MemSnapshot, ReadSet, Result, WriteSet, IS_TEST,
+    };
 
     const MAX_DATA_FILE_SIZE: u32 = if IS_TEST { 0x1000 } else { 0x10000 };
     const COMPACT_THRESHOLD: u32 = if IS_TEST { 0x2000 } else { 0x200000 };
@@ -380,18 +1458,61 @@ pub mod rwlock {
         key_dir: HashMap<String, CommandMeta>,
         readers: BTreeMap<u32, Mutex<BufReader<File>>>,
         writer: BufWriter<File>,
+        /// Version of the last written log
+        last_version: u64,
+        watchers: Watchers,
+        /// Map from the id of a file being copied by checkpoints to its pin count
+        pins: HashMap<u32, u32>,
+        /// Pinned files which compaction wants to delete
+        obsolete: Vec<u32>,
     }
 
+    #[derive(Clone, Copy)]
     struct CommandMeta {
         file_id: u32,
         file_offset: u32,
         len: u32,
+        version: u64,
     }
 
     #[derive(Deserialize, Serialize)]
     enum Command {
-        Set { key: String, value: String },
-        Rm { key: String },
+        Set {
+            key: String,
+            value: String,
+        },
+        Rm {
+            key: String,
+        },
+        /// Writes of a committed transaction, applied all or none
+        Txn(Vec<Command>),
+    }
+
+    /// Apply a replayed command to the key dir, return the size it makes useless
+    fn apply_command(
+        key_dir: &mut HashMap<String, CommandMeta>,
+        command: Command,
+        meta: CommandMeta,
+    ) -> u32 {
+        match command {
+            Command::Set { key, .. } => key_dir.insert(key, meta).map_or(0, |old| old.len),
+            Command::Rm { key } => key_dir.remove(&key).map_or(0, |old| old.len),
+            Command::Txn(commands) => {
+                // Share the record between the keys set by it
+                let n_sets = commands
+                    .iter()
+                    .filter(|command| matches!(command, Command::Set { .. }))
+                    .count() as u32;
+                let meta = CommandMeta {
+                    len: meta.len / n_sets.max(1),
+                    ..meta
+                };
+                commands
+                    .into_iter()
+                    .map(|command| apply_command(key_dir, command, meta))
+                    .sum()
+            }
+        }
     }
 
     impl KvStore {
@@ -420,6 +1541,7 @@ pub mod rwlock {
                 readers.insert(file_id, Mutex::new(reader));
             }
             let mut key_dir = HashMap::new();
+            let mut last_version = 0;
             for (&file_id, reader) in readers.iter_mut() {
                 let mut reader = reader.lock().unwrap();
                 let mut de = Deserializer::from_reader(reader.deref_mut()).into_iter();
@@ -427,23 +1549,14 @@ pub mod rwlock {
                 while let Some(command) = de.next() {
                     let command = command?;
                     let new_offset = de.byte_offset() as u32;
-                    match command {
-                        Command::Set { key, .. } => {
-                            let meta = CommandMeta {
-                                file_id,
-                                file_offset,
-                                len: new_offset - file_offset,
-                            };
-                            if let Some(CommandMeta { len, .. }) = key_dir.insert(key, meta) {
-                                useless_size += len;
-                            }
-                        }
-                        Command::Rm { key } => {
-                            if let Some(CommandMeta { len, .. }) = key_dir.remove(&key) {
-                                useless_size += len;
-                            }
-                        }
+                    last_version += 1;
+                    let meta = CommandMeta {
+                        file_id,
+                        file_offset,
+                        len: new_offset - file_offset,
+                        version: last_version,
                     };
+                    useless_size += apply_command(&mut key_dir, command, meta);
                     file_offset = new_offset;
                 }
             }
@@ -466,6 +1579,10 @@ pub mod rwlock {
                 key_dir,
                 readers,
                 writer: BufWriter::new(write_file),
+                last_version,
+                watchers: Watchers::default(),
+                pins: HashMap::new(),
+                obsolete: Vec::new(),
             };
 
             Ok(Self {
@@ -496,15 +1613,10 @@ pub mod rwlock {
                 .insert(self.curr_file_id, Mutex::new(read_file));
             Ok(())
         }
-        /// set value in the disk
-        fn set_impl(&mut self, key: String, value: String) -> Result<()> {
+        /// Append the command to the log, return where it's written
+        fn append_log(&mut self, command: &Command) -> Result<CommandMeta> {
             let mut file_offset = self.writer.stream_position()? as u32;
-
-            let command = Command::Set {
-                key: key.clone(),
-                value,
-            };
-            let log = serde_json::to_vec(&command)?;
+            let log = serde_json::to_vec(command)?;
             assert!(log.len() as u32 <= MAX_DATA_FILE_SIZE);
             if log.len() as u32 + file_offset > MAX_DATA_FILE_SIZE {
                 self.curr_file_id += 1;
@@ -512,19 +1624,47 @@ pub mod rwlock {
                 file_offset = 0;
             }
             self.writer.write_all(&log)?;
-            let meta = CommandMeta {
+            self.last_version += 1;
+            Ok(CommandMeta {
                 file_id: self.curr_file_id,
                 file_offset,
                 len: log.len() as u32,
+                version: self.last_version,
+            })
+        }
+        /// set value in the disk
+        fn set_impl(&mut self, key: String, value: String) -> Result<()> {
+            let command = Command::Set {
+                key: key.clone(),
+                value,
             };
+            let meta = self.append_log(&command)?;
             if let Some(CommandMeta { len, .. }) = self.key_dir.insert(key, meta) {
                 self.useless_size += len;
             }
             Ok(())
         }
+        /// remove key in the disk
+        fn remove_impl(&mut self, key: String) -> Result<()> {
+            if let Some(CommandMeta { len, .. }) = self.key_dir.remove(&key) {
+                self.useless_size += len;
+            } else {
+                return Err(Error::RemoveNonexistKey);
+            }
+            let command = Command::Rm { key };
+            let log = serde_json::to_vec(&command)?;
+            assert!(log.len() as u32 <= MAX_DATA_FILE_SIZE);
+            if log.len() as u32 + self.writer.stream_position()? as u32 > MAX_DATA_FILE_SIZE {
+                self.curr_file_id += 1;
+                self.create_new_data_file()?;
+            }
+            self.writer.write_all(&log)?;
+            Ok(())
+        }
         // find value in the disk
         fn get_impl(
             readers: &BTreeMap<u32, Mutex<BufReader<File>>>,
+            key: &str,
             file_id: u32,
             file_offset: u32,
         ) -> Result<String> {
@@ -540,7 +1680,39 @@ pub mod rwlock {
             match command {
                 Command::Set { value, .. } => Ok(value),
                 Command::Rm { .. } => unreachable!(),
+                // The last write of the key in the transaction must be the `Set`
+                Command::Txn(commands) => {
+                    match commands
+                        .into_iter()
+                        .rev()
+                        .find_map(|command| match command {
+                            Command::Set { key: k, value } if k == key => Some(value),
+                            _ => None,
+                        }) {
+                        Some(value) => Ok(value),
+                        None => unreachable!(),
+                    }
+                }
+            }
+        }
+        /// Unpin the files, and delete those which compaction has dropped
+        fn unpin_files(&mut self, file_ids: &[u32]) -> Result<()> {
+            for file_id in file_ids {
+                if let Some(pins) = self.pins.get_mut(file_id) {
+                    *pins -= 1;
+                    if *pins == 0 {
+                        self.pins.remove(file_id);
+                    }
+                }
+            }
+            let (pinned, released) = std::mem::take(&mut self.obsolete)
+                .into_iter()
+                .partition(|file_id| self.pins.contains_key(file_id));
+            self.obsolete = pinned;
+            for file_id in released {
+                fs::remove_file(self.curr_dir.join(format!("{file_id}.dat")))?;
             }
+            Ok(())
         }
         /// try to begin compacting
         ///
@@ -560,16 +1732,25 @@ pub mod rwlock {
                 CommandMeta {
                     file_id,
                     file_offset,
+                    version,
                     ..
                 },
             ) in key_dir
             {
-                let value = Self::get_impl(&mut readers, file_id, file_offset)?;
-                self.set_impl(key, value)?;
+                let value = Self::get_impl(&mut readers, &key, file_id, file_offset)?;
+                self.set_impl(key.clone(), value)?;
+                // Moving the value is not a write, keep the version
+                if let Some(meta) = self.key_dir.get_mut(&key) {
+                    meta.version = version;
+                }
             }
 
             for (file_id, _) in readers {
-                std::fs::remove_file(self.curr_dir.join(format!("{file_id}.dat")))?;
+                if self.pins.contains_key(&file_id) {
+                    self.obsolete.push(file_id);
+                } else {
+                    std::fs::remove_file(self.curr_dir.join(format!("{file_id}.dat")))?;
+                }
             }
 
             // get enough threshold
@@ -578,6 +1759,8 @@ pub mod rwlock {
     }
 
     impl KvsEngine for KvStore {
+        type Snapshot = MemSnapshot;
+
         /// get the value the `key` corresponding to
         fn get(&self, key: &str) -> Result<Option<String>> {
             let inner = self.inner.read().unwrap();
@@ -589,12 +1772,16 @@ pub mod rwlock {
             {
                 return Ok(None);
             };
-            Inner::get_impl(&inner.readers, file_id, file_offset).map(Some)
+            Inner::get_impl(&inner.readers, key, file_id, file_offset).map(Some)
         }
         /// Set the value corresponding to key to `value`
         fn set(&self, key: String, value: String) -> Result<()> {
             let mut inner = self.inner.write().unwrap();
-            inner.set_impl(key, value)?;
+            inner.set_impl(key.clone(), value.clone())?;
+            // Readers are blocked until the lock is released, so watchers never read the old value
+            inner
+                .watchers
+                .notify(&key, || Event::Set(key.clone(), value));
             if IS_TEST {
                 inner.writer.flush()?;
             }
@@ -606,26 +1793,129 @@ pub mod rwlock {
         /// Remove the key, write to log
         fn remove(&self, key: String) -> Result<()> {
             let mut inner = self.inner.write().unwrap();
-            if let Some(CommandMeta { len, .. }) = inner.key_dir.remove(&key) {
-                inner.useless_size += len;
-            } else {
-                return Err(Error::RemoveNonexistKey);
+            inner.remove_impl(key.clone())?;
+            inner.watchers.notify(&key, || Event::Rm(key.clone()));
+            if IS_TEST {
+                inner.writer.flush()?;
             }
-            let command = Command::Rm { key };
-            let log = serde_json::to_vec(&command)?;
-            assert!(log.len() as u32 <= MAX_DATA_FILE_SIZE);
-            if log.len() as u32 + inner.writer.stream_position()? as u32 > MAX_DATA_FILE_SIZE {
-                inner.curr_file_id += 1;
+            if inner.useless_size > COMPACT_THRESHOLD {
+                inner.compact()?;
+            }
+            Ok(())
+        }
+        fn scan(&self, prefix: &str) -> Result<Vec<(String, String)>> {
+            let inner = self.inner.read().unwrap();
+            let mut pairs = inner
+                .key_dir
+                .iter()
+                .filter(|(key, _)| key.starts_with(prefix))
+                .map(|(key, meta)| {
+                    Inner::get_impl(&inner.readers, key, meta.file_id, meta.file_offset)
+                        .map(|value| (key.clone(), value))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            pairs.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+            Ok(pairs)
+        }
+        /// Freeze the active data file, then copy the frozen files after releasing the lock.
+        ///
+        /// The frozen files are pinned, so compaction leaves them to the checkpoint to delete.
+        fn checkpoint(&self, dest_dir: &Path) -> Result<()> {
+            if dest_dir.exists() && dest_dir.read_dir()?.next().is_some() {
+                return Err(Error::NonEmptyCheckpointDir);
+            }
+            let (curr_dir, file_ids) = {
+                let mut inner = self.inner.write().unwrap();
+                inner.writer.flush()?;
                 inner.create_new_data_file()?;
+                let active_file_id = inner.curr_file_id;
+                let file_ids = inner
+                    .readers
+                    .keys()
+                    .copied()
+                    .filter(|&file_id| file_id < active_file_id)
+                    .collect::<Vec<_>>();
+                for &file_id in &file_ids {
+                    *inner.pins.entry(file_id).or_insert(0) += 1;
+                }
+                (inner.curr_dir.clone(), file_ids)
+            };
+
+            let copy_result = (|| {
+                fs::create_dir_all(dest_dir)?;
+                for &file_id in &file_ids {
+                    let file_name = format!("{file_id}.dat");
+                    fs::copy(curr_dir.join(&file_name), dest_dir.join(&file_name))?;
+                }
+                File::create(dest_dir.join("kvs"))?;
+                Ok(())
+            })();
+            self.inner.write().unwrap().unpin_files(&file_ids)?;
+            copy_result
+        }
+        /// Copy all pairs into memory while holding the read lock
+        fn snapshot(&self) -> Result<Self::Snapshot> {
+            Ok(MemSnapshot::new(self.scan("")?))
+        }
+        fn watch(&self, prefix: &str) -> Result<Receiver<Event>> {
+            Ok(self.inner.write().unwrap().watchers.add(prefix))
+        }
+        fn get_versioned(&self, key: &str) -> Result<Option<(String, u64)>> {
+            let inner = self.inner.read().unwrap();
+            let Some(&CommandMeta {
+                file_id,
+                file_offset,
+                version,
+                ..
+            }) = inner.key_dir.get(key) else
+            {
+                return Ok(None);
+            };
+            let value = Inner::get_impl(&inner.readers, key, file_id, file_offset)?;
+            Ok(Some((value, version)))
+        }
+        /// Validate the reads and append one `Txn` log under the write lock
+        fn commit(&self, reads: &ReadSet, mut writes: WriteSet) -> Result<bool> {
+            let mut inner = self.inner.write().unwrap();
+            for (key, version) in reads {
+                if inner.key_dir.get(key).map(|meta| meta.version) != *version {
+                    return Ok(false);
+                }
+            }
+            writes.retain(|key, value| value.is_some() || inner.key_dir.contains_key(key));
+            if writes.is_empty() {
+                return Ok(true);
+            }
+
+            let commands = writes
+                .iter()
+                .map(|(key, value)| match value {
+                    Some(value) => Command::Set {
+                        key: key.clone(),
+                        value: value.clone(),
+                    },
+                    None => Command::Rm { key: key.clone() },
+                })
+                .collect();
+            let command = Command::Txn(commands);
+            let meta = inner.append_log(&command)?;
+            let useless_size = apply_command(&mut inner.key_dir, command, meta);
+            inner.useless_size += useless_size;
+            for (key, value) in writes {
+                match value {
+                    Some(value) => inner
+                        .watchers
+                        .notify(&key, || Event::Set(key.clone(), value)),
+                    None => inner.watchers.notify(&key, || Event::Rm(key.clone())),
+                }
             }
-            inner.writer.write_all(&log)?;
             if IS_TEST {
                 inner.writer.flush()?;
             }
             if inner.useless_size > COMPACT_THRESHOLD {
                 inner.compact()?;
             }
-            Ok(())
+            Ok(true)
         }
     }
 }
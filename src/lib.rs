@@ -5,37 +5,149 @@
 
 mod error;
 mod kvstore;
+mod memory;
+mod replication;
 mod server;
+mod sharding;
 mod sled;
+mod snapshot;
+mod transaction;
+mod value_cache;
+mod watch;
 
 mod buf_file;
+mod cache;
 mod client;
+pub mod raft;
 /// Thread pool impl
 pub mod thread_pool;
 
 use std::{
     io::{self, Read},
     net::TcpStream,
+    path::Path,
+    sync::mpsc::Receiver,
+    time::Duration,
 };
 
 const IS_TEST: bool = true;
 
 pub use crate::{
-    client::KvsClient,
+    cache::{CacheConfig, CacheEngine, CacheSnapshot, CacheStats, EvictionPolicy},
+    client::{EventStream, KvsClient},
     error::{Error, Result},
-    kvstore::{rwlock, KvStore},
+    kvstore::{
+        data_file_ids, log_file_ids, rwlock, Command, DataFileReader, KvStore, KvStoreOptions,
+        KvStoreSnapshot, LogPosition, LogRecord, LogTail,
+    },
+    memory::MemKvsEngine,
+    replication::{Replica, ReplicaStatus},
     server::{shutdown, KvsServer},
+    sharding::{HashRing, ShardedKvsClient},
     sled::SledKvsEngine,
+    snapshot::MemSnapshot,
+    transaction::{ReadSet, Transaction, WriteSet},
+    value_cache::ValueCacheStats,
+    watch::Event,
 };
+use transaction::MAX_TRANSACTION_RETRIES;
 
 /// A key-value engine
 pub trait KvsEngine: Clone + Send + 'static {
+    /// Read-only view returned by `snapshot`
+    type Snapshot: KvsSnapshot + Send + 'static;
+
     /// Set the value corresponding to key to `value`,
     fn set(&self, key: String, value: String) -> Result<()>;
     /// get the value the `key` corresponding to
     fn get(&self, key: &str) -> Result<Option<String>>;
     /// Remove the key
     fn remove(&self, key: String) -> Result<()>;
+    /// Get all key-value pairs whose key starts with `prefix`, sorted by key
+    fn scan(&self, prefix: &str) -> Result<Vec<(String, String)>>;
+    /// Get at most `limit` pairs of `scan`, starting from the key `start`.
+    ///
+    /// The next page starts from the last key followed by `'\0'`.
+    fn scan_page(&self, prefix: &str, start: &str, limit: usize) -> Result<Vec<(String, String)>> {
+        let mut pairs = self.scan(prefix)?;
+        pairs.retain(|(key, _)| key.as_str() >= start);
+        pairs.truncate(limit);
+        Ok(pairs)
+    }
+    /// Make a consistent copy of the store into `dest_dir`, which must be empty.
+    ///
+    /// The copy can be opened as a store of the same engine.
+    fn checkpoint(&self, dest_dir: &Path) -> Result<()>;
+    /// Get a read-only view of the current state.
+    ///
+    /// Writes after the snapshot is taken are invisible to it.
+    fn snapshot(&self) -> Result<Self::Snapshot>;
+    /// Get the value and its version, which changes whenever the key is written
+    fn get_versioned(&self, key: &str) -> Result<Option<(String, u64)>>;
+    /// Apply the writes atomically if no key in `reads` has changed its version.
+    ///
+    /// Return `false` on conflict, with nothing written. Used by `Transaction`.
+    /// Removals of absent keys are skipped, they are neither written nor watched.
+    fn commit(&self, reads: &ReadSet, writes: WriteSet) -> Result<bool>;
+    /// Receive the events of writes to keys starting with `prefix` from now on.
+    ///
+    /// Dropping the receiver stops watching. A receiver lagging too far behind the writes
+    /// is disconnected after the events it holds.
+    fn watch(&self, prefix: &str) -> Result<Receiver<Event>>;
+    /// Get the value as of sequence number `seq`, which is the version given by `get_versioned`.
+    ///
+    /// Engines without version history return `Error::HistoryUnsupported`.
+    fn get_at(&self, _key: &str, _seq: u64) -> Result<Option<String>> {
+        Err(Error::HistoryUnsupported)
+    }
+    /// Get at most `limit` latest writes of the key with their sequence numbers, newest first.
+    ///
+    /// A removal has no value.
+    fn history(&self, _key: &str, _limit: usize) -> Result<Vec<(u64, Option<String>)>> {
+        Err(Error::HistoryUnsupported)
+    }
+    /// Set the value, which is removed after `ttl`.
+    ///
+    /// Engines without expiration return `Error::TtlUnsupported`.
+    fn set_with_ttl(&self, _key: String, _value: String, _ttl: Duration) -> Result<()> {
+        Err(Error::TtlUnsupported)
+    }
+    /// Get the memory use and eviction counters.
+    ///
+    /// Engines without a memory budget return `Error::CacheUnsupported`.
+    fn cache_stats(&self) -> Result<CacheStats> {
+        Err(Error::CacheUnsupported)
+    }
+    /// Follow the data files from `from`, to ship them to a replica.
+    ///
+    /// Engines without an append-only log return `Error::ReplicationUnsupported`.
+    fn tail_log(&self, _from: LogPosition) -> Result<LogTail> {
+        Err(Error::ReplicationUnsupported)
+    }
+    /// Run `body` in a transaction and commit it.
+    ///
+    /// On conflict `body` is run again, an error returned by `body` aborts the transaction.
+    fn transaction<T, F>(&self, mut body: F) -> Result<T>
+    where
+        F: FnMut(&mut Transaction<'_, Self>) -> Result<T>,
+    {
+        for _ in 0..MAX_TRANSACTION_RETRIES {
+            let mut tx = Transaction::new(self);
+            let output = body(&mut tx)?;
+            if tx.commit()? {
+                return Ok(output);
+            }
+        }
+        Err(Error::TransactionConflict)
+    }
+}
+
+/// A frozen, read-only view of an engine
+pub trait KvsSnapshot {
+    /// get the value the `key` corresponding to
+    fn get(&self, key: &str) -> Result<Option<String>>;
+    /// Get all key-value pairs whose key starts with `prefix`, sorted by key
+    fn scan(&self, prefix: &str) -> Result<Vec<(String, String)>>;
 }
 
 ///
@@ -53,10 +165,40 @@ pub enum Request {
     Get(String) = 1,
     ///
     Rm(String) = 2,
+    /// Several requests sent at once, answered by a `Response::Batch`
+    Batch(Vec<Request>) = 3,
+    /// Get all pairs with the key prefix
+    Scan(String) = 4,
+    /// Get at most the number of pairs with the key prefix, from the start key
+    ScanPage(String, String, u32) = 5,
+    /// Checkpoint the store into the directory on the server
+    Backup(String) = 6,
+    /// Begin a transaction on this connection, following requests are part of it
+    Begin = 7,
+    /// Commit the transaction, answered by `Ok` or `Conflict`
+    Commit = 8,
+    /// Drop the transaction
+    Abort = 9,
+    /// Get the value as of the sequence number
+    GetAt(String, u64) = 10,
+    /// Get at most the number of latest writes of the key
+    History(String, u32) = 11,
+    /// Watch keys with the prefix, answered by `Ok`, then an `Event` for each write.
+    ///
+    /// The connection carries only events afterwards.
+    Watch(String) = 12,
+    /// Replicate the log from the position, answered by `Ok`, or `Resync` if it's compacted.
+    ///
+    /// Then `Record`s follow, with a `Heartbeat` each second.
+    Replicate(LogPosition) = 13,
+    /// Set the key to the value, which expires after the milliseconds
+    SetTtl(String, String, u64) = 14,
+    /// Get the memory use and eviction counters, answered by `CacheStats`
+    CacheStats = 15,
 }
 
 ///
-#[derive(PartialEq, Debug)]
+#[derive(Clone, PartialEq, Debug)]
 pub enum Response {
     ///
     Value(String),
@@ -64,6 +206,26 @@ pub enum Response {
     Ok,
     ///
     NoKey,
+    /// One response for each request of a `Request::Batch`, in order
+    Batch(Vec<Response>),
+    ///
+    Pairs(Vec<(String, String)>),
+    /// The transaction conflicts with others and is dropped
+    Conflict,
+    /// Writes of a key with their sequence numbers, newest first, `None` for removal
+    History(Vec<(u64, Option<String>)>),
+    /// A write streamed to a watcher
+    Event(Event),
+    /// A record of the log streamed to a replica, with its position
+    Record(LogPosition, String),
+    /// Sequence number of the primary's last write, sent to a replica each second
+    Heartbeat(u64),
+    /// The replica must drop its data, the log is streamed from the beginning
+    Resync,
+    /// Memory use and eviction counters of a cache
+    CacheStats(CacheStats),
+    /// The server has too many connections queued, the client may try again later
+    Busy,
     ///
     Err,
 }
@@ -76,6 +238,10 @@ impl Encoder {
     ///
     pub fn encode_request(&mut self, request: Request) -> &[u8] {
         self.bytes.clear();
+        self.encode_request_impl(request);
+        &self.bytes
+    }
+    fn encode_request_impl(&mut self, request: Request) {
         match request {
             Request::Set(key, value) => {
                 self.encode_type(0)
@@ -88,16 +254,82 @@ impl Encoder {
             Request::Rm(key) => {
                 self.encode_type(2).encode_string(&key);
             }
+            Request::Batch(requests) => {
+                self.encode_type(3).encode_len(requests.len() as u32);
+                for request in requests {
+                    self.encode_request_impl(request);
+                }
+            }
+            Request::Scan(prefix) => {
+                self.encode_type(4).encode_string(&prefix);
+            }
+            Request::ScanPage(prefix, start, limit) => {
+                self.encode_type(5)
+                    .encode_string(&prefix)
+                    .encode_string(&start)
+                    .encode_len(limit);
+            }
+            Request::Backup(dest_dir) => {
+                self.encode_type(BACKUP_REQUEST_TYPE)
+                    .encode_string(&dest_dir);
+            }
+            Request::Begin => {
+                self.encode_type(7);
+            }
+            Request::Commit => {
+                self.encode_type(8);
+            }
+            Request::Abort => {
+                self.encode_type(9);
+            }
+            Request::GetAt(key, seq) => {
+                self.encode_type(10).encode_string(&key).encode_u64(seq);
+            }
+            Request::History(key, limit) => {
+                self.encode_type(11).encode_string(&key).encode_len(limit);
+            }
+            Request::Watch(prefix) => {
+                self.encode_type(12).encode_string(&prefix);
+            }
+            Request::Replicate(position) => {
+                self.encode_type(13).encode_position(position);
+            }
+            Request::SetTtl(key, value, ttl) => {
+                self.encode_type(14)
+                    .encode_string(&key)
+                    .encode_string(&value)
+                    .encode_u64(ttl);
+            }
+            Request::CacheStats => {
+                self.encode_type(CACHE_STATS_REQUEST_TYPE);
+            }
         }
-        &self.bytes
     }
     /// encode response to:
     ///
     /// - `Value(value)` -> 0ssssss, s are the bytes of string
-    /// - `None` -> 0
+    /// - `Ok` -> 1
+    /// - `NoKey` -> 2
+    /// - `Batch(responses)` -> 3nnnn, followed by n encoded responses
+    /// - `Pairs(pairs)` -> 4nnnn, followed by n encoded key and value strings
+    /// - `Conflict` -> 5
+    /// - `History(writes)` -> 6nnnn, followed by n writes of 8 bytes sequence number,
+    ///   then 0 for removal or 1 and the value string
+    /// - `Event(Set(key, value))` -> 70, followed by key and value strings
+    /// - `Event(Rm(key))` -> 71, followed by key string
+    /// - `Record(position, record)` -> 8iiiioooo, i and o are the file id and offset,
+    ///   followed by the record string
+    /// - `Heartbeat(seq)` -> 9, followed by 8 bytes sequence number
+    /// - `Resync` -> 10
+    /// - `CacheStats(stats)` -> 11, followed by 8 bytes of each field in order
+    /// - `Busy` -> 12
     /// - `Err` -> 0xff
     pub fn encode_response(&mut self, response: Response) -> &[u8] {
         self.bytes.clear();
+        self.encode_response_impl(response);
+        &self.bytes
+    }
+    fn encode_response_impl(&mut self, response: Response) {
         match response {
             Response::Value(value) => {
                 self.bytes.push(0);
@@ -106,15 +338,74 @@ impl Encoder {
             // why `Response::Ok as u8` doesn't compile?
             Response::Ok => self.bytes.push(1),
             Response::NoKey => self.bytes.push(2),
+            Response::Batch(responses) => {
+                self.encode_type(3).encode_len(responses.len() as u32);
+                for response in responses {
+                    self.encode_response_impl(response);
+                }
+            }
+            Response::Pairs(pairs) => {
+                self.encode_type(4).encode_len(pairs.len() as u32);
+                for (key, value) in pairs {
+                    self.encode_string(&key).encode_string(&value);
+                }
+            }
+            Response::Conflict => self.bytes.push(5),
+            Response::History(writes) => {
+                self.encode_type(6).encode_len(writes.len() as u32);
+                for (seq, value) in writes {
+                    self.encode_u64(seq);
+                    match value {
+                        Some(value) => self.encode_type(1).encode_string(&value),
+                        None => self.encode_type(0),
+                    };
+                }
+            }
+            Response::Event(Event::Set(key, value)) => {
+                self.encode_type(7)
+                    .encode_type(0)
+                    .encode_string(&key)
+                    .encode_string(&value);
+            }
+            Response::Event(Event::Rm(key)) => {
+                self.encode_type(7).encode_type(1).encode_string(&key);
+            }
+            Response::Record(position, record) => {
+                self.encode_type(8)
+                    .encode_position(position)
+                    .encode_string(&record);
+            }
+            Response::Heartbeat(seq) => {
+                self.encode_type(9).encode_u64(seq);
+            }
+            Response::Resync => self.bytes.push(10),
+            Response::CacheStats(stats) => {
+                self.encode_type(11)
+                    .encode_u64(stats.used_memory)
+                    .encode_u64(stats.max_memory)
+                    .encode_u64(stats.keys)
+                    .encode_u64(stats.hits)
+                    .encode_u64(stats.misses)
+                    .encode_u64(stats.evictions)
+                    .encode_u64(stats.expirations);
+            }
+            Response::Busy => self.bytes.push(12),
             Response::Err => self.bytes.push(0xff),
         }
-        &self.bytes
     }
     fn encode_len(&mut self, len: u32) -> &mut Self {
         let len = u32::to_be_bytes(len);
         self.bytes.extend_from_slice(&len);
         self
     }
+    fn encode_u64(&mut self, n: u64) -> &mut Self {
+        self.bytes.extend_from_slice(&u64::to_be_bytes(n));
+        self
+    }
+    fn encode_position(&mut self, position: LogPosition) -> &mut Self {
+        self.encode_len(position.file_id)
+            .encode_len(position.file_offset)
+    }
     fn encode_type(&mut self, type_: u8) -> &mut Self {
         self.bytes.push(type_);
         self
@@ -132,6 +423,15 @@ impl Default for Encoder {
     }
 }
 
+/// Entries reserved for a length read off the wire, the rest grow as they arrive
+const MAX_PREALLOCATED: usize = 1024;
+/// Type byte of `Request::Batch` and `Response::Batch`
+const BATCH_TYPE: u8 = 3;
+/// Type byte of `Request::Backup`, an admin request served before the clients
+pub const BACKUP_REQUEST_TYPE: u8 = 6;
+/// Type byte of `Request::CacheStats`, an admin request served before the clients
+pub const CACHE_STATS_REQUEST_TYPE: u8 = 15;
+
 ///
 pub struct Decoder<'a> {
     buf: Vec<u8>,
@@ -146,6 +446,10 @@ impl<'a> Decoder<'a> {
             reader: io::BufReader::new(stream),
         }
     }
+    /// Whether bytes read off the stream are still waiting to be decoded
+    pub fn has_buffered(&self) -> bool {
+        !self.reader.buffer().is_empty()
+    }
     fn decode_len(&mut self) -> Result<usize> {
         let mut buf = [0; 4];
         if self.reader.read_exact(&mut buf).is_err() {
@@ -153,21 +457,54 @@ impl<'a> Decoder<'a> {
         };
         Ok(u32::from_be_bytes(buf) as usize)
     }
+    fn decode_u64(&mut self) -> Result<u64> {
+        let mut buf = [0; 8];
+        if self.reader.read_exact(&mut buf).is_err() {
+            return Err(Error::DecodeError("Can't get u64".to_string()));
+        };
+        Ok(u64::from_be_bytes(buf))
+    }
+    fn decode_position(&mut self) -> Result<LogPosition> {
+        let file_id = self.decode_len()? as u32;
+        let file_offset = self.decode_len()? as u32;
+        Ok(LogPosition {
+            file_id,
+            file_offset,
+        })
+    }
     fn decode_string(&mut self) -> Result<String> {
         let len = self.decode_len()?;
-        self.buf.resize(len, 0);
-        if self.reader.read_exact(&mut self.buf[0..len]).is_err() {
+        self.buf.clear();
+        // Read what arrives rather than allocating `len` bytes up front
+        let read = (&mut self.reader)
+            .take(len as u64)
+            .read_to_end(&mut self.buf);
+        if !matches!(read, Ok(n) if n == len) {
             return Err(Error::DecodeError("Can't get key".to_string()));
         };
-        Ok(std::str::from_utf8(&self.buf[0..len])?.to_owned())
+        Ok(std::str::from_utf8(&self.buf)?.to_owned())
     }
-    ///
-    pub fn decode_request(&mut self) -> Result<Request> {
+    /// Decode next request, return `None` if the peer closed the connection
+    /// or sent nothing before the read timeout of the stream
+    pub fn decode_request(&mut self) -> Result<Option<Request>> {
         let mut type_ = [0];
-        if self.reader.read_exact(&mut type_).is_err() {
-            return Err(Error::DecodeError("Type byte nonexists".to_string()));
-        };
-        match type_[0] {
+        match self.reader.read(&mut type_) {
+            Ok(0) => return Ok(None),
+            Ok(_) => {}
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                return Ok(None)
+            }
+            Err(_) => return Err(Error::DecodeError("Type byte nonexists".to_string())),
+        }
+        self.decode_request_body(type_[0]).map(Some)
+    }
+    fn decode_request_body(&mut self, type_: u8) -> Result<Request> {
+        match type_ {
             // set
             0 => {
                 let key = self.decode_string()?;
@@ -184,9 +521,79 @@ impl<'a> Decoder<'a> {
                 let key = self.decode_string()?;
                 Ok(Request::Rm(key))
             }
+            // batch
+            3 => {
+                let len = self.decode_len()?;
+                let mut requests = Vec::with_capacity(len.min(MAX_PREALLOCATED));
+                for _ in 0..len {
+                    let type_ = self.decode_type()?;
+                    // Nested batches could recurse until the stack overflows
+                    if type_ == BATCH_TYPE {
+                        return Err(Error::DecodeError("Nested batch".to_string()));
+                    }
+                    requests.push(self.decode_request_body(type_)?);
+                }
+                Ok(Request::Batch(requests))
+            }
+            // scan
+            4 => {
+                let prefix = self.decode_string()?;
+                Ok(Request::Scan(prefix))
+            }
+            // scan page
+            5 => {
+                let prefix = self.decode_string()?;
+                let start = self.decode_string()?;
+                let limit = self.decode_len()? as u32;
+                Ok(Request::ScanPage(prefix, start, limit))
+            }
+            BACKUP_REQUEST_TYPE => {
+                let dest_dir = self.decode_string()?;
+                Ok(Request::Backup(dest_dir))
+            }
+            7 => Ok(Request::Begin),
+            8 => Ok(Request::Commit),
+            9 => Ok(Request::Abort),
+            // get at
+            10 => {
+                let key = self.decode_string()?;
+                let seq = self.decode_u64()?;
+                Ok(Request::GetAt(key, seq))
+            }
+            // history
+            11 => {
+                let key = self.decode_string()?;
+                let limit = self.decode_len()? as u32;
+                Ok(Request::History(key, limit))
+            }
+            // watch
+            12 => {
+                let prefix = self.decode_string()?;
+                Ok(Request::Watch(prefix))
+            }
+            // replicate
+            13 => {
+                let position = self.decode_position()?;
+                Ok(Request::Replicate(position))
+            }
+            // set with TTL
+            14 => {
+                let key = self.decode_string()?;
+                let value = self.decode_string()?;
+                let ttl = self.decode_u64()?;
+                Ok(Request::SetTtl(key, value, ttl))
+            }
+            CACHE_STATS_REQUEST_TYPE => Ok(Request::CacheStats),
             t => Err(Error::DecodeError(format!("Wrong type byte: {t}"))),
         }
     }
+    fn decode_type(&mut self) -> Result<u8> {
+        let mut type_ = [0];
+        if self.reader.read_exact(&mut type_).is_err() {
+            return Err(Error::DecodeError("Type byte nonexists".to_string()));
+        };
+        Ok(type_[0])
+    }
     ///
     pub fn decode_response(&mut self) -> Result<Response> {
         let mut type_ = [0];
@@ -194,13 +601,84 @@ impl<'a> Decoder<'a> {
             log::error!("Type byte error: {e}");
             return Err(Error::DecodeError("Type byte nonexists".to_string()));
         };
-        match type_[0] {
+        self.decode_response_body(type_[0])
+    }
+    fn decode_response_body(&mut self, type_: u8) -> Result<Response> {
+        match type_ {
             0 => {
                 let value = self.decode_string()?;
                 Ok(Response::Value(value))
             }
             1 => Ok(Response::Ok),
             2 => Ok(Response::NoKey),
+            3 => {
+                let len = self.decode_len()?;
+                let mut responses = Vec::with_capacity(len.min(MAX_PREALLOCATED));
+                for _ in 0..len {
+                    let type_ = self.decode_type()?;
+                    if type_ == BATCH_TYPE {
+                        return Err(Error::DecodeError("Nested batch".to_string()));
+                    }
+                    responses.push(self.decode_response_body(type_)?);
+                }
+                Ok(Response::Batch(responses))
+            }
+            4 => {
+                let len = self.decode_len()?;
+                let mut pairs = Vec::with_capacity(len.min(MAX_PREALLOCATED));
+                for _ in 0..len {
+                    let key = self.decode_string()?;
+                    let value = self.decode_string()?;
+                    pairs.push((key, value));
+                }
+                Ok(Response::Pairs(pairs))
+            }
+            5 => Ok(Response::Conflict),
+            6 => {
+                let len = self.decode_len()?;
+                let mut writes = Vec::with_capacity(len.min(MAX_PREALLOCATED));
+                for _ in 0..len {
+                    let seq = self.decode_u64()?;
+                    let value = match self.decode_type()? {
+                        0 => None,
+                        _ => Some(self.decode_string()?),
+                    };
+                    writes.push((seq, value));
+                }
+                Ok(Response::History(writes))
+            }
+            7 => match self.decode_type()? {
+                0 => {
+                    let key = self.decode_string()?;
+                    let value = self.decode_string()?;
+                    Ok(Response::Event(Event::Set(key, value)))
+                }
+                1 => {
+                    let key = self.decode_string()?;
+                    Ok(Response::Event(Event::Rm(key)))
+                }
+                t => Err(Error::DecodeError(format!("Wrong event type byte: {t}"))),
+            },
+            8 => {
+                let position = self.decode_position()?;
+                let record = self.decode_string()?;
+                Ok(Response::Record(position, record))
+            }
+            9 => {
+                let seq = self.decode_u64()?;
+                Ok(Response::Heartbeat(seq))
+            }
+            10 => Ok(Response::Resync),
+            11 => Ok(Response::CacheStats(CacheStats {
+                used_memory: self.decode_u64()?,
+                max_memory: self.decode_u64()?,
+                keys: self.decode_u64()?,
+                hits: self.decode_u64()?,
+                misses: self.decode_u64()?,
+                evictions: self.decode_u64()?,
+                expirations: self.decode_u64()?,
+            })),
+            12 => Ok(Response::Busy),
             0xff => Ok(Response::Err),
             t => Err(Error::DecodeError(format!("Wrong type byte: {t}"))),
         }
@@ -0,0 +1,153 @@
+use std::{
+    path::Path,
+    sync::{mpsc::Receiver, Arc, Mutex},
+};
+
+use dashmap::DashMap;
+
+use crate::{
+    watch::Watchers, Error, Event, KvStore, KvsEngine, MemSnapshot, ReadSet, Result, WriteSet,
+};
+
+/// An engine keeping all pairs in memory, nothing survives dropping it.
+///
+/// Reads go straight to a `DashMap`, writes are serialized by one lock
+/// so that transactions commit atomically.
+#[derive(Clone, Default)]
+pub struct MemKvsEngine {
+    inner: Arc<MemInner>,
+}
+
+#[derive(Default)]
+struct MemInner {
+    /// Map from key to its value and the sequence number of its last write
+    pairs: DashMap<String, (String, u64)>,
+    writer: Mutex<MemWriter>,
+}
+
+#[derive(Default)]
+struct MemWriter {
+    last_seq: u64,
+    watchers: Watchers,
+}
+
+impl MemKvsEngine {
+    /// Make an empty engine
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Scan all pairs with writes blocked, so a transaction is seen whole or not at all
+    fn scan_locked(&self) -> Result<Vec<(String, String)>> {
+        let _writer = self.inner.writer.lock().unwrap();
+        self.scan("")
+    }
+}
+
+impl MemInner {
+    /// Apply a write with the writer locked
+    fn apply(&self, writer: &mut MemWriter, key: String, value: Option<String>) {
+        match value {
+            Some(value) => {
+                writer
+                    .watchers
+                    .notify(&key, || Event::Set(key.clone(), value.clone()));
+                self.pairs.insert(key, (value, writer.last_seq));
+            }
+            None => {
+                writer.watchers.notify(&key, || Event::Rm(key.clone()));
+                self.pairs.remove(&key);
+            }
+        }
+    }
+}
+
+impl KvsEngine for MemKvsEngine {
+    type Snapshot = MemSnapshot;
+
+    fn set(&self, key: String, value: String) -> Result<()> {
+        let mut writer = self.inner.writer.lock().unwrap();
+        writer.last_seq += 1;
+        self.inner.apply(&mut writer, key, Some(value));
+        Ok(())
+    }
+    fn get(&self, key: &str) -> Result<Option<String>> {
+        Ok(self.inner.pairs.get(key).map(|pair| pair.0.clone()))
+    }
+    fn remove(&self, key: String) -> Result<()> {
+        let mut writer = self.inner.writer.lock().unwrap();
+        if !self.inner.pairs.contains_key(&key) {
+            return Err(Error::RemoveNonexistKey);
+        }
+        writer.last_seq += 1;
+        self.inner.apply(&mut writer, key, None);
+        Ok(())
+    }
+    fn scan(&self, prefix: &str) -> Result<Vec<(String, String)>> {
+        let mut pairs = self
+            .inner
+            .pairs
+            .iter()
+            .filter(|pair| pair.key().starts_with(prefix))
+            .map(|pair| (pair.key().clone(), pair.value().0.clone()))
+            .collect::<Vec<_>>();
+        pairs.sort_unstable();
+        Ok(pairs)
+    }
+    fn scan_page(&self, prefix: &str, start: &str, limit: usize) -> Result<Vec<(String, String)>> {
+        let mut keys = self
+            .inner
+            .pairs
+            .iter()
+            .filter(|pair| pair.key().starts_with(prefix) && pair.key().as_str() >= start)
+            .map(|pair| pair.key().clone())
+            .collect::<Vec<_>>();
+        keys.sort_unstable();
+        keys.truncate(limit);
+        // A key removed since is left out of the page
+        Ok(keys
+            .into_iter()
+            .filter_map(|key| {
+                let value = self.inner.pairs.get(&key)?.0.clone();
+                Some((key, value))
+            })
+            .collect())
+    }
+    /// Write the pairs as a kvs store, there's nothing on disk to copy
+    fn checkpoint(&self, dest_dir: &Path) -> Result<()> {
+        if dest_dir.exists() && dest_dir.read_dir()?.next().is_some() {
+            return Err(Error::NonEmptyCheckpointDir);
+        }
+        let snapshot = self.scan_locked()?;
+        let store = KvStore::open(dest_dir)?;
+        for (key, value) in snapshot {
+            store.set(key, value)?;
+        }
+        Ok(())
+    }
+    fn snapshot(&self) -> Result<Self::Snapshot> {
+        Ok(MemSnapshot::new(self.scan_locked()?))
+    }
+    fn watch(&self, prefix: &str) -> Result<Receiver<Event>> {
+        Ok(self.inner.writer.lock().unwrap().watchers.add(prefix))
+    }
+    fn get_versioned(&self, key: &str) -> Result<Option<(String, u64)>> {
+        Ok(self.inner.pairs.get(key).map(|pair| pair.clone()))
+    }
+    fn commit(&self, reads: &ReadSet, mut writes: WriteSet) -> Result<bool> {
+        let mut writer = self.inner.writer.lock().unwrap();
+        for (key, version) in reads {
+            if self.inner.pairs.get(key).map(|pair| pair.1) != *version {
+                return Ok(false);
+            }
+        }
+        writes.retain(|key, value| value.is_some() || self.inner.pairs.contains_key(key));
+        if writes.is_empty() {
+            return Ok(true);
+        }
+        writer.last_seq += 1;
+        for (key, value) in writes {
+            self.inner.apply(&mut writer, key, value);
+        }
+        Ok(true)
+    }
+}
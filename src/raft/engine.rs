@@ -0,0 +1,132 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    path::Path,
+    sync::{mpsc::Receiver, Arc, RwLock},
+};
+
+use super::{NodeId, RaftCommand, RaftNode};
+use crate::{Error, Event, KvsClient, KvsEngine, ReadSet, Request, Response, Result, WriteSet};
+
+/// An engine whose writes are replicated by Raft.
+///
+/// Reads are served by the local engine, so they may be stale on followers.
+/// Writes on a follower are forwarded to the leader, transactions must be run on the leader.
+pub struct RaftEngine<E> {
+    node: RaftNode<E>,
+    engine: E,
+    /// Client addresses of the nodes, to forward writes to the leader
+    addrs: Arc<HashMap<NodeId, SocketAddr>>,
+    /// Held exclusively by a commit from validating its reads until its writes are applied
+    commit_lock: Arc<RwLock<()>>,
+}
+
+impl<E: KvsEngine> Clone for RaftEngine<E> {
+    fn clone(&self) -> Self {
+        Self {
+            node: self.node.clone(),
+            engine: self.engine.clone(),
+            addrs: Arc::clone(&self.addrs),
+            commit_lock: Arc::clone(&self.commit_lock),
+        }
+    }
+}
+
+impl<E: KvsEngine> RaftEngine<E> {
+    /// `addrs` maps the nodes to the addresses their servers listen on
+    pub fn new(node: RaftNode<E>, addrs: HashMap<NodeId, SocketAddr>) -> Self {
+        Self {
+            engine: node.engine(),
+            node,
+            addrs: Arc::new(addrs),
+            commit_lock: Arc::default(),
+        }
+    }
+    /// The Raft node replicating the writes
+    pub fn node(&self) -> &RaftNode<E> {
+        &self.node
+    }
+    /// Propose the write, or forward it to the leader if this is a follower
+    fn write(&self, command: RaftCommand) -> Result<bool> {
+        let result = {
+            let _guard = self.commit_lock.read().unwrap();
+            self.node.propose(command.clone())
+        };
+        match result {
+            Err(Error::NotLeader(Some(leader))) => self.forward(leader, command),
+            result => result,
+        }
+    }
+    fn forward(&self, leader: NodeId, command: RaftCommand) -> Result<bool> {
+        let addr = self.addrs.get(&leader).ok_or(Error::UnknownNode(leader))?;
+        let request = match command {
+            RaftCommand::Set(key, value) => Request::Set(key, value),
+            RaftCommand::Rm(key) => Request::Rm(key),
+            RaftCommand::Noop | RaftCommand::Txn(_) => return Err(Error::NotLeader(Some(leader))),
+        };
+        match KvsClient::connect(*addr)?.request(request)? {
+            Response::Ok => Ok(true),
+            Response::NoKey => Ok(false),
+            response => Err(Error::DecodeError(format!(
+                "Unexpected response from leader {leader}: {response:?}"
+            ))),
+        }
+    }
+}
+
+impl<E: KvsEngine> KvsEngine for RaftEngine<E> {
+    type Snapshot = E::Snapshot;
+
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.write(RaftCommand::Set(key, value))?;
+        Ok(())
+    }
+    fn get(&self, key: &str) -> Result<Option<String>> {
+        self.engine.get(key)
+    }
+    fn remove(&self, key: String) -> Result<()> {
+        match self.write(RaftCommand::Rm(key))? {
+            true => Ok(()),
+            false => Err(Error::RemoveNonexistKey),
+        }
+    }
+    fn scan(&self, prefix: &str) -> Result<Vec<(String, String)>> {
+        self.engine.scan(prefix)
+    }
+    fn scan_page(&self, prefix: &str, start: &str, limit: usize) -> Result<Vec<(String, String)>> {
+        self.engine.scan_page(prefix, start, limit)
+    }
+    fn checkpoint(&self, dest_dir: &Path) -> Result<()> {
+        self.engine.checkpoint(dest_dir)
+    }
+    fn snapshot(&self) -> Result<Self::Snapshot> {
+        self.engine.snapshot()
+    }
+    fn get_versioned(&self, key: &str) -> Result<Option<(String, u64)>> {
+        self.engine.get_versioned(key)
+    }
+    /// Validate the reads on the leader, then replicate the writes as one entry
+    fn commit(&self, reads: &ReadSet, writes: WriteSet) -> Result<bool> {
+        let _guard = self.commit_lock.write().unwrap();
+        // Apply all committed entries first, so the reads are validated against the latest state
+        self.node.propose(RaftCommand::Noop)?;
+        for (key, version) in reads {
+            if self.engine.get_versioned(key)?.map(|(_, version)| version) != *version {
+                return Ok(false);
+            }
+        }
+        if writes.is_empty() {
+            return Ok(true);
+        }
+        self.node.propose(RaftCommand::Txn(writes))
+    }
+    fn watch(&self, prefix: &str) -> Result<Receiver<Event>> {
+        self.engine.watch(prefix)
+    }
+    fn get_at(&self, key: &str, seq: u64) -> Result<Option<String>> {
+        self.engine.get_at(key, seq)
+    }
+    fn history(&self, key: &str, limit: usize) -> Result<Vec<(u64, Option<String>)>> {
+        self.engine.history(key, limit)
+    }
+}
@@ -0,0 +1,142 @@
+//! Replicate the writes of an engine between a static set of nodes with Raft.
+//!
+//! The Raft log drives the writes of the engine, and the engine's data directory
+//! is the snapshot the log is compacted into.
+
+mod engine;
+mod node;
+mod storage;
+mod transport;
+
+use std::{fs, net::SocketAddr, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Result, WriteSet};
+
+pub use engine::RaftEngine;
+pub use node::RaftNode;
+pub use transport::{LocalNetwork, LocalTransport, TcpTransport, Transport};
+
+/// Id of a node in the cluster
+pub type NodeId = u64;
+
+/// A write replicated through the Raft log
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub enum RaftCommand {
+    /// Appended by a new leader, and to make sure all committed entries are applied
+    Noop,
+    /// Set the key to the value
+    Set(String, String),
+    /// Remove the key
+    Rm(String),
+    /// Writes of a transaction validated by the leader
+    Txn(WriteSet),
+}
+
+/// An entry of the Raft log
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Entry {
+    /// Term of the leader which appended it
+    pub term: u64,
+    /// Position in the log, starting from 1
+    pub index: u64,
+    /// The write to apply
+    pub command: RaftCommand,
+}
+
+/// Messages between the nodes, see the Raft paper
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum Message {
+    /// Sent by a candidate to collect votes
+    RequestVote {
+        /// Term of the sender
+        term: u64,
+        /// Index of the candidate's last entry
+        last_log_index: u64,
+        /// Term of the candidate's last entry
+        last_log_term: u64,
+    },
+    /// Answer of `RequestVote`
+    Vote {
+        /// Term of the sender
+        term: u64,
+        /// Whether the vote is given to the candidate
+        granted: bool,
+    },
+    /// Sent by the leader to replicate entries, empty as heartbeats
+    AppendEntries {
+        /// Term of the sender
+        term: u64,
+        /// Index of the entry before `entries`
+        prev_log_index: u64,
+        /// Term of the entry before `entries`
+        prev_log_term: u64,
+        /// Entries to append, in index order
+        entries: Vec<Entry>,
+        /// Index of the last entry the leader has committed
+        leader_commit: u64,
+    },
+    /// Answer of `AppendEntries` and `InstallSnapshot`
+    AppendResult {
+        /// Term of the sender
+        term: u64,
+        /// Whether the follower's log matches the leader's
+        success: bool,
+        /// The last index known to match the leader's log on success,
+        /// otherwise where the leader may try again from
+        match_index: u64,
+    },
+    /// A chunk of the pairs of the leader's engine, for a follower missing compacted entries
+    InstallSnapshot {
+        /// Term of the sender
+        term: u64,
+        /// Index of the last entry applied to the pairs
+        last_index: u64,
+        /// Term of the last applied entry
+        last_term: u64,
+        /// How many pairs the previous chunks carry
+        offset: u64,
+        /// The pairs of the chunk
+        pairs: Vec<(String, String)>,
+        /// Whether this is the last chunk, answered by `AppendResult`
+        done: bool,
+    },
+}
+
+/// Static membership of a cluster, loaded from a JSON file
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ClusterConfig {
+    /// All nodes, including this one
+    pub nodes: Vec<NodeConfig>,
+}
+
+/// A node of the cluster
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct NodeConfig {
+    /// Unique in the cluster
+    pub id: NodeId,
+    /// Where the node serves clients
+    pub addr: SocketAddr,
+    /// Where the node receives Raft messages
+    pub raft_addr: SocketAddr,
+}
+
+impl ClusterConfig {
+    /// Read the config file
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        Ok(serde_json::from_slice(&fs::read(path)?)?)
+    }
+    /// Find the node by its id
+    pub fn node(&self, id: NodeId) -> Option<&NodeConfig> {
+        self.nodes.iter().find(|node| node.id == id)
+    }
+    /// Ids of the nodes other than `id`
+    pub fn peers(&self, id: NodeId) -> Vec<NodeId> {
+        self.nodes
+            .iter()
+            .map(|node| node.id)
+            .filter(|&peer| peer != id)
+            .collect()
+    }
+}
@@ -0,0 +1,733 @@
+use std::{
+    collections::{HashMap, HashSet},
+    ops::Range,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{Receiver, RecvTimeoutError},
+        Arc, Condvar, Mutex, Weak,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use rand::Rng;
+
+use super::{
+    storage::{HardState, Storage},
+    Entry, Message, NodeId, RaftCommand, Transport,
+};
+use crate::{Error, KvsEngine, KvsSnapshot, ReadSet, Result, IS_TEST};
+
+const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(50);
+/// Milliseconds a follower waits for the leader before starting an election, chosen randomly
+const ELECTION_TIMEOUT: Range<u64> = 300..600;
+/// How many entries an `AppendEntries` carries at most
+const MAX_APPEND_ENTRIES: usize = 100;
+/// Compact the log once this many entries are applied after the snapshot
+const COMPACT_THRESHOLD: u64 = if IS_TEST { 100 } else { 10000 };
+/// How long a proposal waits to be applied
+const PROPOSE_TIMEOUT: Duration = Duration::from_secs(5);
+/// How many pairs an `InstallSnapshot` carries at most
+const SNAPSHOT_CHUNK_PAIRS: usize = if IS_TEST { 4 } else { 1000 };
+/// How long the leader waits for a snapshot to be installed before sending another
+const SNAPSHOT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+/// A node of a Raft cluster, applying the committed entries to its engine.
+///
+/// A thread drives the node, handling messages from the inbox and timeouts,
+/// until the node is stopped or all its handles are dropped.
+pub struct RaftNode<E> {
+    shared: Arc<Shared<E>>,
+}
+
+impl<E> Clone for RaftNode<E> {
+    fn clone(&self) -> Self {
+        Self {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+}
+
+struct Shared<E> {
+    core: Mutex<Core<E>>,
+    /// Notified when entries are applied
+    applied: Condvar,
+    stopped: AtomicBool,
+}
+
+struct Core<E> {
+    id: NodeId,
+    peers: Vec<NodeId>,
+    engine: E,
+    storage: Storage,
+    transport: Arc<dyn Transport>,
+    role: Role,
+    leader: Option<NodeId>,
+    votes: HashSet<NodeId>,
+    commit_index: u64,
+    last_applied: u64,
+    /// Leader only, the next entry to send to each peer
+    next_index: HashMap<NodeId, u64>,
+    /// Leader only, the last entry known to be replicated on each peer
+    match_index: HashMap<NodeId, u64>,
+    /// Leader only, until when the snapshot sent to each peer may be installed
+    snapshot_deadlines: HashMap<NodeId, Instant>,
+    /// Snapshots to send once the core is unlocked, as scanning them may take long
+    snapshot_sends: Vec<Box<dyn FnOnce() + Send>>,
+    election_deadline: Instant,
+    heartbeat_deadline: Instant,
+    /// Entries proposed on this node, map from index to term
+    pending: HashMap<u64, u64>,
+    /// Outcomes of the applied pending entries, `None` if another entry took the index
+    outcomes: HashMap<u64, Option<bool>>,
+}
+
+impl<E: KvsEngine> RaftNode<E> {
+    /// Start the node with the Raft state kept in `dir`, the data directory of `engine`.
+    ///
+    /// `inbox` receives the messages sent to the node through the transports.
+    pub fn start(
+        id: NodeId,
+        peers: Vec<NodeId>,
+        dir: impl AsRef<Path>,
+        engine: E,
+        transport: impl Transport,
+        inbox: Receiver<(NodeId, Message)>,
+    ) -> Result<Self> {
+        let storage = Storage::open(dir)?;
+        // The engine has applied the entries up to the snapshot, later ones are applied again
+        let snapshot_index = storage.state().snapshot_index;
+        let now = Instant::now();
+        let mut core = Core {
+            id,
+            peers,
+            engine,
+            storage,
+            transport: Arc::new(transport),
+            role: Role::Follower,
+            leader: None,
+            votes: HashSet::new(),
+            commit_index: snapshot_index,
+            last_applied: snapshot_index,
+            next_index: HashMap::new(),
+            match_index: HashMap::new(),
+            snapshot_deadlines: HashMap::new(),
+            snapshot_sends: Vec::new(),
+            election_deadline: now + random_election_timeout(),
+            heartbeat_deadline: now,
+            pending: HashMap::new(),
+            outcomes: HashMap::new(),
+        };
+        // Installing a received snapshot may have been interrupted, leaving the engine partly wiped
+        core.install_snapshot()?;
+        let node = Self {
+            shared: Arc::new(Shared {
+                core: Mutex::new(core),
+                applied: Condvar::new(),
+                stopped: AtomicBool::new(false),
+            }),
+        };
+        let shared = Arc::downgrade(&node.shared);
+        thread::spawn(move || run(shared, inbox));
+        Ok(node)
+    }
+    /// Id of the node in the cluster
+    pub fn id(&self) -> NodeId {
+        self.shared.core.lock().unwrap().id
+    }
+    /// The engine the committed entries are applied to
+    pub fn engine(&self) -> E {
+        self.shared.core.lock().unwrap().engine.clone()
+    }
+    /// The leader as far as the node knows
+    pub fn leader(&self) -> Option<NodeId> {
+        self.shared.core.lock().unwrap().leader
+    }
+    /// Whether the node leads the current term
+    pub fn is_leader(&self) -> bool {
+        self.shared.core.lock().unwrap().role == Role::Leader
+    }
+    /// The current term
+    pub fn term(&self) -> u64 {
+        self.shared.core.lock().unwrap().storage.state().term
+    }
+    /// Append the command to the log if this is the leader, and wait until it's applied.
+    ///
+    /// Return `false` if the command changes nothing, such as removing a nonexistent key.
+    /// Return `Error::NotLeader` if the node isn't the leader or loses the entry,
+    /// and `Error::ProposalTimeout` if the entry isn't committed in time.
+    pub fn propose(&self, command: RaftCommand) -> Result<bool> {
+        let mut core = self.shared.core.lock().unwrap();
+        let index = core.propose(command)?;
+        let deadline = Instant::now() + PROPOSE_TIMEOUT;
+        loop {
+            if let Some(outcome) = core.outcomes.remove(&index) {
+                return outcome.ok_or(Error::NotLeader(core.leader));
+            }
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            if timeout.is_zero() {
+                core.pending.remove(&index);
+                return Err(Error::ProposalTimeout);
+            }
+            core = self.shared.applied.wait_timeout(core, timeout).unwrap().0;
+        }
+    }
+    /// Stop the driving thread, the node no longer answers others
+    pub fn stop(&self) {
+        self.shared.stopped.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Drive the node until it's stopped or dropped
+fn run<E: KvsEngine>(shared: Weak<Shared<E>>, inbox: Receiver<(NodeId, Message)>) {
+    let mut timeout = HEARTBEAT_INTERVAL;
+    loop {
+        let received = inbox.recv_timeout(timeout);
+        // Not kept alive while waiting
+        let Some(shared) = shared.upgrade() else {
+            break;
+        };
+        if shared.stopped.load(Ordering::SeqCst) {
+            break;
+        }
+        let mut core = shared.core.lock().unwrap();
+        let result = match received {
+            Ok((from, message)) => core.step(from, message),
+            Err(RecvTimeoutError::Timeout) => Ok(()),
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        if let Err(e) = result.and_then(|()| core.tick()) {
+            log::error!("Raft node {} error: {e}", core.id);
+        }
+        timeout = core
+            .next_deadline()
+            .saturating_duration_since(Instant::now())
+            .min(HEARTBEAT_INTERVAL);
+        let snapshot_sends = std::mem::take(&mut core.snapshot_sends);
+        drop(core);
+        shared.applied.notify_all();
+        for send in snapshot_sends {
+            thread::spawn(send);
+        }
+    }
+}
+
+/// Send the pairs of the snapshot to the peer, in chunks of `SNAPSHOT_CHUNK_PAIRS`
+fn send_snapshot(
+    transport: &dyn Transport,
+    peer: NodeId,
+    term: u64,
+    last_index: u64,
+    last_term: u64,
+    snapshot: impl KvsSnapshot,
+) {
+    let pairs = match snapshot.scan("") {
+        Ok(pairs) => pairs,
+        Err(e) => {
+            log::error!("Snapshot for Raft node {peer} error: {e}");
+            return;
+        }
+    };
+    // An empty snapshot is still sent as a chunk
+    let n_chunks = pairs.len().div_ceil(SNAPSHOT_CHUNK_PAIRS).max(1);
+    let mut pairs = pairs.into_iter();
+    for chunk in 0..n_chunks {
+        transport.send(
+            peer,
+            Message::InstallSnapshot {
+                term,
+                last_index,
+                last_term,
+                offset: (chunk * SNAPSHOT_CHUNK_PAIRS) as u64,
+                pairs: pairs.by_ref().take(SNAPSHOT_CHUNK_PAIRS).collect(),
+                done: chunk + 1 == n_chunks,
+            },
+        );
+    }
+}
+
+fn random_election_timeout() -> Duration {
+    Duration::from_millis(rand::thread_rng().gen_range(ELECTION_TIMEOUT))
+}
+
+impl<E: KvsEngine> Core<E> {
+    fn term(&self) -> u64 {
+        self.storage.state().term
+    }
+    fn quorum(&self) -> usize {
+        self.peers.len().div_ceil(2) + 1
+    }
+    fn next_deadline(&self) -> Instant {
+        match self.role {
+            Role::Leader => self.heartbeat_deadline,
+            _ => self.election_deadline,
+        }
+    }
+    fn send(&self, to: NodeId, message: Message) {
+        self.transport.send(to, message);
+    }
+
+    fn tick(&mut self) -> Result<()> {
+        let now = Instant::now();
+        match self.role {
+            Role::Leader if now >= self.heartbeat_deadline => {
+                for peer in self.peers.clone() {
+                    self.send_append(peer)?;
+                }
+                self.heartbeat_deadline = now + HEARTBEAT_INTERVAL;
+            }
+            Role::Follower | Role::Candidate if now >= self.election_deadline => {
+                self.start_election()?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn step(&mut self, from: NodeId, message: Message) -> Result<()> {
+        let term = match &message {
+            Message::RequestVote { term, .. }
+            | Message::Vote { term, .. }
+            | Message::AppendEntries { term, .. }
+            | Message::AppendResult { term, .. }
+            | Message::InstallSnapshot { term, .. } => *term,
+        };
+        if term > self.term() {
+            self.become_follower(term, None)?;
+        }
+        match message {
+            Message::RequestVote {
+                term,
+                last_log_index,
+                last_log_term,
+            } => {
+                let state = self.storage.state();
+                let up_to_date = (last_log_term, last_log_index)
+                    >= (self.storage.last_term(), self.storage.last_index());
+                let granted = term == state.term
+                    && state.voted_for.is_none_or(|voted| voted == from)
+                    && up_to_date;
+                if granted {
+                    self.storage.save_state(HardState {
+                        voted_for: Some(from),
+                        ..state
+                    })?;
+                    self.election_deadline = Instant::now() + random_election_timeout();
+                }
+                self.send(
+                    from,
+                    Message::Vote {
+                        term: self.term(),
+                        granted,
+                    },
+                );
+            }
+            Message::Vote { term, granted } => {
+                if self.role == Role::Candidate && term == self.term() && granted {
+                    self.votes.insert(from);
+                    if self.votes.len() >= self.quorum() {
+                        self.become_leader()?;
+                    }
+                }
+            }
+            Message::AppendEntries {
+                term,
+                prev_log_index,
+                prev_log_term,
+                entries,
+                leader_commit,
+            } => {
+                let (success, match_index) = if term < self.term() {
+                    (false, 0)
+                } else {
+                    self.follow(from);
+                    self.append_entries(prev_log_index, prev_log_term, entries, leader_commit)?
+                };
+                self.send(
+                    from,
+                    Message::AppendResult {
+                        term: self.term(),
+                        success,
+                        match_index,
+                    },
+                );
+            }
+            Message::AppendResult {
+                term,
+                success,
+                match_index,
+            } => {
+                if self.role == Role::Leader && term == self.term() {
+                    self.handle_append_result(from, success, match_index)?;
+                }
+            }
+            Message::InstallSnapshot {
+                term,
+                last_index,
+                last_term,
+                offset,
+                pairs,
+                done,
+            } => {
+                let success = if term < self.term() {
+                    Some(false)
+                } else {
+                    self.follow(from);
+                    self.receive_snapshot(last_index, last_term, offset, pairs, done)?
+                };
+                // Answered once all chunks are received
+                if let Some(success) = success {
+                    self.send(
+                        from,
+                        Message::AppendResult {
+                            term: self.term(),
+                            success,
+                            match_index: if success {
+                                last_index
+                            } else {
+                                self.commit_index
+                            },
+                        },
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn become_follower(&mut self, term: u64, leader: Option<NodeId>) -> Result<()> {
+        if term > self.term() {
+            self.storage.save_state(HardState {
+                term,
+                voted_for: None,
+                ..self.storage.state()
+            })?;
+        }
+        self.role = Role::Follower;
+        self.leader = leader;
+        self.votes.clear();
+        Ok(())
+    }
+
+    /// Accept `leader` as the leader of the current term
+    fn follow(&mut self, leader: NodeId) {
+        self.role = Role::Follower;
+        self.leader = Some(leader);
+        self.votes.clear();
+        self.election_deadline = Instant::now() + random_election_timeout();
+    }
+
+    fn start_election(&mut self) -> Result<()> {
+        let term = self.term() + 1;
+        self.storage.save_state(HardState {
+            term,
+            voted_for: Some(self.id),
+            ..self.storage.state()
+        })?;
+        self.role = Role::Candidate;
+        self.leader = None;
+        self.votes = HashSet::from([self.id]);
+        self.election_deadline = Instant::now() + random_election_timeout();
+        log::info!("Raft node {} starts election of term {term}", self.id);
+        if self.votes.len() >= self.quorum() {
+            return self.become_leader();
+        }
+        for &peer in &self.peers {
+            self.send(
+                peer,
+                Message::RequestVote {
+                    term,
+                    last_log_index: self.storage.last_index(),
+                    last_log_term: self.storage.last_term(),
+                },
+            );
+        }
+        Ok(())
+    }
+
+    fn become_leader(&mut self) -> Result<()> {
+        log::info!("Raft node {} leads term {}", self.id, self.term());
+        self.role = Role::Leader;
+        self.leader = Some(self.id);
+        let next_index = self.storage.last_index() + 1;
+        self.next_index = self.peers.iter().map(|&peer| (peer, next_index)).collect();
+        self.match_index = self.peers.iter().map(|&peer| (peer, 0)).collect();
+        self.snapshot_deadlines.clear();
+        // Entries of former terms are only committed along with one of this term
+        self.append_local(RaftCommand::Noop)?;
+        self.heartbeat_deadline = Instant::now() + HEARTBEAT_INTERVAL;
+        Ok(())
+    }
+
+    fn propose(&mut self, command: RaftCommand) -> Result<u64> {
+        if self.role != Role::Leader {
+            return Err(Error::NotLeader(self.leader));
+        }
+        let index = self.append_local(command)?;
+        self.pending.insert(index, self.term());
+        Ok(index)
+    }
+
+    /// Append an entry to the leader's log and send it to the peers
+    fn append_local(&mut self, command: RaftCommand) -> Result<u64> {
+        let entry = Entry {
+            term: self.term(),
+            index: self.storage.last_index() + 1,
+            command,
+        };
+        let index = entry.index;
+        self.storage.append(vec![entry])?;
+        for peer in self.peers.clone() {
+            self.send_append(peer)?;
+        }
+        // Commits at once without peers
+        self.advance_commit()?;
+        Ok(index)
+    }
+
+    /// Send the entries the peer misses, or a snapshot if they are compacted
+    fn send_append(&mut self, peer: NodeId) -> Result<()> {
+        let next_index = self.next_index[&peer];
+        let state = self.storage.state();
+        if next_index <= state.snapshot_index {
+            // Don't send the pairs again before the answer
+            if self
+                .snapshot_deadlines
+                .get(&peer)
+                .is_some_and(|&deadline| Instant::now() < deadline)
+            {
+                return Ok(());
+            }
+            // The engine is at the last applied entry, which is at or after the snapshot
+            let last_index = self.last_applied;
+            let last_term = self.storage.term_at(last_index).unwrap();
+            let snapshot = self.engine.snapshot()?;
+            let transport = Arc::clone(&self.transport);
+            let term = state.term;
+            self.snapshot_sends.push(Box::new(move || {
+                send_snapshot(&*transport, peer, term, last_index, last_term, snapshot)
+            }));
+            self.snapshot_deadlines
+                .insert(peer, Instant::now() + SNAPSHOT_TIMEOUT);
+            self.next_index.insert(peer, last_index + 1);
+            return Ok(());
+        }
+        let prev_log_index = next_index - 1;
+        let message = Message::AppendEntries {
+            term: state.term,
+            prev_log_index,
+            prev_log_term: self.storage.term_at(prev_log_index).unwrap(),
+            entries: self.storage.entries_from(next_index, MAX_APPEND_ENTRIES),
+            leader_commit: self.commit_index,
+        };
+        self.send(peer, message);
+        Ok(())
+    }
+
+    fn handle_append_result(
+        &mut self,
+        peer: NodeId,
+        success: bool,
+        match_index: u64,
+    ) -> Result<()> {
+        if success {
+            self.snapshot_deadlines.remove(&peer);
+            let matched = self.match_index[&peer].max(match_index);
+            self.match_index.insert(peer, matched);
+            self.next_index.insert(peer, matched + 1);
+            self.advance_commit()?;
+            if matched < self.storage.last_index() {
+                self.send_append(peer)?;
+            }
+        } else {
+            self.next_index.insert(peer, match_index + 1);
+            self.send_append(peer)?;
+        }
+        Ok(())
+    }
+
+    /// Commit the latest entry of this term replicated on a quorum
+    fn advance_commit(&mut self) -> Result<()> {
+        let term = self.term();
+        for index in (self.commit_index + 1..=self.storage.last_index()).rev() {
+            if self.storage.term_at(index) != Some(term) {
+                break;
+            }
+            let n_replicated = 1 + self
+                .match_index
+                .values()
+                .filter(|&&matched| matched >= index)
+                .count();
+            if n_replicated >= self.quorum() {
+                self.commit_index = index;
+                return self.apply();
+            }
+        }
+        Ok(())
+    }
+
+    /// Return whether the entries match the leader's log, and the last index known to match
+    fn append_entries(
+        &mut self,
+        prev_log_index: u64,
+        prev_log_term: u64,
+        mut entries: Vec<Entry>,
+        leader_commit: u64,
+    ) -> Result<(bool, u64)> {
+        let snapshot_index = self.storage.state().snapshot_index;
+        if prev_log_index > self.storage.last_index() {
+            return Ok((false, self.storage.last_index()));
+        }
+        let prev_log_index = if prev_log_index < snapshot_index {
+            // Entries up to the snapshot are committed, so they match
+            entries.retain(|entry| entry.index > snapshot_index);
+            snapshot_index
+        } else {
+            match self.storage.term_at(prev_log_index) {
+                Some(term) if term == prev_log_term => prev_log_index,
+                // Skip the whole conflicting term
+                Some(term) => {
+                    let first = self.storage.first_index_of(term).unwrap_or(prev_log_index);
+                    return Ok((false, (first - 1).max(self.commit_index)));
+                }
+                None => unreachable!(),
+            }
+        };
+        let last_new_index = prev_log_index + entries.len() as u64;
+
+        let mut new_entries = Vec::new();
+        for entry in entries {
+            match self.storage.term_at(entry.index) {
+                Some(term) if term == entry.term => {}
+                Some(_) => {
+                    self.truncate_from(entry.index)?;
+                    new_entries.push(entry);
+                }
+                None => new_entries.push(entry),
+            }
+        }
+        self.storage.append(new_entries)?;
+
+        if leader_commit > self.commit_index {
+            self.commit_index = leader_commit.min(last_new_index);
+            self.apply()?;
+        }
+        Ok((true, last_new_index))
+    }
+
+    fn truncate_from(&mut self, index: u64) -> Result<()> {
+        self.storage.truncate_from(index)?;
+        self.drop_pending(|pending| pending >= index);
+        Ok(())
+    }
+
+    /// Give up the pending entries whose index is taken by others
+    fn drop_pending(&mut self, taken: impl Fn(u64) -> bool) {
+        let dropped = self
+            .pending
+            .keys()
+            .copied()
+            .filter(|&index| taken(index))
+            .collect::<Vec<_>>();
+        for index in dropped {
+            self.pending.remove(&index);
+            self.outcomes.insert(index, None);
+        }
+    }
+
+    /// Receive a chunk of the leader's snapshot, and install the snapshot after its last chunk.
+    ///
+    /// Return whether the snapshot is installed once all chunks are sent, `None` before.
+    fn receive_snapshot(
+        &mut self,
+        last_index: u64,
+        last_term: u64,
+        offset: u64,
+        pairs: Vec<(String, String)>,
+        done: bool,
+    ) -> Result<Option<bool>> {
+        if last_index <= self.commit_index {
+            // Already applied, only the answer is missing
+            return Ok(done.then_some(true));
+        }
+        if offset == 0 {
+            self.storage.begin_snapshot(last_index, last_term)?;
+        }
+        // After a lost chunk, the leader sends the snapshot again
+        let received = self
+            .storage
+            .receive_snapshot_pairs(last_index, offset, &pairs)?;
+        if !done {
+            return Ok(None);
+        }
+        if !received {
+            return Ok(Some(false));
+        }
+        self.storage.finish_snapshot()?;
+        self.install_snapshot()?;
+        Ok(Some(true))
+    }
+
+    /// Replace the engine's pairs with the received snapshot's, if there is one.
+    ///
+    /// The snapshot is kept until the log starts after it, so it's installed again on start
+    /// if a crash interrupts installing it.
+    fn install_snapshot(&mut self) -> Result<()> {
+        let Some(snapshot) = self.storage.received_snapshot()? else {
+            return Ok(());
+        };
+        let last_index = snapshot.index;
+        log::info!("Raft node {} installs snapshot at {last_index}", self.id);
+        for (key, _) in self.engine.scan("")? {
+            self.engine.remove(key)?;
+        }
+        for (key, value) in snapshot.pairs {
+            self.engine.set(key, value)?;
+        }
+        self.storage.install_snapshot(last_index, snapshot.term)?;
+        self.commit_index = last_index;
+        self.last_applied = last_index;
+        self.drop_pending(|pending| pending <= last_index);
+        Ok(())
+    }
+
+    /// Apply the committed entries to the engine, then compact the log if it's long
+    fn apply(&mut self) -> Result<()> {
+        while self.last_applied < self.commit_index {
+            let index = self.last_applied + 1;
+            let entry = self.storage.entry(index).unwrap().clone();
+            let changed = match entry.command {
+                RaftCommand::Noop => true,
+                RaftCommand::Set(key, value) => {
+                    self.engine.set(key, value)?;
+                    true
+                }
+                RaftCommand::Rm(key) => match self.engine.remove(key) {
+                    Ok(()) => true,
+                    Err(Error::RemoveNonexistKey) => false,
+                    Err(e) => return Err(e),
+                },
+                RaftCommand::Txn(writes) => self.engine.commit(&ReadSet::new(), writes)?,
+            };
+            self.last_applied = index;
+            if let Some(term) = self.pending.remove(&index) {
+                self.outcomes
+                    .insert(index, (term == entry.term).then_some(changed));
+            }
+        }
+        let state = self.storage.state();
+        if self.last_applied - state.snapshot_index >= COMPACT_THRESHOLD {
+            let term = self.storage.term_at(self.last_applied).unwrap();
+            self.storage.compact(self.last_applied, term)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,250 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufRead, Write},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use super::{Entry, NodeId};
+use crate::Result;
+
+/// Holds the `HardState`, rewritten on each change
+const STATE_FILE: &str = "RAFT_STATE";
+/// Holds the entries after the snapshot, one JSON per line
+const LOG_FILE: &str = "RAFT_LOG";
+/// Holds a snapshot received from the leader until it's installed,
+/// its index and term on the first line, then one JSON pair per line
+const SNAPSHOT_FILE: &str = "RAFT_SNAPSHOT";
+
+/// State which must survive restarts before answering other nodes
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize)]
+pub(super) struct HardState {
+    pub(super) term: u64,
+    pub(super) voted_for: Option<NodeId>,
+    /// The engine has applied all entries up to it, they are dropped from the log
+    pub(super) snapshot_index: u64,
+    pub(super) snapshot_term: u64,
+}
+
+/// The durable part of a Raft node, kept in the engine's data directory
+pub(super) struct Storage {
+    dir: PathBuf,
+    state: HardState,
+    /// Entries after the snapshot, in index order
+    entries: Vec<Entry>,
+    log: File,
+    /// Snapshot being received from the leader, written to a temporary file until complete
+    receiving: Option<ReceivingSnapshot>,
+}
+
+/// A snapshot received from the leader
+pub(super) struct Snapshot {
+    pub(super) index: u64,
+    pub(super) term: u64,
+    pub(super) pairs: Vec<(String, String)>,
+}
+
+struct ReceivingSnapshot {
+    index: u64,
+    n_pairs: u64,
+    file: io::BufWriter<File>,
+}
+
+impl Storage {
+    pub(super) fn open(dir: impl AsRef<Path>) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let state: HardState = match fs::read(dir.join(STATE_FILE)) {
+            Ok(state) => serde_json::from_slice(&state)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HardState::default(),
+            Err(e) => return Err(e.into()),
+        };
+        let mut entries = Vec::new();
+        match File::open(dir.join(LOG_FILE)) {
+            Ok(log) => {
+                for line in io::BufReader::new(log).lines() {
+                    let entry: Entry = serde_json::from_str(&line?)?;
+                    // Left by a crash between saving the snapshot and rewriting the log
+                    if entry.index > state.snapshot_index {
+                        entries.push(entry);
+                    }
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        let log = File::options()
+            .create(true)
+            .append(true)
+            .open(dir.join(LOG_FILE))?;
+        Ok(Self {
+            dir,
+            state,
+            entries,
+            log,
+            receiving: None,
+        })
+    }
+    pub(super) fn state(&self) -> HardState {
+        self.state
+    }
+    pub(super) fn save_state(&mut self, state: HardState) -> Result<()> {
+        let tmp_path = self.dir.join(format!("{STATE_FILE}.tmp"));
+        fs::write(&tmp_path, serde_json::to_vec(&state)?)?;
+        fs::rename(tmp_path, self.dir.join(STATE_FILE))?;
+        self.state = state;
+        Ok(())
+    }
+    pub(super) fn last_index(&self) -> u64 {
+        self.entries
+            .last()
+            .map_or(self.state.snapshot_index, |entry| entry.index)
+    }
+    pub(super) fn last_term(&self) -> u64 {
+        self.entries
+            .last()
+            .map_or(self.state.snapshot_term, |entry| entry.term)
+    }
+    /// Term of the entry at `index`, `None` if it's not in the log or compacted
+    pub(super) fn term_at(&self, index: u64) -> Option<u64> {
+        if index == self.state.snapshot_index {
+            return Some(self.state.snapshot_term);
+        }
+        self.entry(index).map(|entry| entry.term)
+    }
+    pub(super) fn entry(&self, index: u64) -> Option<&Entry> {
+        let offset = index.checked_sub(self.state.snapshot_index + 1)?;
+        self.entries.get(offset as usize)
+    }
+    /// At most `limit` entries from `index` on, which must be after the snapshot
+    pub(super) fn entries_from(&self, index: u64, limit: usize) -> Vec<Entry> {
+        let offset = (index - self.state.snapshot_index - 1) as usize;
+        self.entries
+            .iter()
+            .skip(offset)
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+    /// Index of the first entry with the term, after the snapshot
+    pub(super) fn first_index_of(&self, term: u64) -> Option<u64> {
+        self.entries
+            .iter()
+            .find(|entry| entry.term == term)
+            .map(|entry| entry.index)
+    }
+    pub(super) fn append(&mut self, entries: Vec<Entry>) -> Result<()> {
+        let mut lines = Vec::new();
+        for entry in &entries {
+            serde_json::to_writer(&mut lines, entry)?;
+            lines.push(b'\n');
+        }
+        self.log.write_all(&lines)?;
+        self.entries.extend(entries);
+        Ok(())
+    }
+    /// Drop the entries from `index` on, which conflict with the leader's
+    pub(super) fn truncate_from(&mut self, index: u64) -> Result<()> {
+        self.entries.retain(|entry| entry.index < index);
+        self.rewrite_log()
+    }
+    /// Drop the entries up to `index`, which the engine has applied
+    pub(super) fn compact(&mut self, index: u64, term: u64) -> Result<()> {
+        self.save_state(HardState {
+            snapshot_index: index,
+            snapshot_term: term,
+            ..self.state
+        })?;
+        self.entries.retain(|entry| entry.index > index);
+        self.rewrite_log()
+    }
+    /// Start receiving the leader's snapshot at `index`, dropping one partly received
+    pub(super) fn begin_snapshot(&mut self, index: u64, term: u64) -> Result<()> {
+        let tmp_path = self.dir.join(format!("{SNAPSHOT_FILE}.tmp"));
+        let mut file = io::BufWriter::new(File::create(tmp_path)?);
+        serde_json::to_writer(&mut file, &(index, term))?;
+        file.write_all(b"\n")?;
+        self.receiving = Some(ReceivingSnapshot {
+            index,
+            n_pairs: 0,
+            file,
+        });
+        Ok(())
+    }
+    /// Add the pairs following the first `offset` ones to the snapshot being received.
+    ///
+    /// Return `false` and drop the snapshot if pairs before them are missing.
+    pub(super) fn receive_snapshot_pairs(
+        &mut self,
+        index: u64,
+        offset: u64,
+        pairs: &[(String, String)],
+    ) -> Result<bool> {
+        let Some(receiving) = self
+            .receiving
+            .as_mut()
+            .filter(|receiving| (receiving.index, receiving.n_pairs) == (index, offset))
+        else {
+            self.receiving = None;
+            return Ok(false);
+        };
+        for pair in pairs {
+            serde_json::to_writer(&mut receiving.file, pair)?;
+            receiving.file.write_all(b"\n")?;
+        }
+        receiving.n_pairs += pairs.len() as u64;
+        Ok(true)
+    }
+    /// Keep the received snapshot until it's installed, so a crash meanwhile installs it again
+    pub(super) fn finish_snapshot(&mut self) -> Result<()> {
+        if let Some(mut receiving) = self.receiving.take() {
+            receiving.file.flush()?;
+            drop(receiving);
+            let tmp_path = self.dir.join(format!("{SNAPSHOT_FILE}.tmp"));
+            fs::rename(tmp_path, self.dir.join(SNAPSHOT_FILE))?;
+        }
+        Ok(())
+    }
+    /// The received snapshot, `None` if there is none to install
+    pub(super) fn received_snapshot(&self) -> Result<Option<Snapshot>> {
+        let file = match File::open(self.dir.join(SNAPSHOT_FILE)) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let mut lines = io::BufReader::new(file).lines();
+        let Some(header) = lines.next() else {
+            return Ok(None);
+        };
+        let (index, term) = serde_json::from_str(&header?)?;
+        let pairs = lines
+            .map(|line| Ok(serde_json::from_str(&line?)?))
+            .collect::<Result<_>>()?;
+        Ok(Some(Snapshot { index, term, pairs }))
+    }
+    /// Start the log after the received snapshot, once the engine holds its pairs.
+    ///
+    /// Entries after it are kept if the log agrees with the snapshot.
+    pub(super) fn install_snapshot(&mut self, index: u64, term: u64) -> Result<()> {
+        if self.term_at(index) != Some(term) {
+            self.entries.clear();
+        }
+        self.compact(index, term)?;
+        fs::remove_file(self.dir.join(SNAPSHOT_FILE))?;
+        Ok(())
+    }
+    fn rewrite_log(&mut self) -> Result<()> {
+        let tmp_path = self.dir.join(format!("{LOG_FILE}.tmp"));
+        let mut tmp = io::BufWriter::new(File::create(&tmp_path)?);
+        for entry in &self.entries {
+            serde_json::to_writer(&mut tmp, entry)?;
+            tmp.write_all(b"\n")?;
+        }
+        tmp.flush()?;
+        drop(tmp);
+        fs::rename(tmp_path, self.dir.join(LOG_FILE))?;
+        self.log = File::options().append(true).open(self.dir.join(LOG_FILE))?;
+        Ok(())
+    }
+}
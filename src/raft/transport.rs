@@ -0,0 +1,197 @@
+use std::{
+    collections::HashMap,
+    io::{self, Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use super::{ClusterConfig, Message, NodeId};
+use crate::{Error, Result};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Sends messages to other nodes.
+///
+/// Messages to a node arrive at the inbox given to its `RaftNode::start`, tagged with the sender.
+pub trait Transport: Send + Sync + 'static {
+    /// Send without waiting, the message may be lost
+    fn send(&self, to: NodeId, message: Message);
+}
+
+/// Connects nodes in one process, partitions can be simulated by cutting them apart
+#[derive(Clone, Default)]
+pub struct LocalNetwork {
+    inner: Arc<Mutex<LocalInner>>,
+}
+
+#[derive(Default)]
+struct LocalInner {
+    inboxes: HashMap<NodeId, Sender<(NodeId, Message)>>,
+    /// Map from node to its group, only nodes in the same group reach each other.
+    ///
+    /// Empty if the network isn't partitioned.
+    groups: HashMap<NodeId, usize>,
+}
+
+impl LocalInner {
+    fn reachable(&self, from: NodeId, to: NodeId) -> bool {
+        self.groups.is_empty()
+            || matches!(
+                (self.groups.get(&from), self.groups.get(&to)),
+                (Some(a), Some(b)) if a == b
+            )
+    }
+}
+
+impl LocalNetwork {
+    /// Make a network without nodes
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Add a node, or replace the inbox of a restarted one
+    pub fn join(&self, id: NodeId) -> (LocalTransport, Receiver<(NodeId, Message)>) {
+        let (sender, inbox) = mpsc::channel();
+        self.inner.lock().unwrap().inboxes.insert(id, sender);
+        let transport = LocalTransport {
+            id,
+            network: self.clone(),
+        };
+        (transport, inbox)
+    }
+    /// Only nodes in the same group can reach each other, nodes in no group are isolated
+    pub fn partition(&self, groups: &[&[NodeId]]) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.groups = groups
+            .iter()
+            .enumerate()
+            .flat_map(|(group, ids)| ids.iter().map(move |&id| (id, group)))
+            .collect();
+    }
+    /// Cut the node off from all others
+    pub fn isolate(&self, id: NodeId) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.groups = inner
+            .inboxes
+            .keys()
+            .map(|&node| (node, usize::from(node == id)))
+            .collect();
+    }
+    /// Reconnect all nodes
+    pub fn heal(&self) {
+        self.inner.lock().unwrap().groups.clear();
+    }
+}
+
+/// The transport of a node in a `LocalNetwork`
+pub struct LocalTransport {
+    id: NodeId,
+    network: LocalNetwork,
+}
+
+impl Transport for LocalTransport {
+    fn send(&self, to: NodeId, message: Message) {
+        let inner = self.network.inner.lock().unwrap();
+        if !inner.reachable(self.id, to) {
+            return;
+        }
+        if let Some(inbox) = inner.inboxes.get(&to) {
+            let _ = inbox.send((self.id, message));
+        }
+    }
+}
+
+/// Sends messages as length-prefixed JSON over TCP, through a thread for each peer
+pub struct TcpTransport {
+    senders: HashMap<NodeId, Sender<Message>>,
+}
+
+impl TcpTransport {
+    /// Listen on the Raft address of the node, and connect to peers when sending to them
+    pub fn start(
+        id: NodeId,
+        config: &ClusterConfig,
+    ) -> Result<(Self, Receiver<(NodeId, Message)>)> {
+        let node = config.node(id).ok_or(Error::UnknownNode(id))?;
+        let listener = TcpListener::bind(node.raft_addr)?;
+        let (inbox_sender, inbox) = mpsc::channel();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(stream) = stream else {
+                    continue;
+                };
+                let inbox_sender = inbox_sender.clone();
+                thread::spawn(move || receive_messages(stream, inbox_sender));
+            }
+        });
+
+        let mut senders = HashMap::new();
+        for peer in config.peers(id) {
+            let addr = config.node(peer).unwrap().raft_addr;
+            let (sender, receiver) = mpsc::channel();
+            senders.insert(peer, sender);
+            thread::spawn(move || {
+                let mut conn = None;
+                for message in receiver.iter() {
+                    if conn.is_none() {
+                        conn = TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT).ok();
+                    }
+                    let Some(stream) = conn.as_mut() else {
+                        // The peer is down, don't wait for it with the queued messages
+                        receiver.try_iter().for_each(drop);
+                        continue;
+                    };
+                    if send_message(stream, id, &message).is_err() {
+                        conn = None;
+                    }
+                }
+            });
+        }
+        Ok((Self { senders }, inbox))
+    }
+}
+
+impl Transport for TcpTransport {
+    fn send(&self, to: NodeId, message: Message) {
+        if let Some(sender) = self.senders.get(&to) {
+            let _ = sender.send(message);
+        }
+    }
+}
+
+fn send_message(stream: &mut TcpStream, from: NodeId, message: &Message) -> Result<()> {
+    let bytes = serde_json::to_vec(&(from, message))?;
+    stream.write_all(&(bytes.len() as u32).to_be_bytes())?;
+    stream.write_all(&bytes)?;
+    Ok(())
+}
+
+fn receive_messages(stream: TcpStream, inbox: Sender<(NodeId, Message)>) {
+    let mut reader = io::BufReader::new(stream);
+    let mut buf = Vec::new();
+    loop {
+        let mut len = [0; 4];
+        if reader.read_exact(&mut len).is_err() {
+            return;
+        }
+        buf.resize(u32::from_be_bytes(len) as usize, 0);
+        if reader.read_exact(&mut buf).is_err() {
+            return;
+        }
+        match serde_json::from_slice(&buf) {
+            Ok(received) => {
+                if inbox.send(received).is_err() {
+                    return;
+                }
+            }
+            Err(e) => {
+                log::error!("Bad Raft message: {e}");
+                return;
+            }
+        }
+    }
+}
@@ -0,0 +1,154 @@
+use std::{
+    fs,
+    io::{self, Write},
+    net::{SocketAddr, TcpStream},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use crate::{Decoder, Encoder, Error, KvStore, LogPosition, Request, Response, Result};
+
+/// Holds the position in the primary's log the replica has applied up to
+const POSITION_FILE: &str = "REPLICA_POSITION";
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+/// The primary sends a heartbeat each second, so a silent connection is broken
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+/// Save the position after this many records, besides on each heartbeat
+const SAVE_INTERVAL: u32 = 1000;
+
+/// Follows a primary kvs-server by applying its log to a local `KvStore`
+pub struct Replica {
+    store: KvStore,
+    primary: SocketAddr,
+    position_path: PathBuf,
+    /// Position of the next record to apply
+    position: LogPosition,
+    status: Arc<ReplicaStatus>,
+}
+
+/// Replication state of a `Replica`, shared with its observers
+#[derive(Debug, Default)]
+pub struct ReplicaStatus {
+    lag: AtomicU64,
+    connected: AtomicBool,
+}
+
+impl ReplicaStatus {
+    /// How many writes the replica is behind the primary, as of the last heartbeat
+    pub fn lag(&self) -> u64 {
+        self.lag.load(Ordering::SeqCst)
+    }
+    /// Whether the replica is streaming from the primary
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::SeqCst)
+    }
+}
+
+impl Replica {
+    /// Open the store in the directory, resuming from the position saved there
+    pub fn open(path: impl AsRef<Path>, primary: SocketAddr) -> Result<Self> {
+        let store = KvStore::open(&path)?;
+        let position_path = path.as_ref().join(POSITION_FILE);
+        let position = match fs::read(&position_path) {
+            Ok(position) => serde_json::from_slice(&position)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => LogPosition::default(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self {
+            store,
+            primary,
+            position_path,
+            position,
+            status: Arc::default(),
+        })
+    }
+    /// The replicated store, writes to it would be lost on resync
+    pub fn store(&self) -> KvStore {
+        self.store.clone()
+    }
+    /// Shared replication state, such as the lag
+    pub fn status(&self) -> Arc<ReplicaStatus> {
+        Arc::clone(&self.status)
+    }
+    /// Follow the primary forever, reconnecting after errors
+    pub fn run(&mut self) {
+        loop {
+            if let Err(e) = self.follow() {
+                log::error!("Replication from {} broken: {e}", self.primary);
+            }
+            self.status.connected.store(false, Ordering::SeqCst);
+            thread::sleep(RECONNECT_INTERVAL);
+        }
+    }
+    /// Apply the records streamed from the primary until the connection breaks
+    fn follow(&mut self) -> Result<()> {
+        let mut conn = TcpStream::connect_timeout(&self.primary, CONNECT_TIMEOUT)?;
+        conn.set_read_timeout(Some(READ_TIMEOUT))?;
+        conn.write_all(Encoder::new().encode_request(Request::Replicate(self.position)))?;
+        let mut decoder = Decoder::new(&mut conn);
+        match decoder.decode_response()? {
+            Response::Ok => log::info!("Replicate {} from {:?}", self.primary, self.position),
+            Response::Resync => {
+                log::warn!(
+                    "{:?} is compacted on {}, replicate all again",
+                    self.position,
+                    self.primary
+                );
+                self.store.reset()?;
+            }
+            Response::Busy => {
+                log::warn!("{} serves too many streams, retry later", self.primary);
+                return Ok(());
+            }
+            response => {
+                return Err(Error::DecodeError(format!(
+                    "Unexpected response: {response:?}"
+                )))
+            }
+        }
+        self.status.connected.store(true, Ordering::SeqCst);
+
+        let mut n_unsaved = 0;
+        loop {
+            match decoder.decode_response()? {
+                Response::Record(position, record) => {
+                    self.store
+                        .apply_replicated(serde_json::from_str(&record)?)?;
+                    self.position = LogPosition {
+                        file_offset: position.file_offset + record.len() as u32,
+                        ..position
+                    };
+                    n_unsaved += 1;
+                    if n_unsaved >= SAVE_INTERVAL {
+                        self.save_position()?;
+                        n_unsaved = 0;
+                    }
+                }
+                Response::Heartbeat(last_seq) => {
+                    self.save_position()?;
+                    n_unsaved = 0;
+                    let lag = last_seq.saturating_sub(self.store.last_seq());
+                    if self.status.lag.swap(lag, Ordering::SeqCst) != lag {
+                        log::info!("Replication lag: {lag} writes");
+                    }
+                }
+                response => {
+                    return Err(Error::DecodeError(format!(
+                        "Unexpected response: {response:?}"
+                    )))
+                }
+            }
+        }
+    }
+    /// Records after the saved position may be applied again after a restart, which is harmless
+    fn save_position(&self) -> Result<()> {
+        fs::write(&self.position_path, serde_json::to_vec(&self.position)?)?;
+        Ok(())
+    }
+}
@@ -1,21 +1,104 @@
 use std::{
-    io::Write,
+    io::{self, Write},
     net::{SocketAddr, TcpListener, TcpStream},
+    path::{Component, Path, PathBuf},
     sync::{
         atomic::{AtomicBool, Ordering},
-        Arc,
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+        Arc, Mutex,
     },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
 };
 
 use crate::{
-    thread_pool::ThreadPool, Decoder, Encoder, Error, KvsEngine, Request, Response, Result,
+    thread_pool::{Priority, ThreadPool},
+    Decoder, Encoder, Error, Event, KvsEngine, LogTail, Request, Response, Result, Transaction,
+    BACKUP_REQUEST_TYPE, CACHE_STATS_REQUEST_TYPE,
 };
 
+/// How long a watch stream waits for events before checking the client is still there
+const WATCH_POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// How long a replication stream waits before reading the log again
+const REPLICATION_POLL_INTERVAL: Duration = Duration::from_millis(100);
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+/// How long a connection may send nothing before it's closed, so it doesn't hold a worker
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+/// How often the idle connections are checked for a new request
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+/// How long the poller waits for a connection when none is idle, before checking for shutdown
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// Most watch and replication streams served at once, each has its own thread
+const MAX_STREAMS: usize = 64;
+
 /// A server, listening client's command
 pub struct KvsServer<E, P> {
     engine: E,
     pool: P,
     shutdown: Arc<AtomicBool>,
+    /// Refuse writes, such as on a replica
+    read_only: bool,
+    idle_timeout: Duration,
+    /// Backups are written under it, `None` refuses them
+    backup_dir: Option<PathBuf>,
+    max_streams: usize,
+}
+
+/// A connection waiting for its next request, in non-blocking mode so it can be polled
+struct Parked {
+    stream: TcpStream,
+    /// When it was accepted or last served
+    since: Instant,
+}
+
+impl Parked {
+    fn new(stream: TcpStream) -> io::Result<Self> {
+        stream.set_nonblocking(true)?;
+        Ok(Self {
+            stream,
+            since: Instant::now(),
+        })
+    }
+}
+
+/// Threads of the watch and replication streams, which outlive the jobs of their connections
+struct Streams {
+    shutdown: Arc<AtomicBool>,
+    limit: usize,
+    handles: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl Streams {
+    fn new(shutdown: Arc<AtomicBool>, limit: usize) -> Self {
+        Self {
+            shutdown,
+            limit,
+            handles: Mutex::new(Vec::new()),
+        }
+    }
+    /// Run the stream on its own thread until it ends or the server shuts down.
+    ///
+    /// Returns `false` without running it if `limit` streams are running,
+    /// or the server is shut down.
+    fn spawn(&self, stream: impl FnOnce(&AtomicBool) + Send + 'static) -> bool {
+        let mut handles = self.handles.lock().unwrap();
+        handles.retain(|handle| !handle.is_finished());
+        if handles.len() >= self.limit || self.shutdown.load(Ordering::SeqCst) {
+            return false;
+        }
+        let shutdown = Arc::clone(&self.shutdown);
+        handles.push(thread::spawn(move || stream(&shutdown)));
+        true
+    }
+    /// Wait for the streams to stop, once the server is shut down
+    fn join(&self) {
+        let handles = std::mem::take(&mut *self.handles.lock().unwrap());
+        for handle in handles {
+            if handle.join().is_err() {
+                log::error!("Stream thread panicked");
+            }
+        }
+    }
 }
 /// shutdown the server listening on `addr`, using signal `shutdown`
 pub fn shutdown(addr: SocketAddr, shutdown: Arc<AtomicBool>) {
@@ -23,76 +106,473 @@ pub fn shutdown(addr: SocketAddr, shutdown: Arc<AtomicBool>) {
     TcpStream::connect(addr).unwrap();
 }
 
+/// The type byte of the next request, `None` until it arrives.
+///
+/// A connection closed by the client is an `UnexpectedEof` error.
+fn peek_request_type(stream: &TcpStream) -> io::Result<Option<u8>> {
+    let mut type_ = [0];
+    match stream.peek(&mut type_) {
+        Ok(0) => Err(io::ErrorKind::UnexpectedEof.into()),
+        Ok(_) => Ok(Some(type_[0])),
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Accept connections until shut down, and park them until they send a request
+fn accept_connections(listener: TcpListener, shutdown: Arc<AtomicBool>, parked: Sender<Parked>) {
+    for stream in listener.incoming() {
+        if shutdown.load(Ordering::SeqCst) {
+            break;
+        }
+        // A failing connection mustn't stop the server from accepting others
+        match stream.and_then(Parked::new) {
+            Ok(connection) => {
+                if parked.send(connection).is_err() {
+                    break;
+                }
+            }
+            Err(e) => log::error!("Connection error: {e}"),
+        }
+    }
+}
+
+/// Resolve the directory a client asked to back up into under the root.
+///
+/// Only relative paths staying under the root are accepted,
+/// so clients can't write anywhere else on the server.
+fn resolve_backup_dir(root: &Path, dest_dir: &str) -> Option<PathBuf> {
+    let dest_dir = Path::new(dest_dir);
+    let mut components = dest_dir.components().peekable();
+    components.peek()?;
+    components
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+        .then(|| root.join(dest_dir))
+}
+
+fn internal_error(e: Error) -> Response {
+    log::error!("Internal error: {e}");
+    Response::Err
+}
+
+/// Send the events to the client until it closes the connection or the server shuts down
+fn stream_events(
+    receiver: Receiver<Event>,
+    mut stream: TcpStream,
+    shutdown: &AtomicBool,
+) -> Result<()> {
+    let mut encoder = Encoder::new();
+    stream.write_all(encoder.encode_response(Response::Ok))?;
+    while !shutdown.load(Ordering::SeqCst) {
+        match receiver.recv_timeout(WATCH_POLL_INTERVAL) {
+            Ok(event) => stream.write_all(encoder.encode_response(Response::Event(event)))?,
+            // Writes would tell, but nothing is written meanwhile
+            Err(RecvTimeoutError::Timeout) => {
+                if peer_closed(&stream)? {
+                    return Ok(());
+                }
+            }
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+    }
+    Ok(())
+}
+
+/// Send the log records to a replica until it closes the connection or the server shuts down.
+///
+/// The heartbeats carry the sequence number of the last write, for the replica to measure its lag.
+fn stream_log(mut tail: LogTail, mut stream: TcpStream, shutdown: &AtomicBool) -> Result<()> {
+    let mut encoder = Encoder::new();
+    let first = if tail.is_resync() {
+        Response::Resync
+    } else {
+        Response::Ok
+    };
+    stream.write_all(encoder.encode_response(first))?;
+    let mut last_heartbeat = Instant::now();
+    while !shutdown.load(Ordering::SeqCst) {
+        if last_heartbeat.elapsed() >= HEARTBEAT_INTERVAL {
+            stream.write_all(encoder.encode_response(Response::Heartbeat(tail.last_seq())))?;
+            last_heartbeat = Instant::now();
+        }
+        match tail.next_record()? {
+            Some((position, record)) => {
+                stream.write_all(encoder.encode_response(Response::Record(position, record)))?
+            }
+            None => thread::sleep(REPLICATION_POLL_INTERVAL),
+        }
+    }
+    Ok(())
+}
+
+/// Whether the peer has closed the connection, without blocking
+fn peer_closed(stream: &TcpStream) -> Result<bool> {
+    stream.set_nonblocking(true)?;
+    let closed = match stream.peek(&mut [0]) {
+        Ok(n_read) => n_read == 0,
+        Err(e) => e.kind() != io::ErrorKind::WouldBlock,
+    };
+    stream.set_nonblocking(false)?;
+    Ok(closed)
+}
+
 impl<E: KvsEngine, P: ThreadPool> KvsServer<E, P> {
     /// create a server
     pub fn new(engine: E, shutdown: Arc<AtomicBool>, n_threads: usize) -> Self {
         let pool = P::new(n_threads as u32).unwrap();
+        Self::with_pool(engine, shutdown, pool)
+    }
+    /// create a server running connections on the pool
+    pub fn with_pool(engine: E, shutdown: Arc<AtomicBool>, pool: P) -> Self {
         Self {
             engine,
             pool,
             shutdown,
+            read_only: false,
+            idle_timeout: IDLE_TIMEOUT,
+            backup_dir: None,
+            max_streams: MAX_STREAMS,
         }
     }
-    fn handle_stream(engine: E, stream: TcpStream) -> Result<()> {
+    /// Refuse writes, reads and replication are still served
+    pub fn read_only(mut self) -> Self {
+        self.read_only = true;
+        self
+    }
+    /// Close connections that send no request for `timeout`, 60 seconds by default
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+    /// Accept backups into relative directories under `dir`, they are refused by default
+    pub fn backup_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.backup_dir = Some(dir.into());
+        self
+    }
+    /// Serve at most `max_streams` watchers and replicas at once, 64 by default.
+    ///
+    /// Others are told the server is busy.
+    pub fn max_streams(mut self, max_streams: usize) -> Self {
+        self.max_streams = max_streams;
+        self
+    }
+    /// Serve the requests the client has sent, then park the connection for its next request.
+    ///
+    /// A connection in a transaction is served until the transaction ends,
+    /// the client closes it or is idle for `idle_timeout`.
+    fn handle_stream(
+        engine: E,
+        stream: TcpStream,
+        read_only: bool,
+        idle_timeout: Duration,
+        backup_dir: Option<PathBuf>,
+        streams: &Streams,
+        parked: &Sender<Parked>,
+    ) -> Result<()> {
         let mut tcp_wrtier = stream;
+        tcp_wrtier.set_nonblocking(false)?;
         let mut tcp_reader = tcp_wrtier.try_clone()?;
-        log::info!("connect to {}", tcp_wrtier.peer_addr()?);
+        tcp_reader.set_read_timeout(Some(idle_timeout))?;
+        log::info!("serve {}", tcp_wrtier.peer_addr()?);
         let mut decoder = Decoder::new(&mut tcp_reader);
-        let request = decoder.decode_request()?;
-        log::info!("request {:?}", request);
         let mut encoder = Encoder::new();
+        // Transaction begun on this connection
+        let mut tx = None;
+        while let Some(request) = decoder.decode_request()? {
+            log::info!("request {:?}", request);
+            if let Request::Watch(prefix) = request {
+                let receiver = match engine.watch(&prefix) {
+                    Ok(receiver) => receiver,
+                    Err(e) => {
+                        tcp_wrtier.write_all(encoder.encode_response(internal_error(e)))?;
+                        continue;
+                    }
+                };
+                // Kept to answer the client if there are too many streams
+                let mut refused = tcp_wrtier.try_clone()?;
+                // Watching may last forever, don't hold a worker of the pool
+                let spawned = streams.spawn(move |shutdown| {
+                    if let Err(e) = stream_events(receiver, tcp_wrtier, shutdown) {
+                        log::error!("Watch stream error: {e}");
+                    }
+                });
+                if !spawned {
+                    log::warn!("Too many streams, refuse a watcher");
+                    refused.write_all(encoder.encode_response(Response::Busy))?;
+                }
+                return Ok(());
+            }
+            if let Request::Replicate(position) = request {
+                let tail = match engine.tail_log(position) {
+                    Ok(tail) => tail,
+                    Err(e) => {
+                        tcp_wrtier.write_all(encoder.encode_response(internal_error(e)))?;
+                        continue;
+                    }
+                };
+                let mut refused = tcp_wrtier.try_clone()?;
+                // Replication lasts as long as the replica, don't hold a worker of the pool
+                let spawned = streams.spawn(move |shutdown| {
+                    if let Err(e) = stream_log(tail, tcp_wrtier, shutdown) {
+                        log::info!("Replication stream ended: {e}");
+                    }
+                });
+                if !spawned {
+                    log::warn!("Too many streams, refuse a replica");
+                    refused.write_all(encoder.encode_response(Response::Busy))?;
+                }
+                return Ok(());
+            }
+            let response =
+                Self::handle_request(&engine, &mut tx, request, read_only, backup_dir.as_deref());
+            tcp_wrtier.write_all(encoder.encode_response(response))?;
+            log::info!("Send response");
+            // The next request is classified again, unless it was already read
+            if tx.is_none() && !decoder.has_buffered() {
+                // The poller is gone once the server is shut down, closing the connection
+                let _ = parked.send(Parked::new(tcp_wrtier)?);
+                return Ok(());
+            }
+        }
+        Ok(())
+    }
+    fn handle_request<'a>(
+        engine: &'a E,
+        tx: &mut Option<Transaction<'a, E>>,
+        request: Request,
+        read_only: bool,
+        backup_dir: Option<&Path>,
+    ) -> Response {
+        if read_only
+            && matches!(
+                request,
+                Request::Set(..) | Request::SetTtl(..) | Request::Rm(_) | Request::Begin
+            )
+        {
+            log::error!("{request:?} is refused by a read-only server");
+            return Response::Err;
+        }
         match request {
-            Request::Set(key, value) => {
-                if let Err(e) = engine.set(key, value) {
-                    log::error!("Internal error: {e}");
-                    tcp_wrtier.write_all(encoder.encode_response(Response::Err))?;
-                    return Err(e);
-                } else {
-                    tcp_wrtier.write_all(encoder.encode_response(Response::Ok))?;
+            Request::Batch(requests) => Response::Batch(
+                requests
+                    .into_iter()
+                    .map(|request| Self::handle_request(engine, tx, request, read_only, backup_dir))
+                    .collect(),
+            ),
+            Request::Begin => {
+                if tx.is_some() {
+                    log::error!("Transaction already begun");
+                    return Response::Err;
                 }
+                *tx = Some(Transaction::new(engine));
+                Response::Ok
             }
-            Request::Get(key) => match engine.get(&key) {
-                Ok(Some(value)) => {
-                    tcp_wrtier.write_all(encoder.encode_response(Response::Value(value)))?;
+            Request::Commit => match tx.take().map(Transaction::commit) {
+                Some(Ok(true)) => Response::Ok,
+                Some(Ok(false)) => Response::Conflict,
+                Some(Err(e)) => internal_error(e),
+                None => {
+                    log::error!("Commit without transaction");
+                    Response::Err
                 }
-                Ok(None) => {
-                    tcp_wrtier.write_all(encoder.encode_response(Response::NoKey))?;
+            },
+            Request::Abort => match tx.take() {
+                Some(_) => Response::Ok,
+                None => {
+                    log::error!("Abort without transaction");
+                    Response::Err
                 }
-                Err(e) => {
-                    log::error!("Internal error: {e}");
-                    tcp_wrtier.write_all(encoder.encode_response(Response::Err))?;
-                    return Err(e);
+            },
+            Request::Watch(_) | Request::Replicate(_) => {
+                log::error!("{request:?} must be sent alone");
+                Response::Err
+            }
+            request => match tx {
+                Some(tx) => Self::handle_in_transaction(tx, request),
+                None => Self::handle_plain(engine, request, backup_dir),
+            },
+        }
+    }
+    fn handle_plain(engine: &E, request: Request, backup_dir: Option<&Path>) -> Response {
+        match request {
+            Request::Set(key, value) => match engine.set(key, value) {
+                Ok(()) => Response::Ok,
+                Err(e) => internal_error(e),
+            },
+            Request::SetTtl(key, value, ttl) => {
+                match engine.set_with_ttl(key, value, Duration::from_millis(ttl)) {
+                    Ok(()) => Response::Ok,
+                    Err(e) => internal_error(e),
                 }
+            }
+            Request::Get(key) => match engine.get(&key) {
+                Ok(Some(value)) => Response::Value(value),
+                Ok(None) => Response::NoKey,
+                Err(e) => internal_error(e),
             },
             Request::Rm(key) => match engine.remove(key) {
-                Ok(_) => tcp_wrtier.write_all(encoder.encode_response(Response::Ok))?,
-                Err(Error::RemoveNonexistKey) => {
-                    tcp_wrtier.write_all(encoder.encode_response(Response::NoKey))?;
+                Ok(()) => Response::Ok,
+                Err(Error::RemoveNonexistKey) => Response::NoKey,
+                Err(e) => internal_error(e),
+            },
+            Request::Scan(prefix) => match engine.scan(&prefix) {
+                Ok(pairs) => Response::Pairs(pairs),
+                Err(e) => internal_error(e),
+            },
+            Request::ScanPage(prefix, start, limit) => {
+                match engine.scan_page(&prefix, &start, limit as usize) {
+                    Ok(pairs) => Response::Pairs(pairs),
+                    Err(e) => internal_error(e),
                 }
-                Err(e) => {
-                    log::error!("Internal error: {e}");
-                    tcp_wrtier.write_all(encoder.encode_response(Response::Err))?;
-                    return Err(e);
+            }
+            Request::GetAt(key, seq) => match engine.get_at(&key, seq) {
+                Ok(Some(value)) => Response::Value(value),
+                Ok(None) => Response::NoKey,
+                Err(e) => internal_error(e),
+            },
+            Request::History(key, limit) => match engine.history(&key, limit as usize) {
+                Ok(writes) => Response::History(writes),
+                Err(e) => internal_error(e),
+            },
+            Request::CacheStats => match engine.cache_stats() {
+                Ok(stats) => Response::CacheStats(stats),
+                Err(e) => internal_error(e),
+            },
+            Request::Backup(dest_dir) => {
+                let Some(root) = backup_dir else {
+                    log::error!("Backups are disabled on this server");
+                    return Response::Err;
+                };
+                let Some(path) = resolve_backup_dir(root, &dest_dir) else {
+                    log::error!(
+                        "Backup directory {dest_dir} is not a relative path under the root"
+                    );
+                    return Response::Err;
+                };
+                match engine.checkpoint(&path) {
+                    Ok(()) => Response::Ok,
+                    Err(e) => {
+                        log::error!("Backup to {} failed: {e}", path.display());
+                        Response::Err
+                    }
                 }
+            }
+            Request::Batch(_)
+            | Request::Begin
+            | Request::Commit
+            | Request::Abort
+            | Request::Watch(_)
+            | Request::Replicate(_) => unreachable!(),
+        }
+    }
+    fn handle_in_transaction(tx: &mut Transaction<'_, E>, request: Request) -> Response {
+        match request {
+            Request::Set(key, value) => {
+                tx.set(key, value);
+                Response::Ok
+            }
+            Request::Get(key) => match tx.get(&key) {
+                Ok(Some(value)) => Response::Value(value),
+                Ok(None) => Response::NoKey,
+                Err(e) => internal_error(e),
+            },
+            Request::Rm(key) => match tx.remove(key) {
+                Ok(()) => Response::Ok,
+                Err(Error::RemoveNonexistKey) => Response::NoKey,
+                Err(e) => internal_error(e),
             },
+            request => {
+                log::error!("{request:?} is not supported in transaction");
+                Response::Err
+            }
+        }
+    }
+    /// Serve the connection on the pool from its next request, admin ones take the high lane
+    fn dispatch(
+        &self,
+        stream: TcpStream,
+        type_: u8,
+        streams: &Arc<Streams>,
+        parked: &Sender<Parked>,
+    ) {
+        let priority = if matches!(type_, BACKUP_REQUEST_TYPE | CACHE_STATS_REQUEST_TYPE) {
+            Priority::High
+        } else {
+            Priority::Normal
+        };
+        // Kept to answer the client if the pool refuses the request
+        let mut refused = match stream.try_clone() {
+            Ok(refused) => refused,
+            Err(e) => {
+                log::error!("Connection error: {e}");
+                return;
+            }
+        };
+        let engine = self.engine.clone();
+        let read_only = self.read_only;
+        let idle_timeout = self.idle_timeout;
+        let backup_dir = self.backup_dir.clone();
+        let streams = Arc::clone(streams);
+        let parked = parked.clone();
+        let job = move || {
+            if let Err(e) = Self::handle_stream(
+                engine,
+                stream,
+                read_only,
+                idle_timeout,
+                backup_dir,
+                &streams,
+                &parked,
+            ) {
+                log::error!("Connection error: {e}");
+            }
+        };
+        if self.pool.try_spawn_with_priority(priority, job).is_err() {
+            log::warn!("Too many requests queued, refuse a client");
+            let busy = refused
+                .set_nonblocking(false)
+                .and_then(|()| refused.write_all(Encoder::new().encode_response(Response::Busy)));
+            if let Err(e) = busy {
+                log::error!("Connection error: {e}");
+            }
         }
-        log::info!("Send response");
-        Ok(())
     }
     /// listen on the sepecified addr
     pub fn listen_on(&self, addr: SocketAddr) -> Result<()> {
         let listener = TcpListener::bind(addr)?;
+        let (parked_sender, parked_receiver) = mpsc::channel();
+        let shutdown = Arc::clone(&self.shutdown);
+        let streams = Arc::new(Streams::new(Arc::clone(&shutdown), self.max_streams));
+        let accepted = parked_sender.clone();
+        thread::spawn(move || accept_connections(listener, shutdown, accepted));
 
-        for stream in listener.incoming() {
-            if self.shutdown.load(Ordering::SeqCst) {
-                break;
+        // Connections waiting for their next request, each is dispatched as it arrives
+        let mut idle: Vec<Parked> = Vec::new();
+        while !self.shutdown.load(Ordering::SeqCst) {
+            let wait = if idle.is_empty() {
+                ACCEPT_POLL_INTERVAL
+            } else {
+                POLL_INTERVAL
+            };
+            if let Ok(connection) = parked_receiver.recv_timeout(wait) {
+                idle.push(connection);
+            }
+            idle.extend(parked_receiver.try_iter());
+            for connection in std::mem::take(&mut idle) {
+                match peek_request_type(&connection.stream) {
+                    Ok(Some(type_)) => {
+                        self.dispatch(connection.stream, type_, &streams, &parked_sender)
+                    }
+                    Ok(None) if connection.since.elapsed() < self.idle_timeout => {
+                        idle.push(connection)
+                    }
+                    Ok(None) => log::info!("Close an idle connection"),
+                    Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {}
+                    Err(e) => log::error!("Connection error: {e}"),
+                }
             }
-            let stream = stream?;
-            let engine = self.engine.clone();
-            self.pool.spawn(move || {
-                Self::handle_stream(engine, stream).unwrap();
-            });
         }
+        streams.join();
         Ok(())
     }
 }
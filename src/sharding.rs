@@ -0,0 +1,262 @@
+use std::{collections::BTreeMap, net::SocketAddr};
+
+use crate::{Error, KvsClient, Request, Response, Result, IS_TEST};
+
+/// Points each node takes on the ring, more of them spread keys more evenly
+const VIRTUAL_NODES: u32 = 160;
+/// How many pairs a server is asked for at once when scanning all its keys
+const SCAN_PAGE_SIZE: usize = if IS_TEST { 4 } else { 1000 };
+
+/// Consistent hashing of keys onto nodes.
+///
+/// Each node takes `VIRTUAL_NODES` points on the ring, a key belongs to the node of
+/// the first point at or after its hash, so adding a node only takes keys from the others.
+#[derive(Clone, Debug, Default)]
+pub struct HashRing {
+    nodes: Vec<SocketAddr>,
+    points: BTreeMap<u64, usize>,
+}
+
+impl HashRing {
+    /// Put the nodes on the ring
+    pub fn new(nodes: &[SocketAddr]) -> Self {
+        let mut ring = Self::default();
+        for &node in nodes {
+            ring.add(node);
+        }
+        ring
+    }
+    /// Put the node on the ring, nothing happens if it's already there
+    pub fn add(&mut self, node: SocketAddr) {
+        if self.nodes.contains(&node) {
+            return;
+        }
+        let index = self.nodes.len();
+        self.nodes.push(node);
+        for i in 0..VIRTUAL_NODES {
+            self.points
+                .insert(hash(format!("{node}#{i}").as_bytes()), index);
+        }
+    }
+    /// Nodes in the order they were added
+    pub fn nodes(&self) -> &[SocketAddr] {
+        &self.nodes
+    }
+    /// The node the key belongs to, `None` if the ring is empty
+    pub fn node_of(&self, key: &str) -> Option<SocketAddr> {
+        self.index_of(key).map(|index| self.nodes[index])
+    }
+    fn index_of(&self, key: &str) -> Option<usize> {
+        let hash = hash(key.as_bytes());
+        self.points
+            .range(hash..)
+            .next()
+            .or_else(|| self.points.iter().next())
+            .map(|(_, &index)| index)
+    }
+}
+
+/// 64-bit FNV-1a, stable so that every client routes keys the same way.
+///
+/// Keys differing only in the last bytes hash close together with plain FNV,
+/// so the result is mixed with the MurmurHash3 finalizer to spread them on the ring.
+fn hash(bytes: &[u8]) -> u64 {
+    let mut hash = bytes.iter().fold(0xcbf29ce484222325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+    });
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51afd7ed558ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ceb9fe1a85ec53);
+    hash ^ (hash >> 33)
+}
+
+/// A client of several servers, each holding the keys the `HashRing` gives it
+pub struct ShardedKvsClient {
+    ring: HashRing,
+    /// In the order of `ring.nodes()`
+    clients: Vec<KvsClient>,
+}
+
+impl ShardedKvsClient {
+    /// Connect to all the servers
+    pub fn connect(addrs: &[SocketAddr]) -> Result<Self> {
+        let ring = HashRing::new(addrs);
+        let clients = ring
+            .nodes()
+            .iter()
+            .map(|&addr| KvsClient::connect(addr))
+            .collect::<Result<_>>()?;
+        Ok(Self { ring, clients })
+    }
+    /// How keys are routed
+    pub fn ring(&self) -> &HashRing {
+        &self.ring
+    }
+    /// Set the key on the server it belongs to
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        match self.request_owner(&key, Request::Set(key.clone(), value))? {
+            Response::Ok => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+    /// Get the key from the server it belongs to
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        match self.request_owner(&key, Request::Get(key.clone()))? {
+            Response::Value(value) => Ok(Some(value)),
+            Response::NoKey => Ok(None),
+            response => Err(unexpected(response)),
+        }
+    }
+    /// Remove the key from the server it belongs to
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        match self.request_owner(&key, Request::Rm(key.clone()))? {
+            Response::Ok => Ok(()),
+            Response::NoKey => Err(Error::RemoveNonexistKey),
+            response => Err(unexpected(response)),
+        }
+    }
+    /// Scan every server and merge the pairs, sorted by key
+    pub fn scan(&mut self, prefix: &str) -> Result<Vec<(String, String)>> {
+        let mut pairs = Vec::new();
+        for client in &mut self.clients {
+            pairs.extend(scan(client, prefix)?);
+        }
+        pairs.sort_unstable();
+        Ok(pairs)
+    }
+    /// Get at most `limit` pairs of `scan`, starting from the key `start`
+    pub fn scan_page(
+        &mut self,
+        prefix: &str,
+        start: &str,
+        limit: usize,
+    ) -> Result<Vec<(String, String)>> {
+        // The first pairs overall are among the first of each server
+        let mut pairs = Vec::new();
+        for client in &mut self.clients {
+            let request = Request::ScanPage(prefix.to_owned(), start.to_owned(), limit as u32);
+            match client.request(request)? {
+                Response::Pairs(page) => pairs.extend(page),
+                response => return Err(unexpected(response)),
+            }
+        }
+        pairs.sort_unstable();
+        pairs.truncate(limit);
+        Ok(pairs)
+    }
+    /// Put a new server on the ring, its keys stay on the old servers until `rebalance`
+    pub fn add_node(&mut self, addr: SocketAddr) -> Result<()> {
+        if self.ring.nodes().contains(&addr) {
+            return Ok(());
+        }
+        self.clients.push(KvsClient::connect(addr)?);
+        self.ring.add(addr);
+        Ok(())
+    }
+    /// Move every key to the server it belongs to, returning how many moved.
+    ///
+    /// Writes must be stopped while it runs, a write to the old server of a key may be lost.
+    /// A key is set on its new server before it's removed from the old one,
+    /// so it can always be read from one of them.
+    /// A key already on its new server is newer, only the old copy is removed.
+    pub fn rebalance(&mut self) -> Result<usize> {
+        let mut moved = 0;
+        for from in 0..self.clients.len() {
+            let mut start = String::new();
+            loop {
+                let page = scan_page(&mut self.clients[from], "", &start)?;
+                let last_page = page.len() < SCAN_PAGE_SIZE;
+                if let Some((key, _)) = page.last() {
+                    start = next_key(key);
+                }
+                for (key, value) in page {
+                    let to = self.ring.index_of(&key).unwrap();
+                    if to == from {
+                        continue;
+                    }
+                    if set_if_absent(&mut self.clients[to], key.clone(), value)? {
+                        moved += 1;
+                    }
+                    match self.clients[from].request(Request::Rm(key))? {
+                        Response::Ok | Response::NoKey => {}
+                        response => return Err(unexpected(response)),
+                    }
+                }
+                if last_page {
+                    break;
+                }
+            }
+        }
+        Ok(moved)
+    }
+    fn request_owner(&mut self, key: &str, request: Request) -> Result<Response> {
+        let index = self.ring.index_of(key).ok_or(Error::EmptyCluster)?;
+        self.clients[index].request(request)
+    }
+}
+
+/// Get all pairs with the prefix from the server, a page at a time
+fn scan(client: &mut KvsClient, prefix: &str) -> Result<Vec<(String, String)>> {
+    let mut pairs = Vec::new();
+    let mut start = prefix.to_owned();
+    loop {
+        let page = scan_page(client, prefix, &start)?;
+        let last_page = page.len() < SCAN_PAGE_SIZE;
+        if let Some((key, _)) = page.last() {
+            start = next_key(key);
+        }
+        pairs.extend(page);
+        if last_page {
+            return Ok(pairs);
+        }
+    }
+}
+
+/// Get at most `SCAN_PAGE_SIZE` pairs with the prefix from the server, from the key `start` on
+fn scan_page(client: &mut KvsClient, prefix: &str, start: &str) -> Result<Vec<(String, String)>> {
+    let request = Request::ScanPage(prefix.to_owned(), start.to_owned(), SCAN_PAGE_SIZE as u32);
+    match client.request(request)? {
+        Response::Pairs(pairs) => Ok(pairs),
+        response => Err(unexpected(response)),
+    }
+}
+
+/// The smallest key after `key`
+fn next_key(key: &str) -> String {
+    format!("{key}\0")
+}
+
+/// Set the key if it's absent, in a transaction so a write meanwhile isn't overwritten
+fn set_if_absent(client: &mut KvsClient, key: String, value: String) -> Result<bool> {
+    expect_ok(client.request(Request::Begin)?)?;
+    match client.request(Request::Get(key.clone()))? {
+        Response::NoKey => {}
+        Response::Value(_) => {
+            expect_ok(client.request(Request::Abort)?)?;
+            return Ok(false);
+        }
+        response => return Err(unexpected(response)),
+    }
+    expect_ok(client.request(Request::Set(key, value))?)?;
+    match client.request(Request::Commit)? {
+        Response::Ok => Ok(true),
+        Response::Conflict => Ok(false),
+        response => Err(unexpected(response)),
+    }
+}
+
+fn expect_ok(response: Response) -> Result<()> {
+    match response {
+        Response::Ok => Ok(()),
+        response => Err(unexpected(response)),
+    }
+}
+
+fn unexpected(response: Response) -> Error {
+    match response {
+        Response::Err => Error::ServerError,
+        Response::Busy => Error::ServerBusy,
+        response => Error::DecodeError(format!("Unexpected response: {response:?}")),
+    }
+}
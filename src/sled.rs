@@ -1,13 +1,33 @@
-use std::path::Path;
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    path::Path,
+    sync::{
+        mpsc::{Receiver, RecvTimeoutError},
+        Arc, Mutex, Weak,
+    },
+    thread,
+    time::Duration,
+};
 
-use sled::Db;
+use sled::{
+    transaction::{ConflictableTransactionError, TransactionError},
+    Db, Subscriber,
+};
 
-use crate::{Error, KvsEngine, Result, IS_TEST};
+use crate::{
+    watch::Watchers, Error, Event, KvsEngine, MemSnapshot, ReadSet, Result, WriteSet, IS_TEST,
+};
+
+/// How long the watch thread waits for events before checking the engine is still there
+const WATCH_POLL_INTERVAL: Duration = Duration::from_secs(1);
 
 /// A sled wrapper to impl `KvsEngine` trait
 #[derive(Clone)]
 pub struct SledKvsEngine {
     db: Db,
+    /// Fed by one thread forwarding the events of sled, `None` until the first watch
+    watchers: Arc<Mutex<Option<Watchers>>>,
 }
 
 impl SledKvsEngine {
@@ -15,6 +35,7 @@ impl SledKvsEngine {
     pub fn open(path: impl AsRef<Path>) -> Result<Self> {
         Ok(Self {
             db: sled::open(path)?,
+            watchers: Arc::default(),
         })
     }
     ///
@@ -25,6 +46,8 @@ impl SledKvsEngine {
 }
 
 impl KvsEngine for SledKvsEngine {
+    type Snapshot = MemSnapshot;
+
     fn get(&self, key: &str) -> Result<Option<String>> {
         let Some(value) = self.db.get(key)? else {
             return Ok(None);
@@ -45,4 +68,135 @@ impl KvsEngine for SledKvsEngine {
         }
         Ok(())
     }
+    fn scan(&self, prefix: &str) -> Result<Vec<(String, String)>> {
+        self.db
+            .scan_prefix(prefix)
+            .map(|pair| {
+                let (key, value) = pair?;
+                let key = std::str::from_utf8(&key)?.to_owned();
+                let value = std::str::from_utf8(&value)?.to_owned();
+                Ok((key, value))
+            })
+            .collect()
+    }
+    fn scan_page(&self, prefix: &str, start: &str, limit: usize) -> Result<Vec<(String, String)>> {
+        self.db
+            .range(start.max(prefix)..)
+            .take_while(|pair| !matches!(pair, Ok((key, _)) if !key.starts_with(prefix.as_bytes())))
+            .take(limit)
+            .map(|pair| {
+                let (key, value) = pair?;
+                let key = std::str::from_utf8(&key)?.to_owned();
+                let value = std::str::from_utf8(&value)?.to_owned();
+                Ok((key, value))
+            })
+            .collect()
+    }
+    fn checkpoint(&self, dest_dir: &Path) -> Result<()> {
+        if dest_dir.exists() && dest_dir.read_dir()?.next().is_some() {
+            return Err(Error::NonEmptyCheckpointDir);
+        }
+        let dest = sled::open(dest_dir)?;
+        dest.import(self.db.export());
+        dest.flush()?;
+        Ok(())
+    }
+    /// Copy all pairs into memory.
+    ///
+    /// sled has no snapshot reads, so writes during copying may be partly seen.
+    fn snapshot(&self) -> Result<Self::Snapshot> {
+        let pairs = self.scan("")?;
+        Ok(MemSnapshot::new(pairs))
+    }
+    /// The first watch starts a thread forwarding all events of sled to the watchers.
+    ///
+    /// The thread ends after the engine is dropped.
+    fn watch(&self, prefix: &str) -> Result<Receiver<Event>> {
+        let mut watchers = self.watchers.lock().unwrap();
+        let watchers = match &mut *watchers {
+            Some(watchers) => watchers,
+            None => {
+                // Subscribed before returning, so no write after the watch is missed
+                let subscriber = self.db.watch_prefix(vec![]);
+                let weak = Arc::downgrade(&self.watchers);
+                thread::spawn(move || forward_events(subscriber, weak));
+                watchers.insert(Watchers::default())
+            }
+        };
+        Ok(watchers.add(prefix))
+    }
+    /// sled keeps no versions, a hash of the value stands for it
+    fn get_versioned(&self, key: &str) -> Result<Option<(String, u64)>> {
+        let Some(value) = self.db.get(key)? else {
+            return Ok(None);
+        };
+        let version = value_version(&value);
+        Ok(Some((std::str::from_utf8(&value)?.to_owned(), version)))
+    }
+    /// Validate the reads and apply the writes in a sled transaction
+    fn commit(&self, reads: &ReadSet, writes: WriteSet) -> Result<bool> {
+        let result = self.db.transaction(|tx| {
+            for (key, version) in reads {
+                let current = tx.get(key)?.map(|value| value_version(&value));
+                if current != *version {
+                    return Err(ConflictableTransactionError::Abort(()));
+                }
+            }
+            for (key, value) in &writes {
+                match value {
+                    Some(value) => tx.insert(key.as_bytes(), value.as_bytes())?,
+                    // Removing an absent key would still make an event
+                    None if tx.get(key)?.is_some() => tx.remove(key.as_bytes())?,
+                    None => None,
+                };
+            }
+            Ok(())
+        });
+        match result {
+            Ok(()) => {}
+            Err(TransactionError::Abort(())) => return Ok(false),
+            Err(TransactionError::Storage(e)) => return Err(e.into()),
+        }
+        if IS_TEST {
+            self.db.flush()?;
+        }
+        Ok(true)
+    }
+}
+
+fn value_version(value: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Forward the events of sled to the watchers, until the engine is dropped
+fn forward_events(mut subscriber: Subscriber, watchers: Weak<Mutex<Option<Watchers>>>) {
+    loop {
+        let event = subscriber.next_timeout(WATCH_POLL_INTERVAL);
+        let Some(watchers) = watchers.upgrade() else {
+            return;
+        };
+        let mut watchers = watchers.lock().unwrap();
+        let (key, event) = match event {
+            Ok(sled::Event::Insert { key, value }) => {
+                let key = String::from_utf8_lossy(&key).into_owned();
+                let value = String::from_utf8_lossy(&value).into_owned();
+                (key.clone(), Event::Set(key, value))
+            }
+            Ok(sled::Event::Remove { key }) => {
+                let key = String::from_utf8_lossy(&key).into_owned();
+                (key.clone(), Event::Rm(key))
+            }
+            Err(RecvTimeoutError::Timeout) => continue,
+            // The db is closed, a later watch starts again
+            Err(RecvTimeoutError::Disconnected) => {
+                *watchers = None;
+                return;
+            }
+        };
+        if let Some(watchers) = &mut *watchers {
+            watchers.notify(&key, || event);
+        }
+    }
 }
@@ -0,0 +1,31 @@
+use std::collections::BTreeMap;
+
+use crate::{KvsSnapshot, Result};
+
+/// A snapshot holding all pairs in memory, for engines can't freeze their state on disk
+pub struct MemSnapshot {
+    pairs: BTreeMap<String, String>,
+}
+
+impl MemSnapshot {
+    /// Make a snapshot of the pairs
+    pub fn new(pairs: impl IntoIterator<Item = (String, String)>) -> Self {
+        Self {
+            pairs: pairs.into_iter().collect(),
+        }
+    }
+}
+
+impl KvsSnapshot for MemSnapshot {
+    fn get(&self, key: &str) -> Result<Option<String>> {
+        Ok(self.pairs.get(key).cloned())
+    }
+    fn scan(&self, prefix: &str) -> Result<Vec<(String, String)>> {
+        Ok(self
+            .pairs
+            .range(prefix.to_owned()..)
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect())
+    }
+}
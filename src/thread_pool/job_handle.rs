@@ -0,0 +1,46 @@
+use std::{
+    any::Any,
+    sync::mpsc::{self, Receiver, SyncSender, TryRecvError},
+    thread,
+};
+
+use crate::{Error, Result};
+
+/// The result of a job spawned by `ThreadPool::spawn_with_handle`
+pub struct JobHandle<T> {
+    receiver: Receiver<thread::Result<T>>,
+}
+
+impl<T> JobHandle<T> {
+    pub(super) fn new() -> (SyncSender<thread::Result<T>>, Self) {
+        let (sender, receiver) = mpsc::sync_channel(1);
+        (sender, Self { receiver })
+    }
+    /// Wait for the job to finish, a panic of it is returned as `Error::JobPanicked`
+    pub fn join(self) -> Result<T> {
+        match self.receiver.recv() {
+            Ok(result) => result.map_err(panicked),
+            Err(_) => Err(Error::JobCancelled),
+        }
+    }
+    /// Get the result if the job has finished, or the handle back if not
+    pub fn try_join(self) -> std::result::Result<Result<T>, Self> {
+        match self.receiver.try_recv() {
+            Ok(result) => Ok(result.map_err(panicked)),
+            Err(TryRecvError::Empty) => Err(self),
+            Err(TryRecvError::Disconnected) => Ok(Err(Error::JobCancelled)),
+        }
+    }
+}
+
+fn panicked(payload: Box<dyn Any + Send>) -> Error {
+    Error::JobPanicked(panic_message(&*payload).to_owned())
+}
+
+/// The message a job panicked with, empty if it isn't a string
+pub fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    match payload.downcast_ref::<String>() {
+        Some(message) => message,
+        None => payload.downcast_ref::<&str>().copied().unwrap_or_default(),
+    }
+}
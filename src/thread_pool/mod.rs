@@ -1,12 +1,49 @@
+mod job_handle;
 mod naive;
 mod rayon_wrapper;
+mod scope;
 mod shared_queue;
+mod timer;
+mod work_stealing;
 
-use crate::Result;
+use std::{
+    panic::{catch_unwind, AssertUnwindSafe},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
+use crate::{Error, Result};
+
+pub use job_handle::{panic_message, JobHandle};
 pub use naive::NaiveThreadPool;
 pub use rayon_wrapper::RayonThreadPool;
-pub use shared_queue::SharedQueueThreadPool;
+pub use scope::Scope;
+pub use shared_queue::{
+    FullQueuePolicy, PoolConfig, PoolMonitor, PoolStats, SharedQueueThreadPool, WorkerMetrics,
+};
+pub use timer::{Spawner, TimerHandle};
+pub use work_stealing::WorkStealingThreadPool;
+
+use timer::TimerJob;
+
+/// What `SharedQueueThreadPool::shutdown` does with the queued jobs
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ShutdownMode {
+    /// Run all of them
+    Drain,
+    /// Drop those not yet given to a worker
+    Cancel,
+}
+
+/// Which lane of `SharedQueueThreadPool` a job waits in
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Priority {
+    /// Dispatched before the normal jobs, and given a few places beyond a full queue
+    High,
+    /// The lane of `spawn` and `try_spawn`
+    #[default]
+    Normal,
+}
 
 /// Thread pool trait
 pub trait ThreadPool: Sized {
@@ -16,4 +53,71 @@ pub trait ThreadPool: Sized {
     fn spawn<F>(&self, job: F)
     where
         F: FnOnce() + Send + 'static;
+    /// spawn a task unless the pool is too busy, the task is given back then
+    fn try_spawn<F>(&self, job: F) -> std::result::Result<(), F>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.spawn(job);
+        Ok(())
+    }
+    /// spawn a task in the lane of the priority, pools without lanes just spawn it
+    fn spawn_with_priority<F>(&self, _priority: Priority, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.spawn(job)
+    }
+    /// spawn a task in the lane of the priority unless it's too busy, the task is given back then
+    fn try_spawn_with_priority<F>(&self, _priority: Priority, job: F) -> std::result::Result<(), F>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.try_spawn(job)
+    }
+    /// change the number of threads
+    fn resize(&self, _threads: u32) -> Result<()> {
+        Err(Error::ResizeUnsupported)
+    }
+    /// spawn a task, and get its result or panic by the handle
+    fn spawn_with_handle<F, T>(&self, job: F) -> JobHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (sender, handle) = JobHandle::new();
+        self.spawn(move || {
+            // The handle may be dropped, nobody wants the result then
+            _ = sender.send(catch_unwind(AssertUnwindSafe(job)));
+        });
+        handle
+    }
+}
+
+/// Thread pools running tasks after a delay, on the timer thread shared by all pools
+pub trait Scheduler: ThreadPool {
+    /// a handle spawning tasks on the pool from the timer thread, without keeping it alive
+    fn spawner(&self) -> Spawner;
+    /// spawn a task after the delay
+    fn schedule_after<F>(&self, delay: Duration, job: F) -> TimerHandle
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        timer::schedule(
+            Instant::now() + delay,
+            self.spawner(),
+            TimerJob::Once(Box::new(job)),
+        )
+    }
+    /// spawn a task every interval until cancelled, skipping a run while the last is running
+    fn schedule_every<F>(&self, interval: Duration, job: F) -> TimerHandle
+    where
+        F: FnMut() + Send + 'static,
+    {
+        timer::schedule(
+            Instant::now() + interval,
+            self.spawner(),
+            TimerJob::Every(interval, Arc::new(Mutex::new(job))),
+        )
+    }
 }
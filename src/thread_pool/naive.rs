@@ -1,15 +1,18 @@
 use std::{
     cell::RefCell,
+    sync::Arc,
     thread::{self, JoinHandle},
 };
 
-use super::ThreadPool;
+use super::{Scheduler, Spawner, ThreadPool};
 
 use crate::Result;
 
 /// A naive thread pool, do not reuse, just spawn new thread
 pub struct NaiveThreadPool {
     handles: RefCell<Vec<JoinHandle<()>>>,
+    /// Spawners hold it weakly, to stop once the pool is dropped
+    alive: Arc<()>,
 }
 
 impl Drop for NaiveThreadPool {
@@ -25,6 +28,7 @@ impl ThreadPool for NaiveThreadPool {
     fn new(threads: u32) -> Result<Self> {
         Ok(Self {
             handles: RefCell::new(Vec::with_capacity(threads as usize)),
+            alive: Arc::new(()),
         })
     }
     fn spawn<F>(&self, job: F)
@@ -34,3 +38,17 @@ impl ThreadPool for NaiveThreadPool {
         self.handles.borrow_mut().push(thread::spawn(job))
     }
 }
+
+impl Scheduler for NaiveThreadPool {
+    /// Threads of timer jobs are detached, the pool doesn't join them
+    fn spawner(&self) -> Spawner {
+        let alive = Arc::downgrade(&self.alive);
+        Spawner::new(move |job| {
+            if alive.strong_count() == 0 {
+                return false;
+            }
+            thread::spawn(job);
+            true
+        })
+    }
+}
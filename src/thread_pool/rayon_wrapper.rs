@@ -1,24 +1,61 @@
-use super::ThreadPool;
+use std::{
+    panic::{catch_unwind, AssertUnwindSafe},
+    sync::Arc,
+    thread,
+};
+
+use super::{Scheduler, Spawner, ThreadPool};
 
 use crate::Result;
 
 /// A rayon based thread pool
 pub struct RayonThreadPool {
-    inner: rayon::ThreadPool,
+    inner: Arc<rayon::ThreadPool>,
 }
 
 impl ThreadPool for RayonThreadPool {
     fn new(threads: u32) -> Result<Self> {
         Ok(RayonThreadPool {
-            inner: rayon::ThreadPoolBuilder::new()
-                .num_threads(threads as usize)
-                .build()?,
+            inner: Arc::new(
+                rayon::ThreadPoolBuilder::new()
+                    .num_threads(threads as usize)
+                    .thread_name(|id| format!("kvs-worker-{id}"))
+                    .build()?,
+            ),
         })
     }
     fn spawn<F>(&self, job: F)
     where
         F: FnOnce() + Send + 'static,
     {
-        self.inner.install(job)
+        spawn_caught(&self.inner, job)
     }
 }
+
+impl Scheduler for RayonThreadPool {
+    fn spawner(&self) -> Spawner {
+        let inner = Arc::downgrade(&self.inner);
+        Spawner::new(move |job| match inner.upgrade() {
+            Some(inner) => {
+                spawn_caught(&inner, job);
+                true
+            }
+            None => false,
+        })
+    }
+}
+
+fn spawn_caught<F>(pool: &rayon::ThreadPool, job: F)
+where
+    F: FnOnce() + Send + 'static,
+{
+    pool.spawn(move || {
+        // rayon aborts the process on a panic of a spawned job, so stop it here
+        if catch_unwind(AssertUnwindSafe(job)).is_err() {
+            eprintln!(
+                "thread '{:?}' panicked and recover",
+                thread::current().name()
+            );
+        }
+    })
+}
@@ -0,0 +1,99 @@
+use std::{
+    any::Any,
+    marker::PhantomData,
+    mem,
+    panic::{catch_unwind, resume_unwind, AssertUnwindSafe},
+    sync::{Arc, Condvar, Mutex},
+};
+
+use super::{SharedQueueThreadPool, ThreadPool};
+
+/// Spawns tasks borrowing from outside the scope, see `SharedQueueThreadPool::scope`
+pub struct Scope<'scope, 'env: 'scope> {
+    pool: &'scope SharedQueueThreadPool,
+    state: Arc<ScopeState>,
+    /// Invariant over both lifetimes, like `std::thread::Scope`
+    _marker: PhantomData<(&'scope mut &'scope (), &'env mut &'env ())>,
+}
+
+#[derive(Default)]
+struct ScopeState {
+    /// Tasks spawned and not yet finished or dropped
+    running: Mutex<usize>,
+    finished: Condvar,
+    /// Of the first task which panicked
+    panic: Mutex<Option<Box<dyn Any + Send>>>,
+}
+
+/// Counts the task finished when dropped, even if it never runs
+struct Finished(Arc<ScopeState>);
+
+impl Drop for Finished {
+    fn drop(&mut self) {
+        *self.0.running.lock().unwrap() -= 1;
+        self.0.finished.notify_all();
+    }
+}
+
+/// Fields drop in order, so the task is gone before it counts as finished
+struct ScopedTask<F> {
+    task: F,
+    finished: Finished,
+}
+
+impl<'scope> Scope<'scope, '_> {
+    /// Run the task on the pool, it's finished before the scope returns
+    pub fn spawn<F>(&'scope self, task: F)
+    where
+        F: FnOnce() + Send + 'scope,
+    {
+        *self.state.running.lock().unwrap() += 1;
+        let scoped = ScopedTask {
+            task,
+            finished: Finished(Arc::clone(&self.state)),
+        };
+        let job: Box<dyn FnOnce() + Send + 'scope> = Box::new(move || {
+            let ScopedTask { task, finished } = scoped;
+            if let Err(payload) = catch_unwind(AssertUnwindSafe(task)) {
+                finished.0.panic.lock().unwrap().get_or_insert(payload);
+            }
+        });
+        // SAFETY: `SharedQueueThreadPool::scope` doesn't return until every task
+        // has finished or been dropped, so nothing borrowed for 'scope outlives it
+        let job: Box<dyn FnOnce() + Send + 'static> = unsafe { mem::transmute(job) };
+        self.pool.spawn(job);
+    }
+    fn wait(&self) {
+        let mut running = self.state.running.lock().unwrap();
+        while *running > 0 {
+            running = self.state.finished.wait(running).unwrap();
+        }
+    }
+}
+
+impl SharedQueueThreadPool {
+    /// Run tasks which may borrow from the caller's stack, returning after all are finished.
+    ///
+    /// A panic of `f` or of a task is raised again here, once every task is finished.
+    /// Calling it from a job of the same pool may deadlock if no other worker is free.
+    pub fn scope<'env, F, R>(&self, f: F) -> R
+    where
+        F: for<'scope> FnOnce(&'scope Scope<'scope, 'env>) -> R,
+    {
+        let scope = Scope {
+            pool: self,
+            state: Arc::new(ScopeState::default()),
+            _marker: PhantomData,
+        };
+        let result = catch_unwind(AssertUnwindSafe(|| f(&scope)));
+        scope.wait();
+        let result = match result {
+            Ok(result) => result,
+            Err(payload) => resume_unwind(payload),
+        };
+        if let Some(payload) = scope.state.panic.lock().unwrap().take() {
+            resume_unwind(payload);
+        }
+        result
+    }
+}
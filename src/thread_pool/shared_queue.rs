@@ -1,99 +1,667 @@
 use std::{
+    any::Any,
+    collections::{HashMap, VecDeque},
+    fmt,
     panic::{catch_unwind, AssertUnwindSafe},
-    sync::mpsc::{self, Sender, SyncSender},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, Sender, SyncSender, TryRecvError},
+        Arc, Condvar, Mutex, MutexGuard,
+    },
     thread::{self, JoinHandle},
+    time::{Duration, Instant},
 };
 
-use super::ThreadPool;
+use super::{panic_message, Priority, Scheduler, ShutdownMode, Spawner, ThreadPool};
 
 use crate::{Error, Result};
 
-/// A thread pool based on shared queue
+/// How long an idle worker above the minimum lives by default
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+/// High priority jobs dispatched in a row before a waiting normal one
+const HIGH_PRIORITY_BURST: usize = 8;
+/// Places high priority jobs may take beyond a full queue by default
+const DEFAULT_HIGH_PRIORITY_RESERVE: usize = 8;
+/// How many of the latest run times the percentiles are taken from
+const RUN_TIME_SAMPLES: usize = 1024;
+
+/// Called on the worker with the payload of a panicked job
+type PanicHook = Arc<dyn Fn(&(dyn Any + Send)) + Send + Sync>;
+
+/// What spawning does when the queue of a `SharedQueueThreadPool` is full
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FullQueuePolicy {
+    /// Wait for room in the queue
+    #[default]
+    Block,
+    /// Give the job back from `try_spawn`
+    Reject,
+    /// Run the job on the spawning thread
+    CallerRuns,
+}
+
+/// Sizing of a `SharedQueueThreadPool`
+#[derive(Clone)]
+pub struct PoolConfig {
+    min_threads: usize,
+    max_threads: usize,
+    idle_timeout: Duration,
+    /// `None` for an unbounded queue
+    queue_capacity: Option<usize>,
+    full_queue_policy: FullQueuePolicy,
+    /// Places beyond `queue_capacity` only high priority jobs may take
+    high_priority_reserve: usize,
+    /// `None` to print the panics to stderr
+    on_panic: Option<PanicHook>,
+}
+
+impl fmt::Debug for PoolConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PoolConfig")
+            .field("min_threads", &self.min_threads)
+            .field("max_threads", &self.max_threads)
+            .field("idle_timeout", &self.idle_timeout)
+            .field("queue_capacity", &self.queue_capacity)
+            .field("full_queue_policy", &self.full_queue_policy)
+            .field("high_priority_reserve", &self.high_priority_reserve)
+            .field("on_panic", &self.on_panic.is_some())
+            .finish()
+    }
+}
+
+impl PoolConfig {
+    /// A pool of fixed `threads` workers
+    pub fn new(threads: u32) -> Self {
+        Self {
+            min_threads: threads as usize,
+            max_threads: threads as usize,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            queue_capacity: None,
+            full_queue_policy: FullQueuePolicy::Block,
+            high_priority_reserve: DEFAULT_HIGH_PRIORITY_RESERVE,
+            on_panic: None,
+        }
+    }
+    /// Start more workers, up to `max_threads`, when all are busy
+    pub fn max_threads(mut self, max_threads: u32) -> Self {
+        self.max_threads = max_threads as usize;
+        self
+    }
+    /// Stop workers above the minimum once idle for this long
+    pub fn idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+    /// Queue at most `capacity` jobs waiting for a worker, and apply the policy beyond it
+    pub fn queue_capacity(mut self, capacity: usize, policy: FullQueuePolicy) -> Self {
+        self.queue_capacity = Some(capacity);
+        self.full_queue_policy = policy;
+        self
+    }
+    /// Let high priority jobs take `reserve` places beyond a full queue, 8 by default
+    pub fn high_priority_reserve(mut self, reserve: usize) -> Self {
+        self.high_priority_reserve = reserve;
+        self
+    }
+    /// Report the panics of jobs to the hook instead of stderr, or to both if the hook panics
+    pub fn on_panic<H>(mut self, hook: H) -> Self
+    where
+        H: Fn(&(dyn Any + Send)) + Send + Sync + 'static,
+    {
+        self.on_panic = Some(Arc::new(hook));
+        self
+    }
+}
+
+/// Live worker counts of a `SharedQueueThreadPool`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct WorkerMetrics {
+    /// Workers started and not yet stopped
+    pub live: usize,
+    /// Workers running a job
+    pub busy: usize,
+}
+
+/// Job counts and run times of a `SharedQueueThreadPool`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PoolStats {
+    /// Jobs waiting in the queue
+    pub queued: usize,
+    /// Jobs running now
+    pub running: usize,
+    /// Jobs returned without panicking
+    pub completed: u64,
+    /// Jobs which panicked
+    pub panicked: u64,
+    /// Median run time of the latest jobs
+    pub p50: Duration,
+    /// 99th percentile run time of the latest jobs
+    pub p99: Duration,
+}
+
+/// Reads the stats of a `SharedQueueThreadPool`, also after it's moved or dropped
+#[derive(Clone)]
+pub struct PoolMonitor {
+    counters: Arc<Counters>,
+}
+
+impl PoolMonitor {
+    /// Stats of the pool now
+    pub fn stats(&self) -> PoolStats {
+        let counters = &self.counters;
+        let mut run_times = Vec::from_iter(counters.run_times.lock().unwrap().iter().copied());
+        run_times.sort_unstable();
+        // By nearest rank
+        let percentile = |p: usize| {
+            run_times
+                .get((run_times.len() * p).div_ceil(100).saturating_sub(1))
+                .copied()
+                .unwrap_or_default()
+        };
+        PoolStats {
+            queued: *counters.queued.lock().unwrap(),
+            running: counters.busy.load(Ordering::SeqCst),
+            completed: counters.completed.load(Ordering::SeqCst),
+            panicked: counters.panicked.load(Ordering::SeqCst),
+            p50: percentile(50),
+            p99: percentile(99),
+        }
+    }
+}
+
+/// A thread pool based on shared queue.
+///
+/// The pool keeps `min_threads` workers, starts more up to `max_threads` when a job
+/// finds all of them busy, and stops those idle longer than `idle_timeout`.
+///
+/// With a bounded queue, `spawn` always waits for room, only `try_spawn` applies the policy.
+///
+/// Jobs spawned with `Priority::High` go first, but a normal job still goes
+/// after every few high ones, so the normal lane isn't starved.
 pub struct SharedQueueThreadPool {
-    job_dispatcher: Sender<BoxedJob>,
+    /// `None` once shut down, spawners hold it weakly
+    job_dispatcher: Option<Arc<Sender<Message>>>,
+    /// Returns the number of jobs dropped without running
+    master: Option<JoinHandle<usize>>,
+    /// Set to make the master drop the queued jobs
+    cancel: Arc<AtomicBool>,
+    counters: Arc<Counters>,
+    queue_capacity: Option<usize>,
+    full_queue_policy: FullQueuePolicy,
+    high_priority_reserve: usize,
 }
 
 type BoxedJob = Box<dyn FnOnce() + Send + 'static>;
 
+enum Message {
+    Job(BoxedJob, Priority),
+    /// Change the maximum number of workers
+    Resize(usize),
+}
+
+#[derive(Default)]
+struct Counters {
+    live: AtomicUsize,
+    busy: AtomicUsize,
+    /// Jobs spawned and not yet given to a worker
+    queued: Mutex<usize>,
+    /// Notified when the master gives a job to a worker, to all waiters as their limits differ
+    dequeued: Condvar,
+    completed: AtomicU64,
+    panicked: AtomicU64,
+    /// Of the latest jobs, the oldest first
+    run_times: Mutex<VecDeque<Duration>>,
+}
+
+impl Counters {
+    /// Count the job queued and send it, under the lock so the master can't take it first
+    fn enqueue(
+        &self,
+        mut queued: MutexGuard<usize>,
+        job_dispatcher: &Sender<Message>,
+        job: BoxedJob,
+        priority: Priority,
+    ) -> bool {
+        *queued += 1;
+        if job_dispatcher.send(Message::Job(job, priority)).is_err() {
+            *queued -= 1;
+            return false;
+        }
+        true
+    }
+    fn dequeue(&self) {
+        *self.queued.lock().unwrap() -= 1;
+        self.dequeued.notify_all();
+    }
+    fn finish(&self, run_time: Duration, panicked: bool) {
+        if panicked {
+            self.panicked.fetch_add(1, Ordering::SeqCst);
+        } else {
+            self.completed.fetch_add(1, Ordering::SeqCst);
+        }
+        let mut run_times = self.run_times.lock().unwrap();
+        if run_times.len() == RUN_TIME_SAMPLES {
+            run_times.pop_front();
+        }
+        run_times.push_back(run_time);
+    }
+}
+
 struct WorkerHandle {
     join_handle: JoinHandle<()>,
     job_sender: SyncSender<BoxedJob>,
 }
 
 impl WorkerHandle {
-    fn new(id: usize, waker: SyncSender<usize>) -> Self {
+    fn new(
+        id: usize,
+        waker: Sender<usize>,
+        counters: Arc<Counters>,
+        on_panic: Option<PanicHook>,
+    ) -> Self {
         let (job_sender, job_receiver) = mpsc::sync_channel::<BoxedJob>(1);
-        let join_handle = thread::spawn({
-            move || {
+        let join_handle = thread::Builder::new()
+            .name(format!("kvs-worker-{id}"))
+            .spawn(move || {
                 // thread created and waiting for job
                 if waker.send(id).is_err() {
                     return;
                 }
                 for job in job_receiver {
-                    let job = AssertUnwindSafe(job);
+                    counters.busy.fetch_add(1, Ordering::SeqCst);
+                    let start = Instant::now();
+                    let result = catch_unwind(AssertUnwindSafe(job));
+                    counters.busy.fetch_sub(1, Ordering::SeqCst);
+                    counters.finish(start.elapsed(), result.is_err());
                     // if panicked, just continue and abort the job
-                    if catch_unwind(job).is_err() {
-                        eprintln!(
-                            "thread '{:?}' panicked and recover",
-                            thread::current().name()
-                        );
+                    if let Err(payload) = result {
+                        // A panicking hook mustn't kill the worker, stderr gets the report then
+                        let reported = on_panic.as_ref().is_some_and(|on_panic| {
+                            catch_unwind(AssertUnwindSafe(|| on_panic(&*payload))).is_ok()
+                        });
+                        if !reported {
+                            eprintln!(
+                                "thread '{:?}' panicked and recover: {}",
+                                thread::current().name(),
+                                panic_message(&*payload)
+                            );
+                        }
                     }
                     // thread pool is dropped
                     if waker.send(id).is_err() {
                         return;
                     }
                 }
-            }
-        });
+            })
+            .unwrap();
         WorkerHandle {
             join_handle,
             job_sender,
         }
     }
+    /// The worker exits once its job sender is dropped
+    fn stop(self) {
+        drop(self.job_sender);
+        self.join_handle.join().unwrap();
+    }
 }
 
-impl Drop for SharedQueueThreadPool {
-    fn drop(&mut self) {}
+/// Jobs received by the master and waiting for a worker
+#[derive(Default)]
+struct Lanes {
+    high: VecDeque<BoxedJob>,
+    normal: VecDeque<BoxedJob>,
+    /// High priority jobs taken since the last normal one
+    high_streak: usize,
 }
 
-impl ThreadPool for SharedQueueThreadPool {
-    fn new(n_threads: u32) -> Result<Self> {
-        let n_threads = n_threads as usize;
-        if n_threads == 0 {
-            return Err(Error::ZeroSizedPool);
+impl Lanes {
+    fn push(&mut self, job: BoxedJob, priority: Priority) {
+        match priority {
+            Priority::High => self.high.push_back(job),
+            Priority::Normal => self.normal.push_back(job),
         }
-        let (job_dispatcher, repeater) = mpsc::channel();
-        // this thread(master) will return when job_dispatcher is dropped, aka thread pool is dropped
-        // no need to join it, just detach
-        thread::spawn(move || {
-            // spawn `n_threads` threads
-            let mut threads = Vec::with_capacity(n_threads);
-            let (waker, sleeper) = mpsc::sync_channel(n_threads);
-            for i in 0..n_threads {
-                // worker will waker the master when it is idle
-                let thread_handle = WorkerHandle::new(i, waker.clone());
-                threads.push(thread_handle);
+    }
+    fn is_empty(&self) -> bool {
+        self.high.is_empty() && self.normal.is_empty()
+    }
+    /// Take a high priority job first, unless too many went before a waiting normal one
+    fn pop(&mut self) -> Option<BoxedJob> {
+        if self.high.is_empty()
+            || (self.high_streak >= HIGH_PRIORITY_BURST && !self.normal.is_empty())
+        {
+            self.high_streak = 0;
+            return self.normal.pop_front();
+        }
+        self.high_streak += 1;
+        self.high.pop_front()
+    }
+}
+
+/// Starts, feeds and stops the workers
+struct Master {
+    config: PoolConfig,
+    workers: HashMap<usize, WorkerHandle>,
+    next_id: usize,
+    /// Idle workers and since when, the most recently idle last
+    idle: VecDeque<(usize, Instant)>,
+    waker: Sender<usize>,
+    sleeper: Receiver<usize>,
+    counters: Arc<Counters>,
+}
+
+impl Master {
+    fn start_worker(&mut self) {
+        let id = self.next_id;
+        self.next_id += 1;
+        let worker = WorkerHandle::new(
+            id,
+            self.waker.clone(),
+            Arc::clone(&self.counters),
+            self.config.on_panic.clone(),
+        );
+        self.workers.insert(id, worker);
+        self.counters
+            .live
+            .store(self.workers.len(), Ordering::SeqCst);
+    }
+    fn stop_worker(&mut self, id: usize) {
+        if let Some(worker) = self.workers.remove(&id) {
+            worker.stop();
+        }
+        self.counters
+            .live
+            .store(self.workers.len(), Ordering::SeqCst);
+    }
+    /// Take the workers which became idle, stopping those above the maximum
+    fn collect_idle(&mut self) {
+        while let Ok(id) = self.sleeper.try_recv() {
+            if self.workers.len() > self.config.max_threads {
+                self.stop_worker(id);
+            } else {
+                self.idle.push_back((id, Instant::now()));
+            }
+        }
+    }
+    /// Stop the workers idle for too long, keeping the minimum
+    fn stop_expired(&mut self) {
+        while self.workers.len() > self.config.min_threads {
+            match self.idle.front() {
+                Some(&(id, since)) if since.elapsed() >= self.config.idle_timeout => {
+                    self.idle.pop_front();
+                    self.stop_worker(id);
+                }
+                _ => break,
+            }
+        }
+    }
+    /// Get an idle worker, starting a new one if all are busy and there's room
+    fn idle_worker(&mut self) -> usize {
+        self.collect_idle();
+        // Reuse the most recently idle, so the others can time out
+        if let Some((id, _)) = self.idle.pop_back() {
+            return id;
+        }
+        if self.workers.len() < self.config.max_threads {
+            self.start_worker();
+        }
+        // waker will always dropped after sleeper
+        self.sleeper.recv().unwrap()
+    }
+    fn resize(&mut self, max_threads: usize) {
+        self.config.max_threads = max_threads;
+        self.config.min_threads = self.config.min_threads.min(max_threads);
+        self.collect_idle();
+        while self.workers.len() > max_threads {
+            // Busy workers are stopped by `collect_idle` once they finish
+            let Some((id, _)) = self.idle.pop_front() else {
+                break;
+            };
+            self.stop_worker(id);
+        }
+    }
+    fn receive(&mut self, message: Message, lanes: &mut Lanes) {
+        match message {
+            Message::Job(job, priority) => lanes.push(job, priority),
+            Message::Resize(max_threads) => self.resize(max_threads),
+        }
+    }
+    /// Receive the messages already sent, returning `true` if the pool is dropped
+    fn receive_pending(&mut self, repeater: &Receiver<Message>, lanes: &mut Lanes) -> bool {
+        loop {
+            match repeater.try_recv() {
+                Ok(message) => self.receive(message, lanes),
+                Err(TryRecvError::Empty) => return false,
+                Err(TryRecvError::Disconnected) => return true,
             }
-            // receive job from user, and transmit to idle worker
-            for job in repeater {
-                // waker will always dropped after sleeper
-                let id = sleeper.recv().unwrap();
-                threads[id].job_sender.send(job).unwrap();
+        }
+    }
+    /// Dispatch jobs until the pool is dropped, returning how many were cancelled
+    fn run(mut self, repeater: Receiver<Message>, cancel: Arc<AtomicBool>) -> usize {
+        for _ in 0..self.config.min_threads {
+            self.start_worker();
+        }
+        let mut lanes = Lanes::default();
+        let mut dropped = 0;
+        let mut closed = false;
+        loop {
+            if lanes.is_empty() {
+                if closed {
+                    break;
+                }
+                match repeater.recv_timeout(self.config.idle_timeout) {
+                    Ok(message) => self.receive(message, &mut lanes),
+                    Err(RecvTimeoutError::Timeout) => {
+                        self.collect_idle();
+                        self.stop_expired();
+                        continue;
+                    }
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+                // It may be a resize
+                if lanes.is_empty() {
+                    self.stop_expired();
+                    continue;
+                }
             }
-            // worker thread's loop will be break
-            drop(sleeper);
-            drop(waker);
-            for handle in threads {
-                drop(handle.job_sender);
-                handle.join_handle.join().unwrap();
+            if cancel.load(Ordering::SeqCst) {
+                closed |= self.receive_pending(&repeater, &mut lanes);
+                while lanes.pop().is_some() {
+                    self.counters.dequeue();
+                    dropped += 1;
+                }
+                continue;
             }
+            let id = self.idle_worker();
+            // Jobs sent while waiting for the worker may be of a higher priority
+            closed |= self.receive_pending(&repeater, &mut lanes);
+            let job = lanes.pop().unwrap();
+            self.counters.dequeue();
+            self.workers[&id].job_sender.send(job).unwrap();
+            self.stop_expired();
+        }
+        // worker thread's loop will be break
+        drop(self.sleeper);
+        drop(self.waker);
+        for (_, worker) in self.workers.drain() {
+            worker.stop();
+        }
+        self.counters.live.store(0, Ordering::SeqCst);
+        dropped
+    }
+}
+
+impl SharedQueueThreadPool {
+    /// Start a pool sized by the config
+    pub fn with_config(config: PoolConfig) -> Result<Self> {
+        if config.max_threads == 0 {
+            return Err(Error::ZeroSizedPool);
+        }
+        let config = PoolConfig {
+            min_threads: config.min_threads.min(config.max_threads),
+            ..config
+        };
+        let queue_capacity = config.queue_capacity;
+        let full_queue_policy = config.full_queue_policy;
+        let high_priority_reserve = config.high_priority_reserve;
+        let (job_dispatcher, repeater) = mpsc::channel();
+        let cancel = Arc::new(AtomicBool::new(false));
+        let counters = Arc::new(Counters::default());
+        let (waker, sleeper) = mpsc::channel();
+        let master = Master {
+            config,
+            workers: HashMap::new(),
+            next_id: 0,
+            idle: VecDeque::new(),
+            waker,
+            sleeper,
+            counters: Arc::clone(&counters),
+        };
+        // this thread(master) will return when job_dispatcher is dropped, aka thread pool is dropped
+        let master = thread::spawn({
+            let cancel = Arc::clone(&cancel);
+            move || master.run(repeater, cancel)
         });
-        Ok(Self { job_dispatcher })
+        Ok(Self {
+            job_dispatcher: Some(Arc::new(job_dispatcher)),
+            master: Some(master),
+            cancel,
+            counters,
+            queue_capacity,
+            full_queue_policy,
+            high_priority_reserve,
+        })
+    }
+    /// Queue the job, or give it back if the queue is full and `wait` is false.
+    ///
+    /// High priority jobs may also take the places reserved for them beyond the capacity.
+    fn enqueue<F>(&self, job: F, priority: Priority, wait: bool) -> std::result::Result<(), F>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let Some(job_dispatcher) = &self.job_dispatcher else {
+            return Ok(());
+        };
+        let limit = self.queue_capacity.map(|capacity| match priority {
+            Priority::High => capacity + self.high_priority_reserve,
+            Priority::Normal => capacity,
+        });
+        let mut queued = self.counters.queued.lock().unwrap();
+        while limit.is_some_and(|limit| *queued >= limit) {
+            if !wait {
+                return Err(job);
+            }
+            queued = self.counters.dequeued.wait(queued).unwrap();
+        }
+        self.counters
+            .enqueue(queued, job_dispatcher, Box::new(job), priority);
+        Ok(())
+    }
+    /// Workers alive and busy now
+    pub fn metrics(&self) -> WorkerMetrics {
+        WorkerMetrics {
+            live: self.counters.live.load(Ordering::SeqCst),
+            busy: self.counters.busy.load(Ordering::SeqCst),
+        }
+    }
+    /// Job counts and run times now
+    pub fn stats(&self) -> PoolStats {
+        self.monitor().stats()
+    }
+    /// A handle reading the stats, which doesn't keep the pool alive
+    pub fn monitor(&self) -> PoolMonitor {
+        PoolMonitor {
+            counters: Arc::clone(&self.counters),
+        }
+    }
+    /// Stop taking jobs, and wait for all workers to exit.
+    ///
+    /// Returns the number of queued jobs dropped without running,
+    /// always 0 for `ShutdownMode::Drain`.
+    pub fn shutdown(mut self, mode: ShutdownMode) -> usize {
+        self.stop(mode)
+    }
+    fn stop(&mut self, mode: ShutdownMode) -> usize {
+        if mode == ShutdownMode::Cancel {
+            self.cancel.store(true, Ordering::SeqCst);
+        }
+        // The master exits when it sees the queue closed
+        drop(self.job_dispatcher.take());
+        match self.master.take() {
+            Some(master) => master.join().unwrap(),
+            None => 0,
+        }
+    }
+}
+
+/// Run the queued jobs before returning
+impl Drop for SharedQueueThreadPool {
+    fn drop(&mut self) {
+        self.stop(ShutdownMode::Drain);
+    }
+}
+
+impl ThreadPool for SharedQueueThreadPool {
+    fn new(n_threads: u32) -> Result<Self> {
+        Self::with_config(PoolConfig::new(n_threads))
     }
     fn spawn<F>(&self, job: F)
     where
         F: FnOnce() + Send + 'static,
     {
-        self.job_dispatcher.send(Box::new(job)).unwrap()
+        _ = self.enqueue(job, Priority::Normal, true);
+    }
+    fn spawn_with_priority<F>(&self, priority: Priority, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        _ = self.enqueue(job, priority, true);
+    }
+    fn try_spawn<F>(&self, job: F) -> std::result::Result<(), F>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.try_spawn_with_priority(Priority::Normal, job)
+    }
+    fn try_spawn_with_priority<F>(&self, priority: Priority, job: F) -> std::result::Result<(), F>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        match self.full_queue_policy {
+            FullQueuePolicy::Block => self.enqueue(job, priority, true),
+            FullQueuePolicy::Reject => self.enqueue(job, priority, false),
+            FullQueuePolicy::CallerRuns => self.enqueue(job, priority, false).or_else(|job| {
+                job();
+                Ok(())
+            }),
+        }
+    }
+    /// Busy workers above the new maximum stop once they finish their jobs
+    fn resize(&self, n_threads: u32) -> Result<()> {
+        if n_threads == 0 {
+            return Err(Error::ZeroSizedPool);
+        }
+        if let Some(job_dispatcher) = &self.job_dispatcher {
+            job_dispatcher
+                .send(Message::Resize(n_threads as usize))
+                .unwrap();
+        }
+        Ok(())
+    }
+}
+
+impl Scheduler for SharedQueueThreadPool {
+    /// Timer jobs are queued even beyond the capacity, so the timer never waits
+    fn spawner(&self) -> Spawner {
+        let job_dispatcher = self.job_dispatcher.as_ref().map(Arc::downgrade);
+        let counters = Arc::clone(&self.counters);
+        Spawner::new(move |job| {
+            let Some(job_dispatcher) = job_dispatcher.as_ref().and_then(|weak| weak.upgrade())
+            else {
+                return false;
+            };
+            let queued = counters.queued.lock().unwrap();
+            counters.enqueue(queued, &job_dispatcher, job, Priority::Normal)
+        })
     }
 }
@@ -0,0 +1,173 @@
+use std::{
+    cmp::{Ordering as CmpOrdering, Reverse},
+    collections::BinaryHeap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Condvar, Mutex, OnceLock, TryLockError,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+type BoxedJob = Box<dyn FnOnce() + Send + 'static>;
+
+/// Spawns jobs on a pool from the timer thread, without keeping the pool alive
+pub struct Spawner(Box<dyn Fn(BoxedJob) -> bool + Send>);
+
+impl Spawner {
+    /// `spawn` returns `false` once the pool is dropped, the timer forgets the job then
+    pub(crate) fn new(spawn: impl Fn(BoxedJob) -> bool + Send + 'static) -> Self {
+        Self(Box::new(spawn))
+    }
+}
+
+/// A job scheduled by `Scheduler::schedule_after` or `Scheduler::schedule_every`.
+///
+/// Dropping the handle doesn't cancel the job.
+pub struct TimerHandle {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl TimerHandle {
+    /// Stop the job from being spawned again, a run already spawned still finishes
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+    /// Whether `cancel` was called
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+pub(crate) enum TimerJob {
+    Once(BoxedJob),
+    /// Spawned every interval, skipped if the last run is still running
+    Every(Duration, Arc<Mutex<dyn FnMut() + Send>>),
+}
+
+struct Entry {
+    deadline: Instant,
+    /// Orders entries of the same deadline by when they were scheduled
+    seq: u64,
+    spawner: Spawner,
+    job: TimerJob,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl PartialEq for Entry {
+    fn eq(&self, other: &Self) -> bool {
+        (self.deadline, self.seq) == (other.deadline, other.seq)
+    }
+}
+
+impl Eq for Entry {}
+
+impl PartialOrd for Entry {
+    fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Entry {
+    fn cmp(&self, other: &Self) -> CmpOrdering {
+        (self.deadline, self.seq).cmp(&(other.deadline, other.seq))
+    }
+}
+
+/// One thread for the jobs of all pools, it only spawns them when they're due
+#[derive(Default)]
+struct Timer {
+    queue: Mutex<TimerQueue>,
+    wakeup: Condvar,
+}
+
+#[derive(Default)]
+struct TimerQueue {
+    entries: BinaryHeap<Reverse<Entry>>,
+    next_seq: u64,
+}
+
+impl Timer {
+    fn global() -> &'static Timer {
+        static TIMER: OnceLock<Timer> = OnceLock::new();
+        static STARTED: OnceLock<()> = OnceLock::new();
+        let timer = TIMER.get_or_init(Timer::default);
+        STARTED.get_or_init(|| {
+            thread::Builder::new()
+                .name("kvs-timer".to_owned())
+                .spawn(|| timer.run())
+                .unwrap();
+        });
+        timer
+    }
+    fn push(&self, deadline: Instant, spawner: Spawner, job: TimerJob, cancelled: Arc<AtomicBool>) {
+        let mut queue = self.queue.lock().unwrap();
+        let seq = queue.next_seq;
+        queue.next_seq += 1;
+        queue.entries.push(Reverse(Entry {
+            deadline,
+            seq,
+            spawner,
+            job,
+            cancelled,
+        }));
+        // The new entry may be the first due
+        self.wakeup.notify_one();
+    }
+    fn run(&self) {
+        let mut queue = self.queue.lock().unwrap();
+        loop {
+            let Some(Reverse(first)) = queue.entries.peek() else {
+                queue = self.wakeup.wait(queue).unwrap();
+                continue;
+            };
+            let now = Instant::now();
+            if first.deadline > now {
+                let timeout = first.deadline - now;
+                queue = self.wakeup.wait_timeout(queue, timeout).unwrap().0;
+                continue;
+            }
+            let Reverse(entry) = queue.entries.pop().unwrap();
+            drop(queue);
+            self.fire(entry);
+            queue = self.queue.lock().unwrap();
+        }
+    }
+    /// Spawn the due job, and schedule its next run if it's periodic
+    fn fire(&self, entry: Entry) {
+        if entry.cancelled.load(Ordering::SeqCst) {
+            return;
+        }
+        match entry.job {
+            TimerJob::Once(job) => {
+                (entry.spawner.0)(job);
+            }
+            TimerJob::Every(interval, job) => {
+                let run = Arc::clone(&job);
+                let spawned = (entry.spawner.0)(Box::new(move || match run.try_lock() {
+                    Ok(mut run) => run(),
+                    // A panic of the last run doesn't stop the next ones
+                    Err(TryLockError::Poisoned(poisoned)) => poisoned.into_inner()(),
+                    Err(TryLockError::WouldBlock) => {}
+                }));
+                if spawned {
+                    // Missed runs are skipped rather than spawned in a burst
+                    let deadline = (entry.deadline + interval).max(Instant::now());
+                    self.push(
+                        deadline,
+                        entry.spawner,
+                        TimerJob::Every(interval, job),
+                        entry.cancelled,
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Spawn the job with the spawner once the deadline passes
+pub(crate) fn schedule(deadline: Instant, spawner: Spawner, job: TimerJob) -> TimerHandle {
+    let cancelled = Arc::new(AtomicBool::new(false));
+    Timer::global().push(deadline, spawner, job, Arc::clone(&cancelled));
+    TimerHandle { cancelled }
+}
@@ -0,0 +1,194 @@
+use std::{
+    cell::RefCell,
+    iter,
+    panic::{catch_unwind, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    thread::{self, JoinHandle},
+};
+
+use crossbeam_deque::{Injector, Stealer, Worker};
+
+use super::{Scheduler, Spawner, ThreadPool};
+
+use crate::{Error, Result};
+
+type BoxedJob = Box<dyn FnOnce() + Send + 'static>;
+
+/// A thread pool where each worker has its own deque, and steals from the others when idle.
+///
+/// Jobs spawned by a worker go to its own deque, others go to a shared injector.
+/// There's no master thread, so spawning never waits for a worker.
+pub struct WorkStealingThreadPool {
+    shared: Arc<Shared>,
+    handles: Vec<JoinHandle<()>>,
+}
+
+struct Shared {
+    injector: Injector<BoxedJob>,
+    stealers: Vec<Stealer<BoxedJob>>,
+    /// Jobs spawned and not yet taken by a worker
+    pending: AtomicUsize,
+    /// Workers waiting on `wakeup`
+    sleeping: AtomicUsize,
+    lock: Mutex<()>,
+    wakeup: Condvar,
+    shutdown: AtomicBool,
+}
+
+thread_local! {
+    /// The pool the current thread works for, and its deque
+    static LOCAL: RefCell<Option<(*const Shared, Worker<BoxedJob>)>> = const { RefCell::new(None) };
+}
+
+impl Shared {
+    /// Take a job from the own deque, then the injector, then the other workers
+    fn find_job(&self, local: &Worker<BoxedJob>) -> Option<BoxedJob> {
+        let job = local.pop().or_else(|| {
+            iter::repeat_with(|| {
+                self.injector
+                    .steal_batch_and_pop(local)
+                    .or_else(|| self.stealers.iter().map(Stealer::steal).collect())
+            })
+            .find(|steal| !steal.is_retry())
+            .and_then(|steal| steal.success())
+        })?;
+        self.pending.fetch_sub(1, Ordering::SeqCst);
+        Some(job)
+    }
+    /// Wait until there may be a job, return `false` if the pool is dropped and drained
+    fn sleep(&self) -> bool {
+        let mut guard = self.lock.lock().unwrap();
+        self.sleeping.fetch_add(1, Ordering::SeqCst);
+        while self.pending.load(Ordering::SeqCst) == 0 {
+            if self.shutdown.load(Ordering::SeqCst) {
+                self.sleeping.fetch_sub(1, Ordering::SeqCst);
+                return false;
+            }
+            guard = self.wakeup.wait(guard).unwrap();
+        }
+        self.sleeping.fetch_sub(1, Ordering::SeqCst);
+        true
+    }
+    /// Wake a sleeping worker for a new job.
+    ///
+    /// A worker counts itself sleeping before it checks `pending`,
+    /// so either it sees the job or it's seen here.
+    fn wake_one(&self) {
+        if self.sleeping.load(Ordering::SeqCst) > 0 {
+            let _guard = self.lock.lock().unwrap();
+            self.wakeup.notify_one();
+        }
+    }
+}
+
+fn run_worker(shared: Arc<Shared>, local: Worker<BoxedJob>) {
+    LOCAL.with(|cell| *cell.borrow_mut() = Some((Arc::as_ptr(&shared), local)));
+    loop {
+        let job = LOCAL.with(|cell| shared.find_job(&cell.borrow().as_ref().unwrap().1));
+        match job {
+            Some(job) => {
+                // if panicked, just continue and abort the job
+                if catch_unwind(AssertUnwindSafe(job)).is_err() {
+                    eprintln!(
+                        "thread '{:?}' panicked and recover",
+                        thread::current().name()
+                    );
+                }
+            }
+            None => {
+                if !shared.sleep() {
+                    break;
+                }
+            }
+        }
+    }
+    LOCAL.with(|cell| cell.borrow_mut().take());
+}
+
+impl ThreadPool for WorkStealingThreadPool {
+    fn new(n_threads: u32) -> Result<Self> {
+        if n_threads == 0 {
+            return Err(Error::ZeroSizedPool);
+        }
+        let locals = (0..n_threads)
+            .map(|_| Worker::new_fifo())
+            .collect::<Vec<_>>();
+        let shared = Arc::new(Shared {
+            injector: Injector::new(),
+            stealers: locals.iter().map(Worker::stealer).collect(),
+            pending: AtomicUsize::new(0),
+            sleeping: AtomicUsize::new(0),
+            lock: Mutex::new(()),
+            wakeup: Condvar::new(),
+            shutdown: AtomicBool::new(false),
+        });
+        let handles = locals
+            .into_iter()
+            .enumerate()
+            .map(|(id, local)| {
+                let shared = Arc::clone(&shared);
+                thread::Builder::new()
+                    .name(format!("kvs-worker-{id}"))
+                    .spawn(move || run_worker(shared, local))
+                    .unwrap()
+            })
+            .collect();
+        Ok(Self { shared, handles })
+    }
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        push_job(&self.shared, Box::new(job));
+    }
+}
+
+impl Scheduler for WorkStealingThreadPool {
+    fn spawner(&self) -> Spawner {
+        let shared = Arc::downgrade(&self.shared);
+        Spawner::new(move |job| match shared.upgrade() {
+            Some(shared) if !shared.shutdown.load(Ordering::SeqCst) => {
+                push_job(&shared, job);
+                true
+            }
+            _ => false,
+        })
+    }
+}
+
+/// Push the job to the deque of the current worker, or the injector from other threads
+fn push_job(shared: &Arc<Shared>, job: BoxedJob) {
+    shared.pending.fetch_add(1, Ordering::SeqCst);
+    let job = LOCAL.with(|cell| match &*cell.borrow() {
+        Some((pool, local)) if *pool == Arc::as_ptr(shared) => {
+            local.push(job);
+            None
+        }
+        _ => Some(job),
+    });
+    if let Some(job) = job {
+        shared.injector.push(job);
+    }
+    shared.wake_one();
+}
+
+/// Run the spawned jobs, then stop the workers
+impl Drop for WorkStealingThreadPool {
+    fn drop(&mut self) {
+        {
+            let _guard = self.shared.lock.lock().unwrap();
+            self.shared.shutdown.store(true, Ordering::SeqCst);
+            self.shared.wakeup.notify_all();
+        }
+        let current = thread::current().id();
+        for handle in self.handles.drain(..) {
+            // A job may drop the pool, its own worker can't be joined
+            if handle.thread().id() != current {
+                _ = handle.join();
+            }
+        }
+    }
+}
@@ -0,0 +1,60 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::{Error, KvsEngine, Result};
+
+/// How many times `KvsEngine::transaction` runs the body before giving up
+pub(crate) const MAX_TRANSACTION_RETRIES: usize = 64;
+
+/// Versions of the keys read by a transaction, `None` for absent keys
+pub type ReadSet = HashMap<String, Option<u64>>;
+/// Buffered writes of a transaction, `None` for removal
+pub type WriteSet = BTreeMap<String, Option<String>>;
+
+/// An optimistic transaction.
+///
+/// Reads go to the engine and remember the version of the key,
+/// writes are buffered until `commit`, which fails if any read key has changed since.
+pub struct Transaction<'a, E> {
+    engine: &'a E,
+    reads: ReadSet,
+    writes: WriteSet,
+}
+
+impl<'a, E: KvsEngine> Transaction<'a, E> {
+    /// Begin a transaction on the engine
+    pub fn new(engine: &'a E) -> Self {
+        Self {
+            engine,
+            reads: HashMap::new(),
+            writes: BTreeMap::new(),
+        }
+    }
+    /// get the value the `key` corresponding to, including writes of this transaction
+    pub fn get(&mut self, key: &str) -> Result<Option<String>> {
+        if let Some(value) = self.writes.get(key) {
+            return Ok(value.clone());
+        }
+        let read = self.engine.get_versioned(key)?;
+        // Keep the first version seen, a different one later means conflict anyway
+        self.reads
+            .entry(key.to_owned())
+            .or_insert(read.as_ref().map(|(_, version)| *version));
+        Ok(read.map(|(value, _)| value))
+    }
+    /// Set the value corresponding to key to `value` when committed
+    pub fn set(&mut self, key: String, value: String) {
+        self.writes.insert(key, Some(value));
+    }
+    /// Remove the key when committed
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        if self.get(&key)?.is_none() {
+            return Err(Error::RemoveNonexistKey);
+        }
+        self.writes.insert(key, None);
+        Ok(())
+    }
+    /// Apply the writes atomically, return `false` if it conflicts with others
+    pub fn commit(self) -> Result<bool> {
+        self.engine.commit(&self.reads, self.writes)
+    }
+}
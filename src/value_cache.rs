@@ -0,0 +1,129 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{
+        atomic::{AtomicU32, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use crate::{Command, Result};
+
+/// Shards of the cache, each with its own lock
+const SHARDS: usize = 16;
+
+/// Hit and miss counters of the value cache of a `KvStore`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ValueCacheStats {
+    /// Reads served from memory
+    pub hits: u64,
+    /// Reads which went to disk
+    pub misses: u64,
+}
+
+/// Position of a record in the data files
+type RecordId = (u32, u32);
+
+/// LRU cache of decoded records, keyed by their position in the data files.
+///
+/// A write goes to a new position, so the records of older writes are never read again
+/// and just age out. Compaction rewrites all records, the cache is cleared then.
+pub(crate) struct ValueCache {
+    shards: Vec<Mutex<LruShard>>,
+    /// The `global_version` of the store the cache was last cleared at
+    version: AtomicU32,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+struct LruShard {
+    capacity: usize,
+    records: HashMap<RecordId, (Arc<Command>, u64)>,
+    /// Map from the last use to the record, the first is the least recently used
+    order: BTreeMap<u64, RecordId>,
+    clock: u64,
+}
+
+impl ValueCache {
+    /// Keep at most about `capacity` records
+    pub(crate) fn new(capacity: usize) -> Self {
+        let shard_capacity = capacity.div_ceil(SHARDS).max(1);
+        Self {
+            shards: (0..SHARDS)
+                .map(|_| {
+                    Mutex::new(LruShard {
+                        capacity: shard_capacity,
+                        records: HashMap::new(),
+                        order: BTreeMap::new(),
+                        clock: 0,
+                    })
+                })
+                .collect(),
+            version: AtomicU32::new(0),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+    /// Get the record, or read it with `read` and keep it.
+    ///
+    /// `global_version` is the store's, all records are dropped once it's increased.
+    pub(crate) fn get_or_read(
+        &self,
+        id: RecordId,
+        global_version: u32,
+        read: impl FnOnce() -> Result<Command>,
+    ) -> Result<Arc<Command>> {
+        if self.version.fetch_max(global_version, Ordering::SeqCst) < global_version {
+            for shard in &self.shards {
+                shard.lock().unwrap().clear();
+            }
+        }
+        let shard = &self.shards[shard_of(id)];
+        if let Some(record) = shard.lock().unwrap().get(id) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(record);
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        // Read without the lock, two readers of the same record may both read it
+        let record = Arc::new(read()?);
+        shard.lock().unwrap().insert(id, Arc::clone(&record));
+        Ok(record)
+    }
+    /// Counters since the store was opened
+    pub(crate) fn stats(&self) -> ValueCacheStats {
+        ValueCacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+}
+
+fn shard_of((file_id, file_offset): RecordId) -> usize {
+    let hash = ((file_id as u64) << 32 | file_offset as u64).wrapping_mul(0x9e3779b97f4a7c15);
+    (hash >> 32) as usize % SHARDS
+}
+
+impl LruShard {
+    fn get(&mut self, id: RecordId) -> Option<Arc<Command>> {
+        self.clock += 1;
+        let (record, last_used) = self.records.get_mut(&id)?;
+        self.order.remove(last_used);
+        *last_used = self.clock;
+        self.order.insert(self.clock, id);
+        Some(Arc::clone(record))
+    }
+    fn insert(&mut self, id: RecordId, record: Arc<Command>) {
+        self.clock += 1;
+        if let Some((_, last_used)) = self.records.insert(id, (record, self.clock)) {
+            self.order.remove(&last_used);
+        }
+        self.order.insert(self.clock, id);
+        while self.records.len() > self.capacity {
+            let (_, oldest) = self.order.pop_first().unwrap();
+            self.records.remove(&oldest);
+        }
+    }
+    fn clear(&mut self) {
+        self.records.clear();
+        self.order.clear();
+    }
+}
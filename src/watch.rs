@@ -0,0 +1,51 @@
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+
+/// Events a watcher may leave unreceived, a watcher lagging further behind is dropped
+const WATCH_CAPACITY: usize = 1024;
+
+/// A change of a key, see `KvsEngine::watch`
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event {
+    /// The key is set to the value
+    Set(String, String),
+    /// The key is removed
+    Rm(String),
+}
+
+/// Senders of the receivers returned by `KvsEngine::watch`, with the prefixes they watch
+#[derive(Default)]
+pub(crate) struct Watchers {
+    senders: Vec<(String, SyncSender<Event>)>,
+}
+
+impl Watchers {
+    /// Watch the keys starting with `prefix`
+    pub(crate) fn add(&mut self, prefix: &str) -> Receiver<Event> {
+        let (sender, receiver) = mpsc::sync_channel(WATCH_CAPACITY);
+        self.senders.push((prefix.to_owned(), sender));
+        receiver
+    }
+    /// Send the event of `key` to its watchers, and forget those whose receiver is dropped.
+    ///
+    /// Writers never wait for watchers, a full watcher is forgotten too,
+    /// its receiver is disconnected after the events it holds.
+    /// `event` is only called if the key is watched.
+    pub(crate) fn notify(&mut self, key: &str, event: impl FnOnce() -> Event) {
+        let mut event = Some(event);
+        let mut made = None;
+        self.senders.retain(|(prefix, sender)| {
+            if !key.starts_with(prefix.as_str()) {
+                return true;
+            }
+            let event = made.get_or_insert_with(|| event.take().unwrap()());
+            match sender.try_send(event.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    log::warn!("Watcher of prefix {prefix:?} lags behind, dropped");
+                    false
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            }
+        });
+    }
+}
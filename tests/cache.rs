@@ -0,0 +1,215 @@
+use kvs::{
+    CacheConfig, CacheEngine, Error, EvictionPolicy, KvStore, KvsEngine, KvsSnapshot, MemKvsEngine,
+    Result, Transaction,
+};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+/// Each pair of these tests takes 64 bytes of overhead, plus 4 of key and 4 of value
+const PAIR_SIZE: u64 = 72;
+
+fn cache(pairs: u64, config: impl FnOnce(CacheConfig) -> CacheConfig) -> CacheEngine<MemKvsEngine> {
+    CacheEngine::new(
+        MemKvsEngine::new(),
+        config(CacheConfig::new(pairs * PAIR_SIZE)),
+    )
+    .unwrap()
+}
+
+// The least recently read or written key should be evicted first
+#[test]
+fn evict_lru() -> Result<()> {
+    let cache = cache(3, |config| config);
+    cache.set("key1".to_owned(), "val1".to_owned())?;
+    cache.set("key2".to_owned(), "val2".to_owned())?;
+    cache.set("key3".to_owned(), "val3".to_owned())?;
+    assert_eq!(cache.get("key1")?, Some("val1".to_owned()));
+    cache.set("key4".to_owned(), "val4".to_owned())?;
+
+    assert_eq!(cache.get("key2")?, None);
+    assert_eq!(cache.scan("")?.len(), 3);
+    let stats = cache.cache_stats()?;
+    assert_eq!(stats.evictions, 1);
+    assert_eq!(stats.keys, 3);
+    assert_eq!(stats.used_memory, 3 * PAIR_SIZE);
+    assert_eq!((stats.hits, stats.misses), (1, 1));
+    Ok(())
+}
+
+// A transaction should not evict the keys it read, which would fail its own commit
+#[test]
+fn evict_not_read_by_transaction() -> Result<()> {
+    let cache = cache(3, |config| config);
+    cache.set("key1".to_owned(), "val1".to_owned())?;
+    cache.set("key2".to_owned(), "val2".to_owned())?;
+    cache.set("key3".to_owned(), "val3".to_owned())?;
+
+    let mut tx = Transaction::new(&cache);
+    assert_eq!(tx.get("key1")?, Some("val1".to_owned()));
+    tx.set("key4".to_owned(), "val4".to_owned());
+    // key1 is the least recently used
+    cache.get("key2")?;
+    cache.get("key3")?;
+    assert!(tx.commit()?);
+
+    assert_eq!(cache.get("key1")?, Some("val1".to_owned()));
+    assert_eq!(cache.get("key2")?, None);
+    assert_eq!(cache.get("key4")?, Some("val4".to_owned()));
+    Ok(())
+}
+
+// A transaction failing validation should not evict anything
+#[test]
+fn evict_after_commit() -> Result<()> {
+    let cache = cache(3, |config| config);
+    cache.set("key1".to_owned(), "val1".to_owned())?;
+    cache.set("key2".to_owned(), "val2".to_owned())?;
+    cache.set("key3".to_owned(), "val3".to_owned())?;
+
+    let mut tx = Transaction::new(&cache);
+    assert_eq!(tx.get("key1")?, Some("val1".to_owned()));
+    tx.set("key4".to_owned(), "val4".to_owned());
+    cache.set("key1".to_owned(), "val5".to_owned())?;
+    assert!(!tx.commit()?);
+
+    assert_eq!(cache.cache_stats()?.evictions, 0);
+    assert_eq!(cache.get("key2")?, Some("val2".to_owned()));
+    assert_eq!(cache.get("key4")?, None);
+    Ok(())
+}
+
+// The least frequently used key should be evicted first
+#[test]
+fn evict_lfu() -> Result<()> {
+    let cache = cache(3, |config| config.policy(EvictionPolicy::Lfu));
+    cache.set("key1".to_owned(), "val1".to_owned())?;
+    cache.set("key2".to_owned(), "val2".to_owned())?;
+    cache.set("key3".to_owned(), "val3".to_owned())?;
+    cache.get("key1")?;
+    cache.get("key1")?;
+    cache.get("key3")?;
+    cache.set("key4".to_owned(), "val4".to_owned())?;
+    assert_eq!(cache.get("key2")?, None);
+
+    // key4 is used once, key3 twice
+    cache.set("key5".to_owned(), "val5".to_owned())?;
+    assert_eq!(cache.get("key4")?, None);
+    assert_eq!(cache.get("key1")?, Some("val1".to_owned()));
+    assert_eq!(cache.get("key3")?, Some("val3".to_owned()));
+    Ok(())
+}
+
+// Keys should be gone after their TTL, also from scans
+#[test]
+fn expire_ttl() -> Result<()> {
+    let cache = cache(10, |config| config);
+    cache.set_with_ttl(
+        "key1".to_owned(),
+        "val1".to_owned(),
+        Duration::from_millis(50),
+    )?;
+    cache.set_with_ttl(
+        "key2".to_owned(),
+        "val2".to_owned(),
+        Duration::from_millis(50),
+    )?;
+    cache.set_with_ttl(
+        "key3".to_owned(),
+        "val3".to_owned(),
+        Duration::from_millis(50),
+    )?;
+    cache.set("key3".to_owned(), "val4".to_owned())?;
+    assert_eq!(cache.get("key1")?, Some("val1".to_owned()));
+
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(cache.get("key1")?, None);
+    assert!(matches!(
+        cache.remove("key2".to_owned()),
+        Err(Error::RemoveNonexistKey)
+    ));
+    assert_eq!(
+        cache.scan("")?,
+        vec![("key3".to_owned(), "val4".to_owned())]
+    );
+    assert_eq!(cache.cache_stats()?.expirations, 2);
+    Ok(())
+}
+
+// Expired keys should be gone from snapshots and from reads of past writes
+#[test]
+fn expire_ttl_snapshot_and_history() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let cache = CacheEngine::new(KvStore::open(temp_dir.path())?, CacheConfig::new(1 << 20))?;
+    cache.set_with_ttl(
+        "key1".to_owned(),
+        "val1".to_owned(),
+        Duration::from_millis(50),
+    )?;
+    let snapshot = cache.snapshot()?;
+    assert_eq!(snapshot.get("key1")?, Some("val1".to_owned()));
+
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(snapshot.get("key1")?, None);
+    assert_eq!(snapshot.scan("")?, Vec::new());
+    let history = cache.history("key1", 10)?;
+    assert_eq!(history.len(), 2);
+    let (seq, value) = history[0].clone();
+    assert_eq!(value, None);
+    assert_eq!(cache.get_at("key1", seq)?, None);
+    assert_eq!(cache.cache_stats()?.expirations, 1);
+    Ok(())
+}
+
+// Only keys with a TTL should be evicted, writes fail if there's none
+#[test]
+fn evict_ttl_only() -> Result<()> {
+    let cache = cache(2, CacheConfig::ttl_only);
+    cache.set("key1".to_owned(), "val1".to_owned())?;
+    cache.set_with_ttl(
+        "key2".to_owned(),
+        "val2".to_owned(),
+        Duration::from_secs(60),
+    )?;
+    cache.get("key2")?;
+    cache.set("key3".to_owned(), "val3".to_owned())?;
+    assert_eq!(cache.get("key1")?, Some("val1".to_owned()));
+    assert_eq!(cache.get("key2")?, None);
+
+    assert!(matches!(
+        cache.set("key4".to_owned(), "val4".to_owned()),
+        Err(Error::CacheFull)
+    ));
+    assert_eq!(cache.get("key4")?, None);
+    // Overwriting doesn't need more room
+    cache.set("key3".to_owned(), "val5".to_owned())?;
+
+    // Transactions make room the same way
+    assert!(matches!(
+        cache.transaction(|tx| {
+            tx.set("key5".to_owned(), "val5".to_owned());
+            Ok(())
+        }),
+        Err(Error::CacheFull)
+    ));
+    cache.transaction(|tx| {
+        tx.remove("key1".to_owned())?;
+        tx.set("key5".to_owned(), "val5".to_owned());
+        Ok(())
+    })?;
+    assert_eq!(cache.get("key5")?, Some("val5".to_owned()));
+    Ok(())
+}
+
+// Engines without a budget can't expire keys
+#[test]
+fn ttl_unsupported() {
+    assert!(matches!(
+        MemKvsEngine::new().set_with_ttl(
+            "key".to_owned(),
+            "value".to_owned(),
+            Duration::from_secs(1)
+        ),
+        Err(Error::TtlUnsupported)
+    ));
+}
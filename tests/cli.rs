@@ -1,6 +1,9 @@
 use assert_cmd::prelude::*;
+use kvs::{Event, HashRing, KvStore, KvsClient, KvsEngine, Request, Response};
+use predicates::prelude::*;
 use predicates::str::{contains, is_empty};
 use std::fs::{self, File};
+use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::sync::mpsc;
 use std::thread;
@@ -335,3 +338,787 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+#[test]
+fn cli_load_and_dump() {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4006";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    fs::write(
+        temp_dir.path().join("data.jsonl"),
+        "{\"key\":\"key1\",\"value\":\"value1\"}\n\
+         {\"key\":\"key2\",\"value\":\"value2\"}\n",
+    )
+    .unwrap();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["load", "data.jsonl", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    // The bad record is skipped, and the exit code reports it
+    fs::write(
+        temp_dir.path().join("data.csv"),
+        "key3,\"value,3\"\nbad\nother,value4\n",
+    )
+    .unwrap();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["load", "data.csv", "--batch-size", "1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("1 failed"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["dump", "--prefix", "key", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(
+            "{\"key\":\"key1\",\"value\":\"value1\"}\n\
+             {\"key\":\"key2\",\"value\":\"value2\"}\n\
+             {\"key\":\"key3\",\"value\":\"value,3\"}\n",
+        );
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["dump", "--format", "csv", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("key1,value1\nkey2,value2\nkey3,\"value,3\"\nother,value4\n");
+
+    // Dumped a page at a time, with progress
+    let records = (0..2500)
+        .map(|i| format!("page{i:04},value{i}\n"))
+        .collect::<String>();
+    fs::write(temp_dir.path().join("pages.csv"), &records).unwrap();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["load", "pages.csv", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&[
+            "dump", "--prefix", "page", "--format", "csv", "--addr", addr,
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(records)
+        .stderr(contains("Dumped 2000 records").and(contains("Dumped 2500 records")));
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+#[test]
+fn tool_cli_inspect() {
+    let temp_dir = TempDir::new().unwrap();
+    File::create(temp_dir.path().join("kvs")).unwrap();
+    fs::write(
+        temp_dir.path().join("0.dat"),
+        "{\"Set\":{\"key\":\"key1\",\"value\":\"value1\"}}\
+         {\"Set\":{\"key\":\"key2\",\"value\":\"value2\"}}",
+    )
+    .unwrap();
+    fs::write(
+        temp_dir.path().join("1.dat"),
+        "{\"Set\":{\"key\":\"key1\",\"value\":\"value3\"}}{\"Rm\":{\"key\":\"key2\"}}",
+    )
+    .unwrap();
+
+    Command::cargo_bin("kvs-tool")
+        .unwrap()
+        .args(&["verify", "."])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("4 records ok"));
+
+    Command::cargo_bin("kvs-tool")
+        .unwrap()
+        .args(&["stats", "."])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("keys: 1"))
+        .stdout(contains("key1"))
+        .stdout(contains("key2").not());
+
+    // A stale copy left by an interrupted compaction doesn't bring a removed key back
+    let stale_dir = TempDir::new().unwrap();
+    File::create(stale_dir.path().join("kvs")).unwrap();
+    fs::write(
+        stale_dir.path().join("0.dat"),
+        "{\"Set\":{\"key\":\"key1\",\"value\":\"value1\",\"seq\":1}}\
+         {\"Rm\":{\"key\":\"key1\",\"seq\":2}}",
+    )
+    .unwrap();
+    fs::write(
+        stale_dir.path().join("1.dat"),
+        "{\"Set\":{\"key\":\"key1\",\"value\":\"value1\",\"seq\":1}}",
+    )
+    .unwrap();
+    Command::cargo_bin("kvs-tool")
+        .unwrap()
+        .args(&["stats", "."])
+        .current_dir(&stale_dir)
+        .assert()
+        .success()
+        .stdout(contains("keys: 0"));
+
+    Command::cargo_bin("kvs-tool")
+        .unwrap()
+        .args(&["dump", "."])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(
+            "0 0 39 {\"Set\":{\"key\":\"key1\",\"value\":\"value1\"}}\n\
+             0 39 39 {\"Set\":{\"key\":\"key2\",\"value\":\"value2\"}}\n\
+             1 0 39 {\"Set\":{\"key\":\"key1\",\"value\":\"value3\"}}\n\
+             1 39 21 {\"Rm\":{\"key\":\"key2\"}}\n",
+        );
+
+    // Append a torn record
+    let mut content = fs::read_to_string(temp_dir.path().join("1.dat")).unwrap();
+    content.push_str("{\"Set\":{\"key\":\"ke");
+    fs::write(temp_dir.path().join("1.dat"), content).unwrap();
+    Command::cargo_bin("kvs-tool")
+        .unwrap()
+        .args(&["verify", "."])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(contains(
+            "1.dat: bad record at offset 60, 17 bytes unreadable",
+        ));
+
+    // Not a kvs directory
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-tool")
+        .unwrap()
+        .args(&["verify", "."])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
+#[test]
+fn tool_cli_migrate() {
+    let temp_dir = TempDir::new().unwrap();
+    let kvs_dir = temp_dir.path().join("kvs_data");
+    fs::create_dir(&kvs_dir).unwrap();
+    File::create(kvs_dir.join("kvs")).unwrap();
+    fs::write(
+        kvs_dir.join("0.dat"),
+        "{\"Set\":{\"key\":\"key1\",\"value\":\"value1\"}}\
+         {\"Set\":{\"key\":\"key2\",\"value\":\"value2\"}}\
+         {\"Rm\":{\"key\":\"key1\"}}",
+    )
+    .unwrap();
+    let source_content = dir_content(&kvs_dir);
+
+    Command::cargo_bin("kvs-tool")
+        .unwrap()
+        .args(&[
+            "migrate",
+            "--from",
+            "kvs:kvs_data",
+            "--to",
+            "sled:sled_data",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Migrated 1 keys"));
+    // The source is not written
+    assert_eq!(dir_content(&kvs_dir), source_content);
+
+    // Refuse a non-empty destination
+    Command::cargo_bin("kvs-tool")
+        .unwrap()
+        .args(&[
+            "migrate",
+            "--from",
+            "kvs:kvs_data",
+            "--to",
+            "sled:sled_data",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+    // Refuse a source of the wrong engine
+    Command::cargo_bin("kvs-tool")
+        .unwrap()
+        .args(&["migrate", "--from", "kvs:sled_data", "--to", "sled:other"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    let source_content = dir_content(&temp_dir.path().join("sled_data"));
+    Command::cargo_bin("kvs-tool")
+        .unwrap()
+        .args(&[
+            "migrate",
+            "--from",
+            "sled:sled_data",
+            "--to",
+            "kvs:kvs_back",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Migrated 1 keys"));
+    assert_eq!(
+        dir_content(&temp_dir.path().join("sled_data")),
+        source_content
+    );
+    Command::cargo_bin("kvs-tool")
+        .unwrap()
+        .args(&["dump", "kvs_back"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("0 0 47 {\"Set\":{\"key\":\"key2\",\"value\":\"value2\",\"seq\":1}}\n");
+
+    // More keys than a page, with overwrites and a transaction
+    let many_dir = temp_dir.path().join("many");
+    fs::create_dir(&many_dir).unwrap();
+    File::create(many_dir.join("kvs")).unwrap();
+    let mut content = String::new();
+    for i in 0..2500 {
+        content += &format!("{{\"Set\":{{\"key\":\"key{i}\",\"value\":\"old\"}}}}");
+    }
+    content += "{\"Txn\":[{\"Set\":{\"key\":\"key0\",\"value\":\"a\"}},\
+                {\"Set\":{\"key\":\"key0\",\"value\":\"b\"}},\
+                {\"Rm\":{\"key\":\"key1\"}}]}";
+    fs::write(many_dir.join("0.dat"), content).unwrap();
+    Command::cargo_bin("kvs-tool")
+        .unwrap()
+        .args(&["migrate", "--from", "kvs:many", "--to", "sled:many_sled"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Migrated 2499 keys"));
+    Command::cargo_bin("kvs-tool")
+        .unwrap()
+        .args(&[
+            "migrate",
+            "--from",
+            "sled:many_sled",
+            "--to",
+            "kvs:many_back",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Migrated 2499 keys"));
+    let store = KvStore::open(temp_dir.path().join("many_back")).unwrap();
+    assert_eq!(store.get("key0").unwrap(), Some("b".to_owned()));
+    assert_eq!(store.get("key1").unwrap(), None);
+    assert_eq!(store.get("key2499").unwrap(), Some("old".to_owned()));
+}
+
+/// Paths and contents of all files under the directory
+fn dir_content(dir: &Path) -> Vec<(PathBuf, Vec<u8>)> {
+    let mut content = Vec::new();
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            content.extend(dir_content(&path));
+        } else {
+            content.push((path.clone(), fs::read(path).unwrap()));
+        }
+    }
+    content.sort();
+    content
+}
+
+#[test]
+fn cli_transaction() {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4007";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    let addr = addr.parse().unwrap();
+    let mut client1 = KvsClient::new(addr);
+    let mut client2 = KvsClient::new(addr);
+    let set = |key: &str, value: &str| Request::Set(key.to_owned(), value.to_owned());
+    let get = |key: &str| Request::Get(key.to_owned());
+
+    assert_eq!(
+        client1.request(set("key1", "value1")).unwrap(),
+        Response::Ok
+    );
+    assert_eq!(client1.request(Request::Begin).unwrap(), Response::Ok);
+    assert_eq!(
+        client1.request(get("key1")).unwrap(),
+        Response::Value("value1".to_owned())
+    );
+    assert_eq!(
+        client1.request(set("key2", "value2")).unwrap(),
+        Response::Ok
+    );
+    // Writes are invisible to others before committing
+    assert_eq!(client2.request(get("key2")).unwrap(), Response::NoKey);
+    assert_eq!(client1.request(Request::Commit).unwrap(), Response::Ok);
+    assert_eq!(
+        client2.request(get("key2")).unwrap(),
+        Response::Value("value2".to_owned())
+    );
+
+    assert_eq!(client1.request(Request::Begin).unwrap(), Response::Ok);
+    client1.request(get("key1")).unwrap();
+    assert_eq!(
+        client1.request(set("key1", "value3")).unwrap(),
+        Response::Ok
+    );
+    assert_eq!(
+        client2.request(set("key1", "value4")).unwrap(),
+        Response::Ok
+    );
+    assert_eq!(
+        client1.request(Request::Commit).unwrap(),
+        Response::Conflict
+    );
+    assert_eq!(
+        client1.request(get("key1")).unwrap(),
+        Response::Value("value4".to_owned())
+    );
+
+    assert_eq!(client1.request(Request::Begin).unwrap(), Response::Ok);
+    assert_eq!(
+        client1.request(set("key1", "value5")).unwrap(),
+        Response::Ok
+    );
+    assert_eq!(client1.request(Request::Abort).unwrap(), Response::Ok);
+    assert_eq!(
+        client1.request(get("key1")).unwrap(),
+        Response::Value("value4".to_owned())
+    );
+    assert_eq!(client1.request(Request::Commit).unwrap(), Response::Err);
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+#[test]
+fn cli_history() {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4008";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    for args in [
+        ["set", "key1", "value1"].as_slice(),
+        &["set", "key1", "value2"],
+        &["rm", "key1"],
+    ] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(args)
+            .args(&["--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+    }
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["history", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("3 (removed)\n2 value2\n1 value1\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get-at", "key1", "1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get-at", "key1", "3", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("Key not found\n");
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+#[test]
+fn cli_watch() {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4009";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    let addr = addr.parse().unwrap();
+    let mut watcher = KvsClient::new(addr);
+    let events = watcher.watch("key".to_owned()).unwrap();
+    let mut client = KvsClient::new(addr);
+    for request in [
+        Request::Set("key1".to_owned(), "value1".to_owned()),
+        Request::Set("other".to_owned(), "value2".to_owned()),
+        Request::Rm("key1".to_owned()),
+    ] {
+        assert_eq!(client.request(request).unwrap(), Response::Ok);
+    }
+    assert_eq!(
+        events.take(2).collect::<kvs::Result<Vec<_>>>().unwrap(),
+        vec![
+            Event::Set("key1".to_owned(), "value1".to_owned()),
+            Event::Rm("key1".to_owned()),
+        ]
+    );
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+#[test]
+fn cli_replication() {
+    let primary_dir = TempDir::new().unwrap();
+    let replica_dir = TempDir::new().unwrap();
+    let primary_addr = "127.0.0.1:4010";
+    let replica_addr = "127.0.0.1:4011";
+    let spawn_replica = || {
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(&["--addr", replica_addr, "--replica-of", primary_addr])
+            .current_dir(&replica_dir)
+            .spawn()
+            .unwrap()
+    };
+    let mut primary_child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--engine", "kvs", "--addr", primary_addr])
+        .current_dir(&primary_dir)
+        .spawn()
+        .unwrap();
+    let mut replica_child = spawn_replica();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut primary = KvsClient::new(primary_addr.parse().unwrap());
+    let set = |key: &str, value: &str| Request::Set(key.to_owned(), value.to_owned());
+    for request in [set("key1", "value1"), set("key2", "value2")] {
+        assert_eq!(primary.request(request).unwrap(), Response::Ok);
+    }
+    // Compact the primary while replicating
+    for i in 0..300 {
+        let request = set("key3", &format!("value{i}"));
+        assert_eq!(primary.request(request).unwrap(), Response::Ok);
+    }
+    assert_eq!(
+        primary.request(Request::Rm("key2".to_owned())).unwrap(),
+        Response::Ok
+    );
+    thread::sleep(Duration::from_secs(2));
+
+    let mut replica = KvsClient::new(replica_addr.parse().unwrap());
+    let get = |key: &str| Request::Get(key.to_owned());
+    assert_eq!(
+        replica.request(get("key1")).unwrap(),
+        Response::Value("value1".to_owned())
+    );
+    assert_eq!(replica.request(get("key2")).unwrap(), Response::NoKey);
+    assert_eq!(
+        replica.request(get("key3")).unwrap(),
+        Response::Value("value299".to_owned())
+    );
+    assert_eq!(
+        replica.request(set("key4", "value4")).unwrap(),
+        Response::Err
+    );
+
+    // The restarted replica resumes from its position
+    replica_child.kill().unwrap();
+    replica_child.wait().unwrap();
+    for request in [set("key1", "value5"), set("key4", "value4")] {
+        assert_eq!(primary.request(request).unwrap(), Response::Ok);
+    }
+    let mut replica_child = spawn_replica();
+    thread::sleep(Duration::from_secs(2));
+    let mut replica = KvsClient::new(replica_addr.parse().unwrap());
+    assert_eq!(
+        replica.request(get("key1")).unwrap(),
+        Response::Value("value5".to_owned())
+    );
+    assert_eq!(
+        replica.request(get("key4")).unwrap(),
+        Response::Value("value4".to_owned())
+    );
+    assert!(replica_dir.path().join("REPLICA_POSITION").exists());
+
+    replica_child.kill().unwrap();
+    replica_child.wait().unwrap();
+    primary_child.kill().unwrap();
+    primary_child.wait().unwrap();
+}
+
+#[test]
+fn cli_cluster() {
+    let temp_dir = TempDir::new().unwrap();
+    let addrs = ["127.0.0.1:4012", "127.0.0.1:4013", "127.0.0.1:4014"];
+    let nodes = addrs
+        .iter()
+        .enumerate()
+        .map(|(i, addr)| {
+            format!(
+                r#"{{"id": {}, "addr": "{addr}", "raft_addr": "127.0.0.1:{}"}}"#,
+                i + 1,
+                5012 + i
+            )
+        })
+        .collect::<Vec<_>>();
+    let config = temp_dir.path().join("cluster.json");
+    fs::write(&config, format!(r#"{{"nodes": [{}]}}"#, nodes.join(", "))).unwrap();
+    let mut children = (1..=3)
+        .map(|id| {
+            let dir = temp_dir.path().join(id.to_string());
+            fs::create_dir(&dir).unwrap();
+            Command::cargo_bin("kvs-server")
+                .unwrap()
+                .args(&["--cluster", config.to_str().unwrap()])
+                .args(&["--node-id", &id.to_string()])
+                .current_dir(&dir)
+                .spawn()
+                .unwrap()
+        })
+        .collect::<Vec<_>>();
+    thread::sleep(Duration::from_secs(2));
+
+    // Writes to any node are forwarded to the leader
+    for (i, addr) in addrs.iter().enumerate() {
+        let mut client = KvsClient::new(addr.parse().unwrap());
+        let request = Request::Set(format!("key{i}"), format!("value{i}"));
+        assert_eq!(client.request(request).unwrap(), Response::Ok);
+    }
+    let mut client = KvsClient::new(addrs[1].parse().unwrap());
+    assert_eq!(
+        client.request(Request::Rm("key0".to_owned())).unwrap(),
+        Response::Ok
+    );
+    thread::sleep(Duration::from_secs(1));
+
+    for addr in addrs {
+        let mut client = KvsClient::new(addr.parse().unwrap());
+        assert_eq!(
+            client.request(Request::Scan("key".to_owned())).unwrap(),
+            Response::Pairs(vec![
+                ("key1".to_owned(), "value1".to_owned()),
+                ("key2".to_owned(), "value2".to_owned()),
+            ])
+        );
+    }
+
+    for child in &mut children {
+        child.kill().unwrap();
+        child.wait().unwrap();
+    }
+}
+
+#[test]
+fn cli_sharding() {
+    let temp_dir = TempDir::new().unwrap();
+    let addrs = ["127.0.0.1:4015", "127.0.0.1:4016", "127.0.0.1:4017"];
+    let mut children = addrs
+        .iter()
+        .map(|addr| {
+            let dir = temp_dir.path().join(&addr[10..]);
+            fs::create_dir(&dir).unwrap();
+            Command::cargo_bin("kvs-server")
+                .unwrap()
+                .args(&["--addr", addr])
+                .current_dir(&dir)
+                .spawn()
+                .unwrap()
+        })
+        .collect::<Vec<_>>();
+    thread::sleep(Duration::from_secs(1));
+
+    let two = addrs[..2].join(",");
+    let three = addrs.join(",");
+    let client = || Command::cargo_bin("kvs-client").unwrap();
+    for i in 0..20 {
+        client()
+            .args(&["set", &format!("key{i}"), &format!("value{i}")])
+            .args(&["--cluster", &two])
+            .assert()
+            .success();
+    }
+    client()
+        .args(&["rm", "key0", "--cluster", &two])
+        .assert()
+        .success();
+    client()
+        .args(&["rm", "key0", "--cluster", &two])
+        .assert()
+        .failure()
+        .stdout(contains("Key not found"));
+    client()
+        .args(&["get", "key7", "--cluster", &two])
+        .assert()
+        .success()
+        .stdout("value7\n");
+    // Keys are spread over both servers
+    for addr in &addrs[..2] {
+        let mut client = KvsClient::new(addr.parse().unwrap());
+        match client.request(Request::Scan(String::new())).unwrap() {
+            Response::Pairs(pairs) => assert!(!pairs.is_empty() && pairs.len() < 19),
+            response => panic!("Unexpected response: {response:?}"),
+        }
+    }
+
+    // A key already on its new server is newer, it's not overwritten
+    let ring = HashRing::new(&addrs.map(|addr| addr.parse().unwrap()));
+    let newer = (1..20)
+        .map(|i| format!("key{i}"))
+        .find(|key| ring.node_of(key) == Some(addrs[2].parse().unwrap()))
+        .unwrap();
+    let mut third = KvsClient::new(addrs[2].parse().unwrap());
+    assert_eq!(
+        third
+            .request(Request::Set(newer.clone(), "newer".to_owned()))
+            .unwrap(),
+        Response::Ok
+    );
+
+    // After adding the third server, only its keys move
+    client()
+        .args(&["rebalance", "--cluster", &three])
+        .assert()
+        .success()
+        .stderr(contains("Moved"));
+    assert_ne!(
+        third.request(Request::Scan(String::new())).unwrap(),
+        Response::Pairs(Vec::new())
+    );
+    for i in 1..20 {
+        let key = format!("key{i}");
+        let value = if key == newer {
+            "newer".to_owned()
+        } else {
+            format!("value{i}")
+        };
+        client()
+            .args(&["get", &key, "--cluster", &three])
+            .assert()
+            .success()
+            .stdout(format!("{value}\n"));
+    }
+    let dumped = client()
+        .args(&["dump", "--cluster", &three])
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    assert_eq!(String::from_utf8(dumped).unwrap().lines().count(), 19);
+
+    for child in &mut children {
+        child.kill().unwrap();
+        child.wait().unwrap();
+    }
+}
+
+#[test]
+fn cli_cache() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4018";
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--engine", "memory", "--addr", addr])
+        .args(&["--max-memory", "1K", "--eviction-policy", "lfu"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let client = || Command::cargo_bin("kvs-client").unwrap();
+    for i in 0..20 {
+        client()
+            .args(&["set", &format!("key{i}"), "value", "--addr", addr])
+            .assert()
+            .success();
+    }
+    client()
+        .args(&["set", "temp", "value", "--ttl", "1", "--addr", addr])
+        .assert()
+        .success();
+    client()
+        .args(&["get", "temp", "--addr", addr])
+        .assert()
+        .success()
+        .stdout("value\n");
+    thread::sleep(Duration::from_millis(1100));
+    client()
+        .args(&["get", "temp", "--addr", addr])
+        .assert()
+        .success()
+        .stdout(contains("Key not found"));
+    client()
+        .args(&["stats", "--addr", addr])
+        .assert()
+        .success()
+        .stdout(contains("max_memory 1024\n").and(contains("expirations 1\n")))
+        .stdout(contains("evictions 0\n").not());
+
+    child.kill().unwrap();
+    child.wait().unwrap();
+}
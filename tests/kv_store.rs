@@ -1,6 +1,11 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::{
+    rwlock, Error, Event, KvStore, KvStoreOptions, KvsEngine, KvsSnapshot, LogPosition, LogTail,
+    MemKvsEngine, ReadSet, Result, SledKvsEngine, Transaction, ValueCacheStats, WriteSet,
+};
+use std::mem;
 use std::sync::{Arc, Barrier};
 use std::thread;
+use std::time::Duration;
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -81,8 +86,642 @@ fn remove_key() -> Result<()> {
     Ok(())
 }
 
+// Should get all pairs with the prefix, sorted by key
+#[test]
+fn scan_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.set("user2".to_owned(), "value2".to_owned())?;
+    store.set("user1".to_owned(), "value1".to_owned())?;
+    store.set("item1".to_owned(), "value3".to_owned())?;
+    store.set("user3".to_owned(), "value4".to_owned())?;
+    store.remove("user3".to_owned())?;
+
+    let expected = vec![
+        ("user1".to_owned(), "value1".to_owned()),
+        ("user2".to_owned(), "value2".to_owned()),
+    ];
+    assert_eq!(store.scan("user")?, expected);
+    assert_eq!(store.scan("")?.len(), 3);
+    assert_eq!(store.scan("none")?, vec![]);
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.scan("user")?, expected);
+
+    // Pages start from a key, the next from the last key followed by '\0'
+    assert_eq!(store.scan_page("user", "user", 1)?, expected[..1]);
+    assert_eq!(store.scan_page("user", "user1\0", 1)?, expected[1..]);
+    assert_eq!(store.scan_page("user", "user2\0", 1)?, vec![]);
+    assert_eq!(store.scan_page("", "", 10)?.len(), 3);
+
+    Ok(())
+}
+
+// Checkpoint should hold the state when it's made, and writes can go on
+#[test]
+fn checkpoint() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "old".to_owned())?;
+    }
+    store.remove("key0".to_owned())?;
+    store.checkpoint(backup_dir.path())?;
+    // Refuse a non-empty directory
+    assert!(store.checkpoint(backup_dir.path()).is_err());
+
+    // Overwrite enough to trigger compaction
+    for iter in 0..100 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+
+    let backup = KvStore::open(backup_dir.path())?;
+    assert_eq!(backup.get("key0")?, None);
+    for key_id in 1..100 {
+        assert_eq!(
+            backup.get(&format!("key{}", key_id))?,
+            Some("old".to_owned())
+        );
+    }
+    // Writing to the backup won't change the source
+    backup.set("key1".to_owned(), "backup".to_owned())?;
+    drop(backup);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        assert_eq!(store.get(&format!("key{}", key_id))?, Some("99".to_owned()));
+    }
+
+    // Files compacted away but pinned by a snapshot are not copied
+    let snapshot = store.snapshot()?;
+    for round in 0..2 {
+        // The removal is compacted away in the second round
+        if round == 1 {
+            store.remove("key0".to_owned())?;
+        }
+        for iter in 0..100 {
+            for key_id in 1..100 {
+                store.set(format!("key{}", key_id), format!("{}", iter))?;
+            }
+        }
+    }
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    store.checkpoint(backup_dir.path())?;
+    drop(snapshot);
+    let backup = KvStore::open(backup_dir.path())?;
+    assert_eq!(backup.get("key0")?, None);
+    assert_eq!(backup.get("key1")?, Some("99".to_owned()));
+
+    Ok(())
+}
+
+// The rwlock store should checkpoint the same way, with compaction waiting for the copy
+#[test]
+fn rwlock_checkpoint() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = rwlock::KvStore::open(temp_dir.path())?;
+
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "old".to_owned())?;
+    }
+    store.remove("key0".to_owned())?;
+    // Writes go on while the checkpoint is copied
+    let writer = thread::spawn({
+        let store = store.clone();
+        move || -> Result<()> {
+            for iter in 0..100 {
+                for key_id in 1..100 {
+                    store.set(format!("key{}", key_id), format!("{}", iter))?;
+                }
+            }
+            Ok(())
+        }
+    });
+    store.checkpoint(backup_dir.path())?;
+    writer.join().unwrap()?;
+
+    let backup = rwlock::KvStore::open(backup_dir.path())?;
+    assert_eq!(backup.get("key0")?, None);
+    assert_eq!(backup.scan("key")?.len(), 99);
+    assert_eq!(store.get("key1")?, Some("99".to_owned()));
+    // Files compaction dropped during the copy are deleted once it's done
+    drop(store);
+    let store = rwlock::KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0")?, None);
+    assert_eq!(store.get("key1")?, Some("99".to_owned()));
+
+    Ok(())
+}
+
+// Snapshot should see the state when it's taken, even across compaction
+#[test]
+fn snapshot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "old".to_owned())?;
+    }
+    let snapshot = store.snapshot()?;
+
+    store.remove("key0".to_owned())?;
+    store.set("new".to_owned(), "new".to_owned())?;
+    // Overwrite enough to trigger compaction
+    for iter in 0..100 {
+        for key_id in 1..100 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+
+    // A later snapshot sees its own state, also once the writes it sees are compacted
+    let later = store.snapshot()?;
+    store.set("key0".to_owned(), "new".to_owned())?;
+    for iter in 0..100 {
+        for key_id in 1..100 {
+            store.set(format!("key{}", key_id), format!("new{}", iter))?;
+        }
+    }
+    assert_eq!(later.get("key0")?, None);
+    assert_eq!(later.get("key1")?, Some("99".to_owned()));
+    assert_eq!(later.scan("key")?.len(), 99);
+
+    let handle = thread::spawn(move || -> Result<()> {
+        assert_eq!(snapshot.get("key0")?, Some("old".to_owned()));
+        assert_eq!(snapshot.get("new")?, None);
+        let pairs = snapshot.scan("key")?;
+        assert_eq!(pairs.len(), 100);
+        assert!(pairs.iter().all(|(_, value)| value == "old"));
+        Ok(())
+    });
+    handle.join().unwrap()?;
+
+    assert_eq!(store.get("key0")?, Some("new".to_owned()));
+    assert_eq!(store.get("key1")?, Some("new99".to_owned()));
+    assert_eq!(store.snapshot()?.get("key1")?, Some("new99".to_owned()));
+
+    Ok(())
+}
+
+// Files kept for a snapshot are out of the log once compacted, even if the process dies
+#[test]
+fn snapshot_restart() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "old".to_owned())?;
+    }
+    let snapshot = store.snapshot()?;
+    // The removal goes to a data file not pinned by the snapshot
+    for key_id in 1..100 {
+        store.set(format!("key{}", key_id), "new".to_owned())?;
+    }
+    store.remove("key0".to_owned())?;
+    // Overwrite enough to trigger compaction, which drops the removal
+    for iter in 0..100 {
+        for key_id in 1..100 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    // Never released, as if the process died holding it
+    mem::forget(snapshot);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0")?, None);
+    assert_eq!(store.get("key1")?, Some("99".to_owned()));
+    assert_eq!(store.scan("key")?.len(), 99);
+
+    Ok(())
+}
+
+// Transfers in transactions should keep the total unchanged
+#[test]
+fn transaction_transfer() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for account in 0..4 {
+        store.set(format!("account{}", account), "100".to_owned())?;
+    }
+
+    let mut handles = Vec::new();
+    for thread_id in 0..4 {
+        let store = store.clone();
+        handles.push(thread::spawn(move || -> Result<()> {
+            for i in 0..50 {
+                let from = format!("account{}", (thread_id + i) % 4);
+                let to = format!("account{}", (thread_id + i + 1) % 4);
+                store.transaction(|tx| {
+                    let from_balance: i64 = tx.get(&from)?.unwrap().parse().unwrap();
+                    let to_balance: i64 = tx.get(&to)?.unwrap().parse().unwrap();
+                    tx.set(from.clone(), (from_balance - 1).to_string());
+                    tx.set(to.clone(), (to_balance + 1).to_string());
+                    Ok(())
+                })?;
+            }
+            Ok(())
+        }));
+    }
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+
+    let total = |store: &KvStore| -> Result<i64> {
+        let mut total = 0;
+        for (_, balance) in store.scan("account")? {
+            total += balance.parse::<i64>().unwrap();
+        }
+        Ok(total)
+    };
+    assert_eq!(total(&store)?, 400);
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(total(&store)?, 400);
+
+    Ok(())
+}
+
+// Transaction should fail to commit if a key it read has changed
+#[test]
+fn transaction_conflict() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let mut tx = Transaction::new(&store);
+    assert_eq!(tx.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(tx.get("key2")?, None);
+    tx.set("key2".to_owned(), "value2".to_owned());
+    assert_eq!(tx.get("key2")?, Some("value2".to_owned()));
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(!tx.commit()?);
+    assert_eq!(store.get("key2")?, None);
+
+    let mut tx = Transaction::new(&store);
+    tx.remove("key1".to_owned())?;
+    assert!(tx.remove("key3".to_owned()).is_err());
+    tx.set("key2".to_owned(), "value2".to_owned());
+    assert!(tx.commit()?);
+    assert_eq!(store.get("key1")?, None);
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+
+    // An error of the body aborts the transaction
+    assert!(store
+        .transaction(|tx| {
+            tx.set("key3".to_owned(), "value3".to_owned());
+            tx.remove("key1".to_owned())
+        })
+        .is_err());
+    assert_eq!(store.get("key3")?, None);
+
+    Ok(())
+}
+
+// The rwlock store should apply a commit as one record, and notify watchers of its writes
+#[test]
+fn rwlock_commit() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = rwlock::KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let receiver = store.watch("key")?;
+
+    let mut tx = Transaction::new(&store);
+    tx.remove("key1".to_owned())?;
+    tx.set("key2".to_owned(), "value2".to_owned());
+    tx.set("key3".to_owned(), "value3".to_owned());
+    assert!(tx.commit()?);
+    assert_eq!(
+        receiver.try_iter().collect::<Vec<_>>(),
+        vec![
+            Event::Rm("key1".to_owned()),
+            Event::Set("key2".to_owned(), "value2".to_owned()),
+            Event::Set("key3".to_owned(), "value3".to_owned()),
+        ]
+    );
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = rwlock::KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, None);
+    assert_eq!(
+        store.scan("key")?,
+        vec![
+            ("key2".to_owned(), "value2".to_owned()),
+            ("key3".to_owned(), "value3".to_owned()),
+        ]
+    );
+
+    Ok(())
+}
+
+fn commit_remove_absent<E: KvsEngine>(engine: E) -> Result<()> {
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    let receiver = engine.watch("key")?;
+    let writes = WriteSet::from([
+        ("key1".to_owned(), None),
+        ("key2".to_owned(), Some("value2".to_owned())),
+        ("key3".to_owned(), None),
+    ]);
+    assert!(engine.commit(&ReadSet::new(), writes)?);
+    assert_eq!(
+        engine.scan("key")?,
+        vec![("key2".to_owned(), "value2".to_owned())]
+    );
+    // Events of sled come from another thread, the next write shows nothing is between
+    engine.set("key4".to_owned(), "value4".to_owned())?;
+    let events = (0..3)
+        .map(|_| receiver.recv_timeout(Duration::from_secs(5)).unwrap())
+        .collect::<Vec<_>>();
+    // sled applies the writes of a transaction in no particular order
+    assert!(events[..2].contains(&Event::Rm("key1".to_owned())));
+    assert!(events[..2].contains(&Event::Set("key2".to_owned(), "value2".to_owned())));
+    assert_eq!(
+        events[2],
+        Event::Set("key4".to_owned(), "value4".to_owned())
+    );
+    Ok(())
+}
+
+// Committing the removal of an absent key should skip it the same way on every engine
+#[test]
+fn commit_remove_absent_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    commit_remove_absent(KvStore::open(temp_dir.path())?)?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    commit_remove_absent(rwlock::KvStore::open(temp_dir.path())?)?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    commit_remove_absent(SledKvsEngine::open(temp_dir.path())?)?;
+    commit_remove_absent(MemKvsEngine::new())
+}
+
 // Insert data until total size of the directory decreases.
 // Test data correctness after compaction.
+// Watchers should receive the writes to keys with the prefix in order
+#[test]
+fn watch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value0".to_owned())?;
+    let receiver = store.watch("key")?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("other".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    store.transaction(|tx| {
+        tx.set("key2".to_owned(), "value3".to_owned());
+        tx.set("other".to_owned(), "value4".to_owned());
+        Ok(())
+    })?;
+
+    assert_eq!(
+        receiver.try_iter().collect::<Vec<_>>(),
+        vec![
+            Event::Set("key1".to_owned(), "value1".to_owned()),
+            Event::Rm("key1".to_owned()),
+            Event::Set("key2".to_owned(), "value3".to_owned()),
+        ]
+    );
+
+    // Writes go on after the receiver is dropped
+    drop(receiver);
+    store.set("key1".to_owned(), "value5".to_owned())?;
+    assert_eq!(store.get("key1")?, Some("value5".to_owned()));
+
+    // A watcher lagging behind is dropped instead of holding the events
+    let receiver = store.watch("key")?;
+    for i in 0..2000 {
+        store.set("key1".to_owned(), i.to_string())?;
+    }
+    let events = receiver.iter().collect::<Vec<_>>();
+    assert!(events.len() < 2000);
+    assert_eq!(events[0], Event::Set("key1".to_owned(), "0".to_owned()));
+    Ok(())
+}
+
+// sled watchers should see the writes in order, fed by one thread
+#[test]
+fn sled_watch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::open(temp_dir.path())?;
+
+    let receivers = (0..100)
+        .map(|_| engine.watch("key"))
+        .collect::<Result<Vec<_>>>()?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("other".to_owned(), "value2".to_owned())?;
+    engine.remove("key1".to_owned())?;
+    for receiver in receivers {
+        let events = receiver.iter().take(2).collect::<Vec<_>>();
+        assert_eq!(
+            events,
+            vec![
+                Event::Set("key1".to_owned(), "value1".to_owned()),
+                Event::Rm("key1".to_owned()),
+            ]
+        );
+    }
+    Ok(())
+}
+
+// `get_at` and `history` should see overwritten and removed values, also after reopening
+#[test]
+fn history() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let (_, seq1) = store.get_versioned("key1")?.unwrap();
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    let (_, seq2) = store.get_versioned("key1")?.unwrap();
+    store.remove("key1".to_owned())?;
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    let (_, seq3) = store.get_versioned("key2")?.unwrap();
+    assert!(seq1 < seq2 && seq2 < seq3);
+
+    let check = |store: &KvStore| -> Result<()> {
+        assert_eq!(store.get_at("key1", seq1 - 1)?, None);
+        assert_eq!(store.get_at("key1", seq1)?, Some("value1".to_owned()));
+        assert_eq!(store.get_at("key1", seq2)?, Some("value2".to_owned()));
+        assert_eq!(store.get_at("key1", seq3)?, None);
+        assert_eq!(store.get_at("key2", seq2)?, None);
+        assert_eq!(store.get_at("key2", seq3)?, Some("value3".to_owned()));
+        assert_eq!(
+            store.history("key1", 10)?,
+            vec![
+                (seq2 + 1, None),
+                (seq2, Some("value2".to_owned())),
+                (seq1, Some("value1".to_owned())),
+            ]
+        );
+        assert_eq!(store.history("key1", 1)?, vec![(seq2 + 1, None)]);
+        assert_eq!(store.history("key3", 10)?, vec![]);
+        Ok(())
+    };
+    check(&store)?;
+
+    // Open from disk again and check persistent history
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    check(&store)
+}
+
+// Compaction should drop the history older than the retention window only
+#[test]
+fn history_retention() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_retention(temp_dir.path(), 100)?;
+
+    store.set("key".to_owned(), "0".to_owned())?;
+    let (_, first_seq) = store.get_versioned("key")?.unwrap();
+    for i in 1..2000 {
+        store.set("key".to_owned(), i.to_string())?;
+    }
+    let last_seq = first_seq + 1999;
+
+    let check = |store: &KvStore| -> Result<()> {
+        assert!(matches!(
+            store.get_at("key", first_seq),
+            Err(Error::HistoryCompacted)
+        ));
+        assert_eq!(store.get_at("key", last_seq - 50)?, Some("1949".to_owned()));
+        assert_eq!(store.get_at("key", last_seq)?, Some("1999".to_owned()));
+        let history = store.history("key", 50)?;
+        assert_eq!(history.len(), 50);
+        assert_eq!(history[49], (last_seq - 49, Some("1950".to_owned())));
+        Ok(())
+    };
+    check(&store)?;
+
+    drop(store);
+    let store = KvStore::open_with_retention(temp_dir.path(), 100)?;
+    check(&store)
+}
+
+// Applying the tailed log should rebuild the store, from the beginning once compacted
+#[test]
+fn tail_log() -> Result<()> {
+    let primary_dir = TempDir::new().expect("unable to create temporary working directory");
+    let replica_dir = TempDir::new().expect("unable to create temporary working directory");
+    let primary = KvStore::open(primary_dir.path())?;
+    let replica = KvStore::open(replica_dir.path())?;
+    let replicate = |tail: &mut LogTail| -> Result<LogPosition> {
+        let mut next = LogPosition::default();
+        while let Some((position, record)) = tail.next_record()? {
+            replica.apply_replicated(serde_json::from_str(&record)?)?;
+            next = LogPosition {
+                file_offset: position.file_offset + record.len() as u32,
+                ..position
+            };
+        }
+        Ok(next)
+    };
+
+    primary.set("key1".to_owned(), "value1".to_owned())?;
+    primary.set("key2".to_owned(), "value2".to_owned())?;
+    let mut tail = primary.tail_log(LogPosition::default())?;
+    assert!(!tail.is_resync());
+    let position = replicate(&mut tail)?;
+    drop(tail);
+
+    primary.remove("key2".to_owned())?;
+    for i in 0..1000 {
+        primary.set("key3".to_owned(), i.to_string())?;
+    }
+    let mut tail = primary.tail_log(position)?;
+    assert!(tail.is_resync());
+    replica.reset()?;
+    replicate(&mut tail)?;
+    // Applying again is harmless
+    let mut tail = primary.tail_log(LogPosition::default())?;
+    replicate(&mut tail)?;
+
+    assert_eq!(replica.scan("")?, primary.scan("")?);
+    assert_eq!(replica.get("key2")?, None);
+    assert_eq!(replica.get("key3")?, Some("999".to_owned()));
+    Ok(())
+}
+
+// Cached values should be served from memory, and stay right across writes and compaction
+#[test]
+fn value_cache() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreOptions::new()
+        .value_cache(100)
+        .open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.transaction(|tx| {
+        tx.set("key2".to_owned(), "value2".to_owned());
+        tx.set("key3".to_owned(), "value3".to_owned());
+        Ok(())
+    })?;
+    for _ in 0..2 {
+        assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+        assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+        assert_eq!(store.get("key3")?, Some("value3".to_owned()));
+    }
+    assert_eq!(
+        store.value_cache_stats(),
+        Some(ValueCacheStats { hits: 4, misses: 2 })
+    );
+
+    store.set("key1".to_owned(), "value4".to_owned())?;
+    assert_eq!(store.get("key1")?, Some("value4".to_owned()));
+    store.remove("key2".to_owned())?;
+    assert_eq!(store.get("key2")?, None);
+
+    // Compact several times while reading
+    for i in 0..2000 {
+        store.set(format!("key{}", i % 10 + 10), i.to_string())?;
+        assert_eq!(store.get("key1")?, Some("value4".to_owned()));
+        assert_eq!(
+            store.get(&format!("key{}", i % 10 + 10))?,
+            Some(i.to_string())
+        );
+    }
+    assert_eq!(store.get("key3")?, Some("value3".to_owned()));
+    assert!(store.value_cache_stats().unwrap().hits > 2000);
+    assert_eq!(KvStore::open(temp_dir.path())?.value_cache_stats(), None);
+    Ok(())
+}
+
+// One store shared by reference, reading while writes fill and compact data files
+#[test]
+fn shared_reads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{i}"), format!("value{i}"))?;
+    }
+    thread::scope(|s| {
+        for _ in 0..4 {
+            s.spawn(|| {
+                for i in 0..1000 {
+                    let key = format!("key{}", i % 100);
+                    assert_eq!(store.get(&key).unwrap(), Some(format!("value{}", i % 100)));
+                }
+            });
+        }
+        for i in 0..2000 {
+            store
+                .set(format!("other{}", i % 10), i.to_string())
+                .unwrap();
+        }
+    });
+    assert_eq!(store.get("other9")?, Some("1999".to_owned()));
+    Ok(())
+}
+
 #[test]
 fn compaction() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
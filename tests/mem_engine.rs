@@ -0,0 +1,101 @@
+use kvs::{Error, Event, KvStore, KvsEngine, KvsSnapshot, MemKvsEngine, Result, Transaction};
+use tempfile::TempDir;
+
+// Should behave like the other engines on plain reads and writes
+#[test]
+fn set_get_remove() -> Result<()> {
+    let engine = MemKvsEngine::new();
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(engine.get("key1")?, Some("value2".to_owned()));
+    assert_eq!(engine.get("key2")?, None);
+
+    engine.remove("key1".to_owned())?;
+    assert_eq!(engine.get("key1")?, None);
+    assert!(matches!(
+        engine.remove("key1".to_owned()),
+        Err(Error::RemoveNonexistKey)
+    ));
+
+    // Clones share the pairs
+    let clone = engine.clone();
+    clone.set("key3".to_owned(), "value3".to_owned())?;
+    assert_eq!(engine.get("key3")?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// Scans should be sorted, snapshots shouldn't see later writes
+#[test]
+fn scan_and_snapshot() -> Result<()> {
+    let engine = MemKvsEngine::new();
+    engine.set("user2".to_owned(), "value2".to_owned())?;
+    engine.set("user1".to_owned(), "value1".to_owned())?;
+    engine.set("item1".to_owned(), "value3".to_owned())?;
+    let expected = vec![
+        ("user1".to_owned(), "value1".to_owned()),
+        ("user2".to_owned(), "value2".to_owned()),
+    ];
+    assert_eq!(engine.scan("user")?, expected);
+    assert_eq!(engine.scan_page("user", "user1\0", 10)?, expected[1..]);
+
+    let snapshot = engine.snapshot()?;
+    engine.remove("user1".to_owned())?;
+    engine.set("user3".to_owned(), "value4".to_owned())?;
+    assert_eq!(snapshot.scan("user")?, expected);
+    assert_eq!(engine.scan("user")?.len(), 2);
+    Ok(())
+}
+
+// Transactions should conflict on changed reads, watchers see committed writes
+#[test]
+fn transaction_and_watch() -> Result<()> {
+    let engine = MemKvsEngine::new();
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    let receiver = engine.watch("key")?;
+
+    let mut tx = Transaction::new(&engine);
+    assert_eq!(tx.get("key1")?, Some("value1".to_owned()));
+    tx.set("key2".to_owned(), "value2".to_owned());
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(!tx.commit()?);
+    assert_eq!(engine.get("key2")?, None);
+
+    engine.transaction(|tx| {
+        let value = tx.get("key1")?.unwrap();
+        tx.remove("key1".to_owned())?;
+        tx.set("key2".to_owned(), value);
+        Ok(())
+    })?;
+    assert_eq!(engine.get("key2")?, Some("value1".to_owned()));
+    assert_eq!(
+        receiver.try_iter().collect::<Vec<_>>(),
+        vec![
+            Event::Set("key1".to_owned(), "value1".to_owned()),
+            Event::Rm("key1".to_owned()),
+            Event::Set("key2".to_owned(), "value1".to_owned()),
+        ]
+    );
+    assert!(matches!(
+        engine.get_at("key2", 1),
+        Err(Error::HistoryUnsupported)
+    ));
+    Ok(())
+}
+
+// A checkpoint should be a kvs store holding the pairs
+#[test]
+fn checkpoint() -> Result<()> {
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = MemKvsEngine::new();
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    engine.checkpoint(backup_dir.path())?;
+    assert!(matches!(
+        engine.checkpoint(backup_dir.path()),
+        Err(Error::NonEmptyCheckpointDir)
+    ));
+
+    let store = KvStore::open(backup_dir.path())?;
+    assert_eq!(store.scan("")?, engine.scan("")?);
+    Ok(())
+}
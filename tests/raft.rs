@@ -0,0 +1,199 @@
+use kvs::raft::{LocalNetwork, NodeId, RaftEngine, RaftNode};
+use kvs::{Error, KvStore, KvsEngine, Result};
+use std::collections::HashMap;
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+const IDS: [NodeId; 3] = [1, 2, 3];
+
+struct Cluster {
+    network: LocalNetwork,
+    dirs: Vec<TempDir>,
+    engines: Vec<RaftEngine<KvStore>>,
+}
+
+impl Cluster {
+    fn new() -> Self {
+        let network = LocalNetwork::new();
+        let dirs = IDS.iter().map(|_| TempDir::new().unwrap()).collect();
+        let mut cluster = Self {
+            network,
+            dirs,
+            engines: Vec::new(),
+        };
+        for id in IDS {
+            let engine = cluster.start(id);
+            cluster.engines.push(engine);
+        }
+        cluster
+    }
+    fn start(&self, id: NodeId) -> RaftEngine<KvStore> {
+        let dir = self.dirs[id as usize - 1].path();
+        let (transport, inbox) = self.network.join(id);
+        let peers = IDS.iter().copied().filter(|&peer| peer != id).collect();
+        let node = RaftNode::start(
+            id,
+            peers,
+            dir,
+            KvStore::open(dir).unwrap(),
+            transport,
+            inbox,
+        )
+        .unwrap();
+        // In-process nodes have no servers to forward writes to
+        RaftEngine::new(node, HashMap::new())
+    }
+    fn engine(&self, id: NodeId) -> &RaftEngine<KvStore> {
+        &self.engines[id as usize - 1]
+    }
+    /// Wait until one of the nodes leads
+    fn leader(&self, among: &[NodeId]) -> NodeId {
+        let mut leader = None;
+        wait_until(|| {
+            leader = among
+                .iter()
+                .copied()
+                .find(|&id| self.engine(id).node().is_leader());
+            leader.is_some()
+        });
+        leader.unwrap()
+    }
+}
+
+fn wait_until(mut condition: impl FnMut() -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !condition() {
+        assert!(Instant::now() < deadline, "timed out");
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+fn value_of(engine: &RaftEngine<KvStore>, key: &str) -> Option<String> {
+    engine.get(key).unwrap()
+}
+
+// Writes on the leader should be applied on all nodes
+#[test]
+fn replicate_writes() -> Result<()> {
+    let cluster = Cluster::new();
+    let leader = cluster.engine(cluster.leader(&IDS));
+    leader.set("key1".to_owned(), "value1".to_owned())?;
+    leader.set("key2".to_owned(), "value2".to_owned())?;
+    leader.remove("key1".to_owned())?;
+    assert!(matches!(
+        leader.remove("key1".to_owned()),
+        Err(Error::RemoveNonexistKey)
+    ));
+    assert!(leader.transaction(|tx| {
+        let value = tx.get("key2")?.unwrap();
+        tx.set("key3".to_owned(), value);
+        Ok(true)
+    })?);
+
+    for id in IDS {
+        wait_until(|| value_of(cluster.engine(id), "key3").as_deref() == Some("value2"));
+        assert_eq!(value_of(cluster.engine(id), "key1"), None);
+    }
+    Ok(())
+}
+
+// Followers without a server should tell who leads
+#[test]
+fn follower_refuses_writes() {
+    let cluster = Cluster::new();
+    let leader = cluster.leader(&IDS);
+    let follower = IDS.iter().copied().find(|&id| id != leader).unwrap();
+    wait_until(|| cluster.engine(follower).node().leader() == Some(leader));
+    assert!(matches!(
+        cluster
+            .engine(follower)
+            .set("key".to_owned(), "value".to_owned()),
+        Err(Error::UnknownNode(id)) if id == leader
+    ));
+}
+
+// The majority side of a partition should elect a leader and go on,
+// the old leader should drop its uncommitted writes after healing
+#[test]
+fn partitioned_leader() -> Result<()> {
+    let cluster = Cluster::new();
+    let old_leader = cluster.leader(&IDS);
+    cluster
+        .engine(old_leader)
+        .set("key".to_owned(), "before".to_owned())?;
+
+    let majority = IDS
+        .iter()
+        .copied()
+        .filter(|&id| id != old_leader)
+        .collect::<Vec<_>>();
+    cluster.network.partition(&[&[old_leader], &majority]);
+    assert!(matches!(
+        cluster
+            .engine(old_leader)
+            .set("key".to_owned(), "lost".to_owned()),
+        Err(Error::ProposalTimeout | Error::NotLeader(_))
+    ));
+    let new_leader = cluster.leader(&majority);
+    assert!(cluster.engine(new_leader).node().term() > 1);
+    cluster
+        .engine(new_leader)
+        .set("key".to_owned(), "after".to_owned())?;
+
+    cluster.network.heal();
+    wait_until(|| value_of(cluster.engine(old_leader), "key").as_deref() == Some("after"));
+    assert!(!cluster.engine(old_leader).node().is_leader());
+    Ok(())
+}
+
+// A follower missing compacted entries should catch up from a snapshot
+#[test]
+fn lagging_follower_snapshot() -> Result<()> {
+    let cluster = Cluster::new();
+    let leader = cluster.leader(&IDS);
+    let follower = IDS.iter().copied().find(|&id| id != leader).unwrap();
+    cluster.network.isolate(follower);
+    for i in 0..300 {
+        cluster
+            .engine(leader)
+            .set(format!("key{}", i % 10), i.to_string())?;
+    }
+    cluster.engine(leader).remove("key0".to_owned())?;
+
+    cluster.network.heal();
+    wait_until(|| value_of(cluster.engine(follower), "key9").as_deref() == Some("299"));
+    assert_eq!(
+        cluster.engine(follower).scan("")?,
+        cluster.engine(leader).scan("")?
+    );
+    Ok(())
+}
+
+// A restarted node should keep its log and catch up
+#[test]
+fn restart_node() -> Result<()> {
+    let mut cluster = Cluster::new();
+    let leader = cluster.leader(&IDS);
+    let follower = IDS.iter().copied().find(|&id| id != leader).unwrap();
+    cluster
+        .engine(leader)
+        .set("key1".to_owned(), "value1".to_owned())?;
+    wait_until(|| value_of(cluster.engine(follower), "key1").is_some());
+
+    cluster.engine(follower).node().stop();
+    cluster
+        .engine(leader)
+        .set("key2".to_owned(), "value2".to_owned())?;
+    // Let the stopped node's thread end before reopening its store
+    thread::sleep(Duration::from_millis(200));
+    let restarted = cluster.start(follower);
+    cluster.engines[follower as usize - 1] = restarted;
+
+    wait_until(|| value_of(cluster.engine(follower), "key2").is_some());
+    assert_eq!(
+        value_of(cluster.engine(follower), "key1"),
+        Some("value1".to_owned())
+    );
+    Ok(())
+}
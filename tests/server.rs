@@ -0,0 +1,215 @@
+use std::{
+    fs,
+    io::{Read, Write},
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream},
+    sync::{atomic::AtomicBool, Arc},
+    thread,
+    time::Duration,
+};
+
+use kvs::{
+    thread_pool::{FullQueuePolicy, PoolConfig, SharedQueueThreadPool},
+    CacheConfig, CacheEngine, Decoder, Encoder, Error, KvStore, KvsClient, KvsEngine, KvsServer,
+    MemKvsEngine, Request, Response, Result,
+};
+use tempfile::TempDir;
+
+// Requests beyond the queue of the pool are answered as busy, not queued forever
+#[test]
+fn server_busy() -> Result<()> {
+    let addr: SocketAddr = "127.0.0.1:4019".parse().unwrap();
+    let pool = SharedQueueThreadPool::with_config(
+        PoolConfig::new(1).queue_capacity(1, FullQueuePolicy::Reject),
+    )?;
+    let server = KvsServer::with_pool(MemKvsEngine::new(), Arc::new(AtomicBool::new(false)), pool);
+    thread::spawn(move || server.listen_on(addr));
+    thread::sleep(Duration::from_millis(200));
+
+    // Holds the only worker until the transaction ends
+    let mut first = KvsClient::connect(addr)?;
+    first.request(Request::Begin)?;
+    first.request(Request::Set("key".to_owned(), "value".to_owned()))?;
+    // Queued requests get nothing, the refused one is told at once
+    let mut waiting = Vec::new();
+    loop {
+        assert!(waiting.len() < 3, "no request is refused");
+        let mut stream = TcpStream::connect(addr)?;
+        stream.write_all(Encoder::new().encode_request(Request::Get("key".to_owned())))?;
+        stream.set_read_timeout(Some(Duration::from_millis(300)))?;
+        match Decoder::new(&mut stream).decode_response() {
+            Ok(response) => {
+                assert_eq!(response, Response::Busy);
+                break;
+            }
+            Err(_) => waiting.push(stream),
+        }
+    }
+    assert_eq!(
+        first.request(Request::Get("key".to_owned()))?,
+        Response::Value("value".to_owned())
+    );
+    Ok(())
+}
+
+// Admin requests skip the queue, and don't wait behind the clients,
+// also on a connection which already sent other requests
+#[test]
+fn server_admin_priority() -> Result<()> {
+    let addr: SocketAddr = "127.0.0.1:4020".parse().unwrap();
+    let pool = SharedQueueThreadPool::with_config(
+        PoolConfig::new(1).queue_capacity(1, FullQueuePolicy::Reject),
+    )?;
+    let engine = CacheEngine::new(MemKvsEngine::new(), CacheConfig::new(1 << 20))?;
+    let server = KvsServer::with_pool(engine, Arc::new(AtomicBool::new(false)), pool);
+    thread::spawn(move || server.listen_on(addr));
+    thread::sleep(Duration::from_millis(200));
+
+    let mut admin = TcpStream::connect(addr)?;
+    admin.write_all(Encoder::new().encode_request(Request::Get("key".to_owned())))?;
+    assert_eq!(Decoder::new(&mut admin).decode_response()?, Response::NoKey);
+    // Holds the only worker until the transaction ends
+    let mut first = KvsClient::connect(addr)?;
+    first.request(Request::Begin)?;
+    // Fills the queue, and would hold the worker next as it never ends its transaction
+    let mut waiting = TcpStream::connect(addr)?;
+    waiting.write_all(Encoder::new().encode_request(Request::Begin))?;
+    thread::sleep(Duration::from_millis(100));
+    admin.write_all(Encoder::new().encode_request(Request::CacheStats))?;
+    thread::sleep(Duration::from_millis(100));
+    drop(first);
+    admin.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let response = Decoder::new(&mut admin).decode_response()?;
+    assert!(matches!(response, Response::CacheStats(_)));
+    Ok(())
+}
+
+// Idle connections are closed, and don't hold a worker from the next client
+#[test]
+fn server_idle_timeout() -> Result<()> {
+    let addr: SocketAddr = "127.0.0.1:4021".parse().unwrap();
+    let pool = SharedQueueThreadPool::with_config(PoolConfig::new(1))?;
+    let server = KvsServer::with_pool(MemKvsEngine::new(), Arc::new(AtomicBool::new(false)), pool)
+        .idle_timeout(Duration::from_millis(200));
+    thread::spawn(move || server.listen_on(addr));
+    thread::sleep(Duration::from_millis(200));
+
+    let mut idle = TcpStream::connect(addr)?;
+    thread::sleep(Duration::from_millis(100));
+    let mut client = KvsClient::connect(addr)?;
+    assert_eq!(
+        client.request(Request::Get("key".to_owned()))?,
+        Response::NoKey
+    );
+    idle.set_read_timeout(Some(Duration::from_secs(5)))?;
+    assert_eq!(idle.read(&mut [0])?, 0);
+    Ok(())
+}
+
+// Backups only go under the backup directory of the server
+#[test]
+fn server_backup_dir() -> Result<()> {
+    let addr: SocketAddr = "127.0.0.1:4022".parse().unwrap();
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let pool = SharedQueueThreadPool::with_config(PoolConfig::new(1))?;
+    let server = KvsServer::with_pool(MemKvsEngine::new(), Arc::new(AtomicBool::new(false)), pool)
+        .backup_dir(backup_dir.path());
+    thread::spawn(move || server.listen_on(addr));
+    thread::sleep(Duration::from_millis(200));
+
+    let mut client = KvsClient::connect(addr)?;
+    client.request(Request::Set("key".to_owned(), "value".to_owned()))?;
+    assert_eq!(
+        client.request(Request::Backup("daily/1".to_owned()))?,
+        Response::Ok
+    );
+    let backup = KvStore::open(backup_dir.path().join("daily/1"))?;
+    assert_eq!(backup.get("key")?, Some("value".to_owned()));
+
+    let outside = TempDir::new().expect("unable to create temporary working directory");
+    for dest_dir in [
+        outside.path().join("backup").to_str().unwrap(),
+        "../backup",
+        "daily/../../backup",
+        "",
+    ] {
+        assert_eq!(
+            client.request(Request::Backup(dest_dir.to_owned()))?,
+            Response::Err
+        );
+    }
+    assert!(!backup_dir.path().parent().unwrap().join("backup").exists());
+    assert_eq!(fs::read_dir(outside.path())?.count(), 0);
+
+    // Refused if the server has no backup directory
+    let addr: SocketAddr = "127.0.0.1:4023".parse().unwrap();
+    let pool = SharedQueueThreadPool::with_config(PoolConfig::new(1))?;
+    let server = KvsServer::with_pool(MemKvsEngine::new(), Arc::new(AtomicBool::new(false)), pool);
+    thread::spawn(move || server.listen_on(addr));
+    thread::sleep(Duration::from_millis(200));
+    let mut client = KvsClient::connect(addr)?;
+    assert_eq!(
+        client.request(Request::Backup("daily/1".to_owned()))?,
+        Response::Err
+    );
+    Ok(())
+}
+
+// Watchers and replicas beyond the limit of streams are answered as busy,
+// and streams stop on shutdown
+#[test]
+fn server_max_streams() -> Result<()> {
+    let addr: SocketAddr = "127.0.0.1:4024".parse().unwrap();
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let shutdown_signal = Arc::new(AtomicBool::new(false));
+    let pool = SharedQueueThreadPool::with_config(PoolConfig::new(2))?;
+    let server = KvsServer::with_pool(
+        KvStore::open(temp_dir.path())?,
+        Arc::clone(&shutdown_signal),
+        pool,
+    )
+    .max_streams(1);
+    let listening = thread::spawn(move || server.listen_on(addr));
+    thread::sleep(Duration::from_millis(200));
+
+    let stream = |request: Request| -> Result<(TcpStream, Response)> {
+        let mut stream = TcpStream::connect(addr)?;
+        stream.write_all(Encoder::new().encode_request(request))?;
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let response = Decoder::new(&mut stream).decode_response()?;
+        Ok((stream, response))
+    };
+    let (mut watcher, response) = stream(Request::Watch("key".to_owned()))?;
+    assert_eq!(response, Response::Ok);
+    assert_eq!(stream(Request::Watch("key".to_owned()))?.1, Response::Busy);
+    assert_eq!(
+        stream(Request::Replicate(Default::default()))?.1,
+        Response::Busy
+    );
+
+    kvs::shutdown(addr, shutdown_signal);
+    listening.join().unwrap()?;
+    assert_eq!(watcher.read(&mut [0])?, 0);
+    Ok(())
+}
+
+// Lengths off the wire aren't trusted for allocation, and batches don't nest
+#[test]
+fn decode_malformed() -> Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let mut client = TcpStream::connect(listener.local_addr()?)?;
+    let (mut server, _) = listener.accept()?;
+    // A batch claiming u32::MAX requests, then a string claiming u32::MAX bytes
+    client.write_all(&[3, 0xff, 0xff, 0xff, 0xff, 1, 0xff, 0xff, 0xff, 0xff, b'k'])?;
+    client.shutdown(Shutdown::Write)?;
+    assert!(Decoder::new(&mut server).decode_request().is_err());
+
+    let mut client = TcpStream::connect(listener.local_addr()?)?;
+    let (mut server, _) = listener.accept()?;
+    let nested = Request::Batch(vec![Request::Batch(vec![Request::Get("key".to_owned())])]);
+    client.write_all(Encoder::new().encode_request(nested))?;
+    assert!(matches!(
+        Decoder::new(&mut server).decode_request(),
+        Err(Error::DecodeError(_))
+    ));
+    Ok(())
+}
@@ -0,0 +1,40 @@
+use kvs::HashRing;
+use std::net::SocketAddr;
+
+fn addrs(count: u16) -> Vec<SocketAddr> {
+    (0..count)
+        .map(|i| SocketAddr::from(([127, 0, 0, 1], 4000 + i)))
+        .collect()
+}
+
+// Keys should be spread evenly, and a new node should only take keys from the others
+#[test]
+fn hash_ring_moves_few_keys() {
+    let keys = (0..10000).map(|i| format!("key{i}")).collect::<Vec<_>>();
+    let mut ring = HashRing::new(&addrs(3));
+    let before = keys
+        .iter()
+        .map(|key| ring.node_of(key).unwrap())
+        .collect::<Vec<_>>();
+    for node in addrs(3) {
+        let count = before.iter().filter(|&&owner| owner == node).count();
+        assert!((2000..4700).contains(&count), "{node} has {count} keys");
+    }
+
+    let new_node = addrs(4)[3];
+    ring.add(new_node);
+    let mut moved = 0;
+    for (key, old) in keys.iter().zip(before) {
+        let new = ring.node_of(key).unwrap();
+        if new != old {
+            assert_eq!(new, new_node);
+            moved += 1;
+        }
+    }
+    assert!((1500..3500).contains(&moved), "{moved} keys moved");
+}
+
+#[test]
+fn hash_ring_empty() {
+    assert_eq!(HashRing::default().node_of("key"), None);
+}
@@ -1,8 +1,11 @@
+use std::panic::{self, AssertUnwindSafe};
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
+use std::sync::{mpsc, Arc, Barrier, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use kvs::thread_pool::*;
-use kvs::Result;
+use kvs::{Error, Result};
 
 use crossbeam_utils::sync::WaitGroup;
 
@@ -46,6 +49,42 @@ fn spawn_panic_task<P: ThreadPool>() -> Result<()> {
     spawn_counter(pool)
 }
 
+fn spawn_with_handle<P: ThreadPool>() -> Result<()> {
+    let pool = P::new(4)?;
+    let handles = (0..20u64)
+        .map(|i| pool.spawn_with_handle(move || (0..1000).map(|j| i * j).sum::<u64>()))
+        .collect::<Vec<_>>();
+    for (i, handle) in handles.into_iter().enumerate() {
+        assert_eq!(handle.join()?, i as u64 * 499500);
+    }
+
+    let handle = pool.spawn_with_handle(|| {
+        panic_control::disable_hook_in_current_thread();
+        panic!("boom");
+    });
+    assert!(matches!(handle.join(), Err(Error::JobPanicked(message)) if message == "boom"));
+
+    let mut handle = pool.spawn_with_handle(|| 42);
+    let result = loop {
+        match handle.try_join() {
+            Ok(result) => break result,
+            Err(pending) => handle = pending,
+        }
+    };
+    assert_eq!(result?, 42);
+    Ok(())
+}
+
+// The caller isn't blocked by the job, or it never finishes
+fn spawn_nonblocking<P: ThreadPool>() -> Result<()> {
+    let pool = P::new(2)?;
+    let (release, blocked) = mpsc::channel::<()>();
+    let handle = pool.spawn_with_handle(move || blocked.recv().is_ok());
+    release.send(()).unwrap();
+    assert!(handle.join()?);
+    Ok(())
+}
+
 #[test]
 fn naive_thread_pool_spawn_counter() -> Result<()> {
     let pool = NaiveThreadPool::new(4)?;
@@ -58,6 +97,12 @@ fn shared_queue_thread_pool_spawn_counter() -> Result<()> {
     spawn_counter(pool)
 }
 
+#[test]
+fn work_stealing_thread_pool_spawn_counter() -> Result<()> {
+    let pool = WorkStealingThreadPool::new(4)?;
+    spawn_counter(pool)
+}
+
 #[test]
 fn rayon_thread_pool_spawn_counter() -> Result<()> {
     let pool = RayonThreadPool::new(4)?;
@@ -68,3 +113,536 @@ fn rayon_thread_pool_spawn_counter() -> Result<()> {
 fn shared_queue_thread_pool_panic_task() -> Result<()> {
     spawn_panic_task::<SharedQueueThreadPool>()
 }
+
+#[test]
+fn rayon_thread_pool_panic_task() -> Result<()> {
+    spawn_panic_task::<RayonThreadPool>()
+}
+
+#[test]
+fn shared_queue_thread_pool_spawn_nonblocking() -> Result<()> {
+    spawn_nonblocking::<SharedQueueThreadPool>()
+}
+
+#[test]
+fn rayon_thread_pool_spawn_nonblocking() -> Result<()> {
+    spawn_nonblocking::<RayonThreadPool>()
+}
+
+#[test]
+fn work_stealing_thread_pool_spawn_nonblocking() -> Result<()> {
+    spawn_nonblocking::<WorkStealingThreadPool>()
+}
+
+#[test]
+fn work_stealing_thread_pool_panic_task() -> Result<()> {
+    spawn_panic_task::<WorkStealingThreadPool>()
+}
+
+// Jobs spawned by a worker go to its own deque, idle workers steal them
+#[test]
+fn work_stealing_thread_pool_nested_spawn() -> Result<()> {
+    const TASK_NUM: usize = 100;
+
+    let pool = Arc::new(WorkStealingThreadPool::new(4)?);
+    let wg = WaitGroup::new();
+    let counter = Arc::new(AtomicUsize::new(0));
+    for _ in 0..TASK_NUM {
+        let inner_pool = Arc::clone(&pool);
+        let counter = Arc::clone(&counter);
+        let wg = wg.clone();
+        pool.spawn(move || {
+            for _ in 0..TASK_NUM {
+                let counter = Arc::clone(&counter);
+                let wg = wg.clone();
+                inner_pool.spawn(move || {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    drop(wg);
+                });
+            }
+        });
+    }
+    wg.wait();
+    assert_eq!(counter.load(Ordering::SeqCst), TASK_NUM * TASK_NUM);
+    Ok(())
+}
+
+#[test]
+fn naive_thread_pool_spawn_with_handle() -> Result<()> {
+    spawn_with_handle::<NaiveThreadPool>()
+}
+
+#[test]
+fn shared_queue_thread_pool_spawn_with_handle() -> Result<()> {
+    spawn_with_handle::<SharedQueueThreadPool>()
+}
+
+#[test]
+fn rayon_thread_pool_spawn_with_handle() -> Result<()> {
+    spawn_with_handle::<RayonThreadPool>()
+}
+
+#[test]
+fn work_stealing_thread_pool_spawn_with_handle() -> Result<()> {
+    spawn_with_handle::<WorkStealingThreadPool>()
+}
+
+#[test]
+fn shared_queue_thread_pool_shutdown_drain() -> Result<()> {
+    let pool = SharedQueueThreadPool::new(2)?;
+    let counter = Arc::new(AtomicUsize::new(0));
+    for _ in 0..100 {
+        let counter = Arc::clone(&counter);
+        pool.spawn(move || {
+            thread::sleep(Duration::from_micros(100));
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+    }
+    assert_eq!(pool.shutdown(ShutdownMode::Drain), 0);
+    assert_eq!(counter.load(Ordering::SeqCst), 100);
+    Ok(())
+}
+
+#[test]
+fn shared_queue_thread_pool_shutdown_cancel() -> Result<()> {
+    let pool = SharedQueueThreadPool::new(1)?;
+    let counter = Arc::new(AtomicUsize::new(0));
+    // Hold the only worker until the pool is shutting down
+    let (release, blocked) = mpsc::channel::<()>();
+    let (started, start) = mpsc::channel();
+    pool.spawn(move || {
+        started.send(()).unwrap();
+        _ = blocked.recv();
+    });
+    start.recv().unwrap();
+    for _ in 0..10 {
+        let counter = Arc::clone(&counter);
+        pool.spawn(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+    }
+    thread::spawn(move || {
+        thread::sleep(Duration::from_millis(100));
+        drop(release);
+    });
+    let dropped = pool.shutdown(ShutdownMode::Cancel);
+    // The master may already hold one job, waiting for the worker
+    assert!(dropped >= 9);
+    assert_eq!(counter.load(Ordering::SeqCst) + dropped, 10);
+    Ok(())
+}
+
+/// Wait until the pool has `live` workers, failing after a few seconds
+fn wait_live(pool: &SharedQueueThreadPool, live: usize) {
+    for _ in 0..300 {
+        if pool.metrics().live == live {
+            return;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    panic!("{:?} never has {live} live workers", pool.metrics());
+}
+
+/// Run `n` jobs which only finish when all of them are running at once
+fn run_together(pool: &SharedQueueThreadPool, n: usize) -> Result<()> {
+    let barrier = Arc::new(Barrier::new(n));
+    let handles = (0..n)
+        .map(|_| {
+            let barrier = Arc::clone(&barrier);
+            pool.spawn_with_handle(move || {
+                barrier.wait();
+            })
+        })
+        .collect::<Vec<_>>();
+    for handle in handles {
+        handle.join()?;
+    }
+    Ok(())
+}
+
+#[test]
+fn shared_queue_thread_pool_grow_and_shrink() -> Result<()> {
+    let pool = SharedQueueThreadPool::with_config(
+        PoolConfig::new(1)
+            .max_threads(4)
+            .idle_timeout(Duration::from_millis(100)),
+    )?;
+    wait_live(&pool, 1);
+    run_together(&pool, 4)?;
+    assert_eq!(pool.metrics().live, 4);
+    wait_live(&pool, 1);
+    assert_eq!(pool.metrics().busy, 0);
+    Ok(())
+}
+
+#[test]
+fn shared_queue_thread_pool_resize() -> Result<()> {
+    let pool = SharedQueueThreadPool::new(2)?;
+    wait_live(&pool, 2);
+    pool.resize(1)?;
+    wait_live(&pool, 1);
+    pool.resize(3)?;
+    run_together(&pool, 3)?;
+    assert_eq!(pool.metrics().live, 3);
+    assert!(matches!(pool.resize(0), Err(Error::ZeroSizedPool)));
+    assert!(matches!(
+        RayonThreadPool::new(2)?.resize(4),
+        Err(Error::ResizeUnsupported)
+    ));
+    Ok(())
+}
+
+/// A pool of one worker, held by a job until the sender is dropped
+fn blocked_pool(policy: FullQueuePolicy) -> Result<(SharedQueueThreadPool, mpsc::Sender<()>)> {
+    blocked_pool_with(PoolConfig::new(1).queue_capacity(1, policy))
+}
+
+/// A pool of the config, its only worker held by a job until the sender is dropped
+fn blocked_pool_with(config: PoolConfig) -> Result<(SharedQueueThreadPool, mpsc::Sender<()>)> {
+    let pool = SharedQueueThreadPool::with_config(config)?;
+    let (release, blocked) = mpsc::channel::<()>();
+    let (started, start) = mpsc::channel();
+    pool.spawn(move || {
+        started.send(()).unwrap();
+        _ = blocked.recv();
+    });
+    start.recv().unwrap();
+    Ok((pool, release))
+}
+
+#[test]
+fn shared_queue_thread_pool_reject() -> Result<()> {
+    let (pool, release) = blocked_pool(FullQueuePolicy::Reject)?;
+    let counter = Arc::new(AtomicUsize::new(0));
+    let mut accepted = 0;
+    for _ in 0..10 {
+        let counter = Arc::clone(&counter);
+        let job = move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+        };
+        if pool.try_spawn(job).is_ok() {
+            accepted += 1;
+        }
+    }
+    // One job waits in the queue, and the master may hold another
+    assert!((1..=2).contains(&accepted));
+    drop(release);
+    assert_eq!(pool.shutdown(ShutdownMode::Drain), 0);
+    assert_eq!(counter.load(Ordering::SeqCst), accepted);
+    Ok(())
+}
+
+#[test]
+fn shared_queue_thread_pool_caller_runs() -> Result<()> {
+    let (pool, release) = blocked_pool(FullQueuePolicy::CallerRuns)?;
+    let caller = thread::current().id();
+    let on_caller = Arc::new(AtomicUsize::new(0));
+    for _ in 0..10 {
+        let on_caller = Arc::clone(&on_caller);
+        let job = move || {
+            if thread::current().id() == caller {
+                on_caller.fetch_add(1, Ordering::SeqCst);
+            }
+        };
+        assert!(pool.try_spawn(job).is_ok());
+    }
+    assert!(on_caller.load(Ordering::SeqCst) >= 8);
+    drop(release);
+    Ok(())
+}
+
+#[test]
+fn shared_queue_thread_pool_scope() -> Result<()> {
+    let pool = SharedQueueThreadPool::new(4)?;
+    let numbers = (1..=1000).collect::<Vec<u64>>();
+    let mut sums = vec![0; 10];
+    pool.scope(|s| {
+        for (chunk, sum) in numbers.chunks(100).zip(&mut sums) {
+            s.spawn(move || *sum = chunk.iter().sum());
+        }
+    });
+    assert_eq!(sums.iter().sum::<u64>(), 500500);
+    Ok(())
+}
+
+// A panicking task is raised from the scope, after the other tasks are finished
+#[test]
+fn shared_queue_thread_pool_scope_panic_task() -> Result<()> {
+    let pool = SharedQueueThreadPool::new(2)?;
+    let finished = AtomicUsize::new(0);
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        pool.scope(|s| {
+            s.spawn(|| {
+                panic_control::disable_hook_in_current_thread();
+                panic!("task");
+            });
+            for _ in 0..4 {
+                s.spawn(|| {
+                    thread::sleep(Duration::from_millis(20));
+                    finished.fetch_add(1, Ordering::SeqCst);
+                });
+            }
+        })
+    }));
+    let payload = result.unwrap_err();
+    assert_eq!(payload.downcast_ref::<&str>(), Some(&"task"));
+    assert_eq!(finished.load(Ordering::SeqCst), 4);
+    // The pool still works
+    pool.scope(|s| s.spawn(|| finished.store(0, Ordering::SeqCst)));
+    assert_eq!(finished.load(Ordering::SeqCst), 0);
+    Ok(())
+}
+
+// The spawned tasks still finish before a panic of the scope itself is raised
+#[test]
+fn shared_queue_thread_pool_scope_panic() -> Result<()> {
+    let pool = SharedQueueThreadPool::new(2)?;
+    let finished = AtomicUsize::new(0);
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        pool.scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(50));
+                finished.fetch_add(1, Ordering::SeqCst);
+            });
+            panic_control::disable_hook_in_current_thread();
+            panic!("scope");
+        })
+    }));
+    assert!(result.is_err());
+    assert_eq!(finished.load(Ordering::SeqCst), 1);
+    Ok(())
+}
+
+fn schedule_after<P: Scheduler>() -> Result<()> {
+    let pool = P::new(2)?;
+    let (sender, receiver) = mpsc::channel();
+    let start = Instant::now();
+    let cancelled = pool.schedule_after(Duration::from_millis(50), {
+        let sender = sender.clone();
+        move || sender.send("cancelled").unwrap()
+    });
+    pool.schedule_after(Duration::from_millis(100), move || {
+        sender.send("due").unwrap()
+    });
+    cancelled.cancel();
+    assert!(cancelled.is_cancelled());
+    assert_eq!(receiver.recv_timeout(Duration::from_secs(5)), Ok("due"));
+    assert!(start.elapsed() >= Duration::from_millis(100));
+    Ok(())
+}
+
+fn schedule_every<P: Scheduler>() -> Result<()> {
+    let pool = P::new(2)?;
+    let (sender, receiver) = mpsc::channel();
+    let handle = pool.schedule_every(Duration::from_millis(10), move || {
+        _ = sender.send(());
+    });
+    for _ in 0..3 {
+        receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+    }
+    handle.cancel();
+    // A run spawned before the cancel may still finish
+    thread::sleep(Duration::from_millis(100));
+    while receiver.try_recv().is_ok() {}
+    thread::sleep(Duration::from_millis(100));
+    assert!(receiver.try_recv().is_err());
+    // Dropping the pool stops a job left scheduled
+    let counter = Arc::new(AtomicUsize::new(0));
+    pool.schedule_every(Duration::from_millis(10), {
+        let counter = Arc::clone(&counter);
+        move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+        }
+    });
+    drop(pool);
+    thread::sleep(Duration::from_millis(50));
+    let runs = counter.load(Ordering::SeqCst);
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(counter.load(Ordering::SeqCst), runs);
+    Ok(())
+}
+
+#[test]
+fn naive_thread_pool_schedule_after() -> Result<()> {
+    schedule_after::<NaiveThreadPool>()
+}
+
+#[test]
+fn shared_queue_thread_pool_schedule_after() -> Result<()> {
+    schedule_after::<SharedQueueThreadPool>()
+}
+
+#[test]
+fn rayon_thread_pool_schedule_after() -> Result<()> {
+    schedule_after::<RayonThreadPool>()
+}
+
+#[test]
+fn work_stealing_thread_pool_schedule_after() -> Result<()> {
+    schedule_after::<WorkStealingThreadPool>()
+}
+
+#[test]
+fn naive_thread_pool_schedule_every() -> Result<()> {
+    schedule_every::<NaiveThreadPool>()
+}
+
+#[test]
+fn shared_queue_thread_pool_schedule_every() -> Result<()> {
+    schedule_every::<SharedQueueThreadPool>()
+}
+
+#[test]
+fn rayon_thread_pool_schedule_every() -> Result<()> {
+    schedule_every::<RayonThreadPool>()
+}
+
+#[test]
+fn work_stealing_thread_pool_schedule_every() -> Result<()> {
+    schedule_every::<WorkStealingThreadPool>()
+}
+
+#[test]
+fn shared_queue_thread_pool_stats() -> Result<()> {
+    let pool = SharedQueueThreadPool::with_config(PoolConfig::new(1).on_panic(|_| {}))?;
+    let (release, blocked) = mpsc::channel::<()>();
+    pool.spawn(move || {
+        blocked.recv().unwrap();
+    });
+    pool.spawn(|| thread::sleep(Duration::from_millis(20)));
+    pool.spawn(|| panic!());
+    let monitor = pool.monitor();
+    while monitor.stats().running == 0 {
+        thread::sleep(Duration::from_millis(1));
+    }
+    let stats = pool.stats();
+    assert_eq!(stats.running, 1);
+    assert_eq!(stats.completed + stats.panicked, 0);
+    release.send(()).unwrap();
+    drop(pool);
+    let stats = monitor.stats();
+    assert_eq!((stats.queued, stats.running), (0, 0));
+    assert_eq!((stats.completed, stats.panicked), (2, 1));
+    assert!(stats.p99 >= Duration::from_millis(20));
+    assert!(stats.p50 <= stats.p99);
+    Ok(())
+}
+
+#[test]
+fn shared_queue_thread_pool_on_panic() -> Result<()> {
+    let (sender, receiver) = mpsc::channel();
+    let sender = Mutex::new(sender);
+    let config = PoolConfig::new(2).on_panic(move |payload| {
+        let name = thread::current().name().map(str::to_owned);
+        let message = panic_message(payload).to_owned();
+        sender.lock().unwrap().send((name, message)).unwrap();
+    });
+    let pool = SharedQueueThreadPool::with_config(config)?;
+    pool.spawn(|| panic!("job {} failed", 1));
+    let (name, message) = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert!(name.unwrap().starts_with("kvs-worker-"));
+    assert_eq!(message, "job 1 failed");
+    // The worker goes on after the hook
+    assert_eq!(pool.spawn_with_handle(|| 1).join()?, 1);
+    Ok(())
+}
+
+// A panicking hook should neither kill the worker nor lose the panics after it
+#[test]
+fn shared_queue_thread_pool_panicking_hook() -> Result<()> {
+    let calls = Arc::new(AtomicUsize::new(0));
+    let config = PoolConfig::new(1).on_panic({
+        let calls = Arc::clone(&calls);
+        move |_| {
+            calls.fetch_add(1, Ordering::SeqCst);
+            panic!("hook failed");
+        }
+    });
+    let pool = SharedQueueThreadPool::with_config(config)?;
+    pool.spawn(|| panic!("job 1 failed"));
+    pool.spawn(|| panic!("job 2 failed"));
+    assert_eq!(pool.spawn_with_handle(|| 1).join()?, 1);
+    assert_eq!(calls.load(Ordering::SeqCst), 2);
+    assert_eq!(pool.stats().panicked, 2);
+    Ok(())
+}
+
+#[test]
+fn shared_queue_thread_pool_priority() -> Result<()> {
+    let (pool, release) = blocked_pool(FullQueuePolicy::Reject)?;
+    let order = Arc::new(Mutex::new(Vec::new()));
+    let job = |name: &'static str| {
+        let order = Arc::clone(&order);
+        move || order.lock().unwrap().push(name)
+    };
+    assert!(pool.try_spawn(job("normal")).is_ok());
+    assert!(pool.try_spawn(job("refused")).is_err());
+    // High priority jobs don't wait for room in the queue
+    pool.spawn_with_priority(Priority::High, job("high"));
+    pool.spawn_with_priority(Priority::High, job("high"));
+    drop(release);
+    pool.shutdown(ShutdownMode::Drain);
+    assert_eq!(*order.lock().unwrap(), ["high", "high", "normal"]);
+    Ok(())
+}
+
+#[test]
+fn shared_queue_thread_pool_priority_reserve() -> Result<()> {
+    let (pool, release) = blocked_pool_with(
+        PoolConfig::new(1)
+            .queue_capacity(1, FullQueuePolicy::Reject)
+            .high_priority_reserve(2),
+    )?;
+    let counter = Arc::new(AtomicUsize::new(0));
+    let job = || {
+        let counter = Arc::clone(&counter);
+        move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+        }
+    };
+    assert!(pool.try_spawn(job()).is_ok());
+    // High priority jobs take the reserved places, and are refused beyond them
+    let accepted = (0..10)
+        .filter(|_| pool.try_spawn_with_priority(Priority::High, job()).is_ok())
+        .count();
+    assert!((2..=3).contains(&accepted));
+    drop(release);
+    assert_eq!(pool.shutdown(ShutdownMode::Drain), 0);
+    assert_eq!(counter.load(Ordering::SeqCst), accepted + 1);
+    Ok(())
+}
+
+#[test]
+fn shared_queue_thread_pool_priority_fairness() -> Result<()> {
+    let (pool, release) = blocked_pool_with(
+        PoolConfig::new(1)
+            .queue_capacity(1, FullQueuePolicy::Block)
+            .high_priority_reserve(30),
+    )?;
+    let order = Arc::new(Mutex::new(Vec::new()));
+    let job = |priority| {
+        let order = Arc::clone(&order);
+        move || order.lock().unwrap().push(priority)
+    };
+    pool.spawn(job(Priority::Normal));
+    for _ in 0..30 {
+        pool.spawn_with_priority(Priority::High, job(Priority::High));
+    }
+    drop(release);
+    pool.shutdown(ShutdownMode::Drain);
+    let order = order.lock().unwrap();
+    assert_eq!(order.len(), 31);
+    // The normal job goes after a burst of high ones, not after all of them
+    let normal = order.iter().position(|&p| p == Priority::Normal).unwrap();
+    assert!((1..30).contains(&normal));
+    Ok(())
+}
+
+#[test]
+fn rayon_thread_pool_spawn_with_priority() -> Result<()> {
+    let pool = RayonThreadPool::new(1)?;
+    let (sender, receiver) = mpsc::channel();
+    pool.spawn_with_priority(Priority::High, move || sender.send(()).unwrap());
+    receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+    Ok(())
+}